@@ -0,0 +1,640 @@
+use std::collections::{
+    HashMap,
+    HashSet,
+    VecDeque,
+};
+
+use crate::{
+    devctx::{self, DeviceContext},
+    dd_cache::DDData,
+    lorawan_config::{self, ADRParams, ADRStrategy, GlobalParams, RfRegionRecord, ServiceProfile},
+    lorawan::{
+        link_check,
+        mac_cmds::{DownlinkMACCmd, UplinkMACCmd},
+        region::Region,
+    },
+};
+
+/// The highest NbTrans set by the ADR engine
+pub const MAX_ADR_NB_TRANS: u8 = 3;
+
+/// The longest back-off after rejected LinkADRReqs (uplinks)
+pub const MAX_ADR_BACKOFF: u32 = 64;
+
+/// The accepted LinkADRReqs after which the limits set by rejections are lifted
+pub const ADR_LIMIT_ACCEPTED_ANSWERS: u8 = 8;
+
+/// The estimated packet error rate above which the link is considered collapsed
+pub const COLLAPSE_PER: f32 = 0.5;
+
+/// The longest FCnt gap counted as lost uplinks in the PER estimate
+const MAX_COUNTED_GAP: u32 = 64;
+
+/// An uplink as seen by all the receiving gateways
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub struct ADRSample {
+    pub f_cnt: u32,
+    pub snr: f32,       // dB, the best SNR of the receiving gateways
+    pub gw_cnt: usize,  // the number of receiving gateways
+}
+
+/// The goals of the PER strategy, set by the service profile
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub struct PERTarget {
+    pub target_per: f32,       // the packet error rate to be met after repetitions
+    pub min_gw_diversity: u8,  // the receiving gateways needed before the data rate is raised
+    pub recovery: ADRSetting,  // the settings of a collapsed link
+}
+
+/// The uplink parameters set by LinkADRReq
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub struct ADRSetting {
+    pub data_rate: u8,
+    pub tx_power: u8,   // TXPower index, 0 is the highest power
+    pub nb_trans: u8,
+}
+
+/// The ADR state of a device
+#[derive(Debug, Clone, Default)]
+pub struct ADRState {
+    pub history: VecDeque<ADRSample>,  // the recent uplinks, oldest first
+    pub per: f32,                      // the estimated packet error rate (after repetitions)
+    pub max_data_rate: Option<u8>,     // lowered when the device rejects a data rate
+    pub max_tx_power: Option<u8>,      // lowered when the device rejects a TXPower index
+    pub rejections: u8,                // consecutive rejected LinkADRReqs
+    pub acceptances: u8,               // accepted LinkADRReqs since the last rejection
+    pub backoff: u32,                  // uplinks to wait before the next LinkADRReq
+}
+impl ADRState {
+
+    /// Adds an uplink to the SNR history, a counter reset restarts the history
+    ///
+    /// The PER estimate is updated with the uplinks missing before the sample, each
+    /// uplink weighted by `forgetting_factor`.
+    ///
+    pub fn add_sample(&mut self, sample: ADRSample, window_size: usize, forgetting_factor: f32) {
+        match self.history.back() {
+            Some(last) if last.f_cnt >= sample.f_cnt => self.history.clear(),
+            Some(last) => {
+                for _ in 0..(sample.f_cnt - last.f_cnt - 1).min(MAX_COUNTED_GAP) {
+                    self.per = (1.0 - forgetting_factor) * self.per + forgetting_factor;
+                }
+            },
+            None => (),
+        }
+        self.per *= 1.0 - forgetting_factor;
+        self.history.push_back(sample);
+        while self.history.len() > window_size.max(1) {
+            self.history.pop_front();
+        }
+    }
+
+    /// The rate of uplinks missing from the history (0.0..1.0)
+    pub fn loss_rate(&self) -> f32 {
+        let (Some(first), Some(last)) = (self.history.front(), self.history.back()) else {
+            return 0.0;
+        };
+        let expected = (last.f_cnt - first.f_cnt + 1) as f32;
+        1.0 - self.history.len() as f32 / expected
+    }
+
+    /// Handles the answer to a LinkADRReq
+    ///
+    /// The device applies the whole request or nothing. The rejected data rate or TXPower
+    /// becomes the new limit and the next request is delayed by an exponential back-off.
+    /// The limits are lifted after `ADR_LIMIT_ACCEPTED_ANSWERS` accepted requests, so that
+    /// a device whose configuration has changed is tried again.
+    ///
+    pub fn handle_link_adr_ans(&mut self, req: &DownlinkMACCmd, ans: &UplinkMACCmd) {
+
+        let (
+            DownlinkMACCmd::LinkADRReq { data_rate, tx_power, .. },
+            UplinkMACCmd::LinkADRAns { power_ack, data_rate_ack, channel_mask_ack },
+        ) = (req, ans) else {
+            return;
+        };
+
+        if *power_ack && *data_rate_ack && *channel_mask_ack {
+            self.rejections = 0;
+            self.backoff = 0;
+            self.acceptances = self.acceptances.saturating_add(1);
+            if self.acceptances >= ADR_LIMIT_ACCEPTED_ANSWERS {
+                self.max_data_rate = None;
+                self.max_tx_power = None;
+            }
+            return;
+        }
+
+        if !data_rate_ack {
+            self.max_data_rate = Some(data_rate.saturating_sub(1));
+        }
+        if !power_ack {
+            self.max_tx_power = Some(tx_power.saturating_sub(1));
+        }
+        if !channel_mask_ack {
+            log::warn!("ChMask rejected by the device, the active channels may be out of sync");
+        }
+        self.acceptances = 0;
+        self.rejections = self.rejections.saturating_add(1);
+        self.backoff = (1_u32 << self.rejections.min(6)).min(MAX_ADR_BACKOFF);
+
+    }
+
+}
+
+/// NbTrans adjusted to the loss rate of the recent uplinks
+fn nb_trans_for_loss(nb_trans: u8, loss_rate: f32) -> u8 {
+    match loss_rate {
+        l if l < 0.05 => nb_trans.saturating_sub(1),
+        l if l < 0.10 => nb_trans,
+        l if l < 0.30 => nb_trans + 1,
+        _ => MAX_ADR_NB_TRANS,
+    }
+}
+
+/// Computes the optimal uplink parameters of a device
+///
+/// The margin is the best SNR of the history above the demodulation floor of the current
+/// spreading factor, reduced by `margin_db`. Every 3 dB of margin increases the data rate,
+/// then lowers the TX power; a negative margin raises the TX power. NbTrans follows the
+/// loss rate of the history.
+///
+/// # Arguments
+///
+/// * __`current`__\
+///   The data rate of the last uplink with the TXPower and NbTrans of the device \
+/// * __`sp_fact`__\
+///   Spreading factor of the last uplink \
+/// * __`dr_range`__\
+///   The data rates the device may use, `[min, max]` \
+/// * __`max_tx_power`__\
+///   The highest TXPower index the device may use \
+///
+/// # Specification
+///
+/// LoRaWAN L2 1.0.4 - line #1148                       \
+/// 5.3 Link ADR commands (LinkADRReq, LinkADRAns)      \
+///
+pub fn optimize(
+    params: &ADRParams,
+    state: &ADRState,
+    current: ADRSetting,
+    sp_fact: u8,
+    dr_range: [u8; 2],
+    max_tx_power: u8,
+) -> Option<ADRSetting> {
+
+    if state.history.len() < params.initial_window_size.max(1) {
+        return None;
+    }
+
+    let snr_max = state.history
+        .iter()
+        .map(|sample| sample.snr)
+        .fold(f32::MIN, f32::max);
+    let margin = snr_max - link_check::snr_limit(sp_fact)? - params.margin_db;
+    let mut n_step = if margin > 0.0 {
+        ((margin - params.hyst_snr) / 3.0).floor().max(0.0) as i32
+    } else {
+        (margin / 3.0).floor() as i32
+    };
+
+    let max_dr = state.max_data_rate.map_or(dr_range[1], |dr| dr.min(dr_range[1]));
+    let max_tx_power = state.max_tx_power.map_or(max_tx_power, |p| p.min(max_tx_power));
+
+    let mut setting = current;
+    setting.data_rate = setting.data_rate.clamp(dr_range[0], max_dr.max(dr_range[0]));
+    setting.tx_power = setting.tx_power.min(max_tx_power);
+
+    while n_step > 0 && setting.data_rate < max_dr {
+        setting.data_rate += 1;
+        n_step -= 1;
+    }
+    while n_step > 0 && setting.tx_power < max_tx_power {
+        setting.tx_power += 1;
+        n_step -= 1;
+    }
+    while n_step < 0 && setting.tx_power > 0 {
+        setting.tx_power -= 1;
+        n_step += 1;
+    }
+
+    setting.nb_trans = nb_trans_for_loss(setting.nb_trans.max(1), state.loss_rate())
+        .clamp(params.redundancy.clamp(1, MAX_ADR_NB_TRANS), MAX_ADR_NB_TRANS);
+
+    Some(setting)
+
+}
+
+/// Computes the uplink parameters that meet a target packet error rate
+///
+/// The error rate of a single transmission is derived from the PER estimate and NbTrans,
+/// NbTrans is set to the repetitions that meet the target within `rep_min..=rep_max`.
+/// If the target cannot be met the data rate is lowered. It is raised when the target is
+/// met without extra repetitions, the SNR allows the next data rate and enough gateways
+/// receive the device, counting each additional gateway with `macro_div_reliab`.
+/// A collapsed link gets the recovery settings.
+///
+pub fn optimize_per(
+    params: &ADRParams,
+    state: &ADRState,
+    current: ADRSetting,
+    sp_fact: u8,
+    dr_range: [u8; 2],
+    per_target: &PERTarget,
+) -> Option<ADRSetting> {
+
+    let PERTarget { target_per, min_gw_diversity, recovery } = *per_target;
+
+    if state.history.len() < params.initial_window_size.max(1) {
+        return None;
+    }
+    if state.per >= COLLAPSE_PER {
+        return Some(recovery);
+    }
+
+    let gw_avg = state.history.iter().map(|sample| sample.gw_cnt as f32).sum::<f32>() / state.history.len() as f32;
+    let diversity = 1.0 + (gw_avg - 1.0).max(0.0) * params.macro_div_reliab;
+    let snr_max = state.history
+        .iter()
+        .map(|sample| sample.snr)
+        .fold(f32::MIN, f32::max);
+
+    let rep_min = params.rep_min.max(1);
+    let rep_max = params.rep_max.max(rep_min);
+    let p_tx = state.per.clamp(0.001, 0.999).powf(1.0 / current.nb_trans.max(1) as f32);
+    let rep = (target_per.ln() / p_tx.ln()).ceil().max(1.0) as u8;
+
+    let max_dr = state.max_data_rate.map_or(dr_range[1], |dr| dr.min(dr_range[1]));
+    let mut setting = current;
+    if rep > rep_max && setting.data_rate > dr_range[0] {
+        setting.data_rate -= 1;
+        setting.nb_trans = rep_max;
+    } else {
+        setting.nb_trans = rep.clamp(rep_min, rep_max);
+        let next_dr_margin = link_check::snr_limit(sp_fact.saturating_sub(1))
+            .map(|floor| snr_max - floor - params.margin_db);
+        if rep <= rep_min
+            && state.per < target_per * params.per_hysteresis
+            && diversity >= min_gw_diversity as f32
+            && setting.data_rate < max_dr
+            && next_dr_margin.is_some_and(|margin| margin >= 0.0)
+        {
+            setting.data_rate += 1;
+        }
+    }
+
+    Some(setting)
+
+}
+
+/// Runs the ADR engine on an uplink of a device
+///
+/// The uplink is added to the SNR history. If the device enables ADR and the optimal
+/// parameters of the strategy of its service profile differ from its current ones,
+/// a LinkADRReq is queued for the next downlink.
+///
+/// # Arguments
+///
+/// * __`f_cnt32`__\
+///   The 32-bit FCnt of the uplink \
+/// * __`adr`__\
+///   The ADR bit of the uplink `FCtrl` \
+///
+pub fn handle_uplink(
+    dev_eui: u64,
+    rf_region: &RfRegionRecord,
+    service_profile: Option<&ServiceProfile>,
+    collected_dd_data: &[DDData],
+    datr: &str,
+    f_cnt32: u32,
+    adr: bool,
+) {
+
+    let Some(snr) = collected_dd_data.iter().map(|dd_data| dd_data.snr).reduce(f32::max) else {
+        return;
+    };
+    let gw_cnt = collected_dd_data
+        .iter()
+        .map(|dd_data| dd_data.gw_eui)
+        .collect::<HashSet<u64>>()
+        .len();
+    let (Some(data_rate), Some(sp_fact)) = (
+        rf_region.data_rate(datr),
+        collected_dd_data.first().map(|dd_data| dd_data.sp_fact),
+    ) else {
+        return;
+    };
+
+    let params = &rf_region.adr;
+    let dr_range = [
+        rf_region.lora_data_rate(params.sf_max).unwrap_or(0)
+            .max(service_profile.and_then(|p| p.dr_min).unwrap_or(0)),
+        rf_region.lora_data_rate(params.sf_min).unwrap_or(data_rate)
+            .min(service_profile.and_then(|p| p.dr_max).unwrap_or(u8::MAX)),
+    ];
+    let max_tx_power = rf_region.max_tx_power_index();
+    let strategy = service_profile.map(|p| p.x_adr_strategy).unwrap_or_default();
+    let per_target = PERTarget {
+        target_per: service_profile.and_then(|p| p.target_per).unwrap_or(params.target_per),
+        min_gw_diversity: service_profile.and_then(|p| p.min_gw_diversity).unwrap_or(1),
+        recovery: ADRSetting {
+            data_rate: rf_region.lora_data_rate(params.recovery_sf).unwrap_or(dr_range[0]),
+            tx_power: rf_region.tx_power_index(params.recovery_tx_power),
+            nb_trans: params.recovery_nb_trans.clamp(1, 15),
+        },
+    };
+    let lorawan_config = lorawan_config::get_or_init();
+    let Some(device_record) = lorawan_config.devices.get(&dev_eui) else {
+        return;
+    };
+    let Some(region) = lorawan_config.region(device_record) else {
+        return;
+    };
+
+    devctx::update_device_context(dev_eui, |ctx| {
+
+        let state = ctx.adr_state_mut();
+        state.add_sample(ADRSample { f_cnt: f_cnt32, snr, gw_cnt }, params.window_size_snr, params.forgetting_factor);
+        if state.backoff > 0 {
+            state.backoff -= 1;
+            return;
+        }
+        if !adr {
+            return;
+        }
+
+        let mac_params = ctx.mac_params_mut();
+        if data_rate < mac_params.data_rate {
+            // ADR back-off: after ADR_ACK_LIMIT + ADR_ACK_DELAY uplinks without downlink the
+            // device has restored its default TX power and NbTrans before lowering the data rate
+            log::debug!("DevEUI: 0x{:016x} ADR back-off to DR{}", dev_eui, data_rate);
+            mac_params.data_rate = data_rate;
+            mac_params.tx_power = 0;
+            mac_params.nb_trans = 1;
+        }
+        let current = ADRSetting {
+            data_rate,
+            tx_power: mac_params.tx_power,
+            nb_trans: mac_params.nb_trans.max(1),
+        };
+        let state = ctx.adr_state_mut();
+        let setting = match strategy {
+            ADRStrategy::SNRMargin => optimize(params, state, current, sp_fact, dr_range, max_tx_power),
+            ADRStrategy::TargetPER => optimize_per(params, state, current, sp_fact, dr_range, &per_target),
+        };
+        let Some(setting) = setting else {
+            return;
+        };
+        if setting == current {
+            return;
+        }
+
+        // A session without channels uses the channels provisioned by its Join-Accept
+        if ctx.active_channels().is_empty() {
+            *ctx.active_channels_mut() = devctx::join_channels(lorawan_config, device_record);
+        }
+        let (ch_mask, ch_mask_cntl) = link_adr_ch_mask(region, ctx.active_channels());
+        let req = DownlinkMACCmd::LinkADRReq {
+            data_rate: setting.data_rate,
+            tx_power: setting.tx_power,
+            ch_mask,
+            ch_mask_cntl,
+            nb_trans: setting.nb_trans,
+        };
+        let mac_state = ctx.mac_state_mut();
+        if mac_state.pending.iter().any(|pending| pending.cmd.cid() as u8 == req.cid() as u8) {
+            return;
+        }
+        log::debug!("DevEUI: 0x{:016x} ADR {:?} -> {:?}", dev_eui, current, setting);
+        mac_state.enqueue(req);
+
+    });
+
+}
+
+/// Queues ADRParamSetupReq if the service profile sets other ADR_ACK_LIMIT or ADR_ACK_DELAY
+/// values than the ones the device uses
+///
+/// The device uses the values of `global_params` until it acknowledges ADRParamSetupReq.
+/// The command exists since LoRaWAN 1.1.
+///
+/// # Specification
+///
+/// LoRaWAN L2 1.0.4 - line #1405                                 \
+/// 5.11 ADR parameters (ADRParamSetupReq, ADRParamSetupAns)     \
+///
+pub fn schedule_adr_param_setup(ctx: &mut DeviceContext, service_profile: &ServiceProfile, global_params: &GlobalParams) {
+
+    if !matches!(ctx, DeviceContext::V12x(_)) {
+        return;
+    }
+
+    let mac_params = ctx.mac_params_mut();
+    let limit_exp = mac_params.adr_ack_limit_exp.unwrap_or(global_params.adr_ack_limit.max(1).ilog2() as u8);
+    let delay_exp = mac_params.adr_ack_delay_exp.unwrap_or(global_params.adr_ack_delay.max(1).ilog2() as u8);
+    let req = DownlinkMACCmd::ADRParamSetupReq {
+        limit_exp: service_profile.x_adr_ack_limit_exp.unwrap_or(limit_exp) & 0x0f,
+        delay_exp: service_profile.x_adr_ack_delay_exp.unwrap_or(delay_exp) & 0x0f,
+    };
+    if req == (DownlinkMACCmd::ADRParamSetupReq { limit_exp, delay_exp }) {
+        return;
+    }
+
+    let mac_state = ctx.mac_state_mut();
+    if !mac_state.is_requested(&req) {
+        mac_state.enqueue(req);
+    }
+
+}
+
+/// The ChMask and ChMaskCntl of a LinkADRReq that keeps the active channels of a device
+///
+/// Dynamic channel plans mask the channels 0..15 with ChMaskCntl 0. US915 and AU915 enable
+/// the blocks of eight 125 kHz channels and their 500 kHz channel with ChMaskCntl 5. CN470
+/// masks its block of 16 channels, or turns all the channels on with ChMaskCntl 6 when the
+/// active channels span several blocks.
+///
+/// # Specification
+///
+/// RP002-1.0.4 - 2.5.5 US902-928 LinkAdrReq command \
+/// RP002-1.0.4 - 2.7.5 AU915-928 LinkAdrReq command \
+/// RP002-1.0.4 - 2.8.5 CN470-510 LinkAdrReq command \
+///
+pub fn link_adr_ch_mask(region: Region, active_channels: &HashMap<u8, (u32, u8)>) -> (u16, u8) {
+    let mask = |ch_indexes: &mut dyn Iterator<Item = u8>| ch_indexes.fold(0_u16, |mask, ch_index| mask | 1 << ch_index);
+    match region {
+        Region::US915 | Region::AU915 => {
+            let blocks = active_channels.keys().map(|ch_index| match ch_index {
+                0..=63 => ch_index / 8,
+                _ => ch_index - 64,
+            });
+            (mask(&mut blocks.filter(|block| *block < 8)), 5)
+        },
+        Region::CN470 => {
+            let blocks: HashSet<u8> = active_channels.keys().map(|ch_index| ch_index / 16).collect();
+            match blocks.iter().next() {
+                Some(block) if blocks.len() == 1 && *block < 6 => {
+                    (mask(&mut active_channels.keys().map(|ch_index| ch_index % 16)), *block)
+                },
+                _ => (0, 6),
+            }
+        },
+        _ => (mask(&mut active_channels.keys().copied().filter(|ch_index| *ch_index < 16)), 0),
+    }
+}
+
+
+#[cfg(test)]
+mod tests {
+
+    use super::*;
+    use crate::{
+        handle_join_request::join_context,
+        join_server::{JoinAnsParams, SessionKeys},
+    };
+
+    fn state_with_snr(snr: f32, f_cnts: impl Iterator<Item = u32>) -> ADRState {
+        let mut state = ADRState::default();
+        for f_cnt in f_cnts {
+            state.add_sample(ADRSample { f_cnt, snr, gw_cnt: 1 }, 50, 0.15);
+        }
+        state
+    }
+
+    #[test]
+    fn test_optimize() {
+
+        let params = ADRParams::default();
+        let current = ADRSetting { data_rate: 0, tx_power: 0, nb_trans: 1 };
+
+        // not enough uplinks yet
+        assert_eq!(optimize(&params, &state_with_snr(10.0, 0..5), current, 12, [0, 5], 6), None);
+
+        // SF12 at 5 dB SNR: margin 14 dB, (14 - 1) / 3 = 4 steps
+        let state = state_with_snr(5.0, 0..20);
+        assert_eq!(
+            optimize(&params, &state, current, 12, [0, 5], 6),
+            Some(ADRSetting { data_rate: 4, tx_power: 0, nb_trans: 1 }),
+        );
+
+        // SF7 at 0 dB SNR: margin -3 dB, the power is raised
+        let current = ADRSetting { data_rate: 5, tx_power: 3, nb_trans: 1 };
+        assert_eq!(
+            optimize(&params, &state_with_snr(0.0, 0..20), current, 7, [0, 5], 6),
+            Some(ADRSetting { data_rate: 5, tx_power: 2, nb_trans: 1 }),
+        );
+
+        // every second uplink is lost
+        let state = state_with_snr(0.0, (0..40).step_by(2));
+        assert_eq!(optimize(&params, &state, current, 7, [0, 5], 6).map(|s| s.nb_trans), Some(3));
+
+    }
+
+    #[test]
+    fn test_optimize_per() {
+
+        let params = ADRParams::default();
+        let recovery = ADRSetting { data_rate: 0, tx_power: 0, nb_trans: 3 };
+        let target = PERTarget { target_per: 0.1, min_gw_diversity: 1, recovery };
+        let current = ADRSetting { data_rate: 3, tx_power: 0, nb_trans: 1 };
+
+        // no loss: faster data rate if the SNR allows it (SF8 margin: 5 + 9 - 10 dB)
+        let state = state_with_snr(5.0, 0..20);
+        assert_eq!(
+            optimize_per(&params, &state, current, 9, [0, 5], &target),
+            Some(ADRSetting { data_rate: 4, tx_power: 0, nb_trans: 1 }),
+        );
+        // not enough receiving gateways
+        let diverse = PERTarget { min_gw_diversity: 2, ..target };
+        assert_eq!(optimize_per(&params, &state, current, 9, [0, 5], &diverse), Some(current));
+
+        // every third uplink is lost: repetitions meet the target
+        let state = state_with_snr(0.0, (0..60).filter(|f_cnt| f_cnt % 3 != 0));
+        assert!(state.per > 0.2 && state.per < COLLAPSE_PER);
+        assert_eq!(
+            optimize_per(&params, &state, current, 9, [0, 5], &target),
+            Some(ADRSetting { data_rate: 3, tx_power: 0, nb_trans: 2 }),
+        );
+
+        // the link collapses
+        let state = state_with_snr(0.0, (0..100).step_by(5));
+        assert_eq!(optimize_per(&params, &state, current, 9, [0, 5], &target), Some(recovery));
+
+    }
+
+    #[test]
+    fn test_link_adr_ans_backoff() {
+
+        let params = ADRParams::default();
+        let mut state = state_with_snr(5.0, 0..20);
+        let req = DownlinkMACCmd::LinkADRReq { data_rate: 4, tx_power: 0, ch_mask: 7, ch_mask_cntl: 0, nb_trans: 1 };
+        let ans = UplinkMACCmd::LinkADRAns { power_ack: true, data_rate_ack: false, channel_mask_ack: true };
+        state.handle_link_adr_ans(&req, &ans);
+        assert_eq!(state.backoff, 2);
+
+        // the rejected data rate is not requested again, the margin lowers the power instead
+        let current = ADRSetting { data_rate: 0, tx_power: 0, nb_trans: 1 };
+        assert_eq!(
+            optimize(&params, &state, current, 12, [0, 5], 6),
+            Some(ADRSetting { data_rate: 3, tx_power: 1, nb_trans: 1 }),
+        );
+
+        // the limit is lifted after ADR_LIMIT_ACCEPTED_ANSWERS accepted requests
+        let req = DownlinkMACCmd::LinkADRReq { data_rate: 3, tx_power: 1, ch_mask: 7, ch_mask_cntl: 0, nb_trans: 1 };
+        let ans = UplinkMACCmd::LinkADRAns { power_ack: true, data_rate_ack: true, channel_mask_ack: true };
+        for _ in 1..ADR_LIMIT_ACCEPTED_ANSWERS {
+            state.handle_link_adr_ans(&req, &ans);
+        }
+        assert_eq!(state.max_data_rate, Some(3));
+        state.handle_link_adr_ans(&req, &ans);
+        assert_eq!(state.max_data_rate, None);
+
+    }
+
+    #[test]
+    fn test_join_adr_ch_mask() {
+
+        // DevEUI 0x0000000000000001 of devices.yaml (EU868) joins with the CFList channels 3..7
+        let dev_eui = 1;
+        let lorawan_config = lorawan_config::get_or_init();
+        let device_record = lorawan_config.devices.get(&dev_eui).unwrap();
+        let rf_region = lorawan_config.rf_region(device_record).unwrap();
+        let join_ans = JoinAnsParams {
+            phy_payload: Vec::new(),
+            session_keys: SessionKeys::V12x {
+                f_nwk_s_int_key: [1; 16],
+                s_nwk_s_int_key: [2; 16],
+                nwk_s_enc_key: [3; 16],
+            },
+            app_s_key: Some([4; 16]),
+            session_key_id: None,
+        };
+        devctx::init_db();
+        devctx::set_device_context(dev_eui, join_context(lorawan_config, device_record, 0x01020304, join_ans));
+
+        // a strong link at DR0 makes the ADR raise the data rate, the CFList channels are kept
+        let dd_data = DDData {
+            gw_eui: 1, tmst: 0, sp_fact: 12, freq: 868.1, rssi: -60, snr: 10.0, tmms: 0, rx_time: std::time::SystemTime::now(),
+        };
+        for f_cnt in 0..rf_region.adr.window_size_snr as u32 {
+            handle_uplink(dev_eui, rf_region, None, std::slice::from_ref(&dd_data), "SF12BW125", f_cnt, true);
+        }
+        let mut ctx = devctx::get_device_context(dev_eui).unwrap();
+        let link_adr_req = ctx.mac_state_mut().queue
+            .iter()
+            .find_map(|cmd| match cmd {
+                DownlinkMACCmd::LinkADRReq { ch_mask, ch_mask_cntl, .. } => Some((*ch_mask, *ch_mask_cntl)),
+                _ => None,
+            });
+        assert_eq!(link_adr_req, Some((0x00ff, 0)));
+
+        // fixed channel plans
+        let channels = |ch_indexes: &[u8]| ch_indexes.iter().map(|ch_index| (*ch_index, (0, 0))).collect();
+        assert_eq!(link_adr_ch_mask(Region::US915, &channels(&[8, 9, 10, 11, 12, 13, 14, 15, 65])), (0x0002, 5));
+        assert_eq!(link_adr_ch_mask(Region::CN470, &channels(&[16, 17, 18])), (0x0007, 1));
+        assert_eq!(link_adr_ch_mask(Region::CN470, &channels(&[0, 16])), (0, 6));
+
+    }
+
+}
@@ -0,0 +1,320 @@
+use std::{
+    collections::{HashMap, VecDeque},
+    sync::{
+        Mutex, OnceLock,
+        atomic::{AtomicU64, Ordering},
+    },
+    time::{Duration, SystemTime},
+};
+use anyhow::{ Result as AnyResult, anyhow };
+
+use crate::app_server::{DownlinkEvent, DownlinkStatus};
+
+/// The number of times an unacknowledged confirmed downlink is sent again before it is dropped
+pub const MAX_CONFIRMED_DOWNLINK_RETRIES: u8 = 3;
+
+/// An application downlink queued for a device
+#[derive(Debug, Clone, PartialEq)]
+pub struct AppDownlink {
+    pub id: u64,
+    pub f_port: u8,                      // 1..223
+    pub frm_payload: Vec<u8>,            // plain application data
+    pub confirmed: bool,                 // sent as Confirmed Data Down, acknowledged by the device
+    pub expires_at: Option<SystemTime>,  // it is dropped if not sent (or acknowledged) until then
+}
+impl AppDownlink {
+    fn is_expired(&self, now: SystemTime) -> bool {
+        self.expires_at.is_some_and(|at| at <= now)
+    }
+
+    fn event(&self, dev_eui: u64, status: DownlinkStatus) -> DownlinkEvent {
+        DownlinkEvent::new(dev_eui, self.id, self.f_port, status)
+    }
+}
+
+/// A confirmed downlink sent to the device, waiting for the ACK bit of the next uplink
+#[derive(Debug, Clone)]
+struct UnackedDownlink {
+    downlink: AppDownlink,
+    f_cnt_down: u32,  // AFCntDown of the last transmission
+    retries: u8,      // the number of times it has been sent again
+}
+
+/// The application downlinks of a device
+#[derive(Debug, Default)]
+struct DeviceQueue {
+    queue: VecDeque<AppDownlink>,
+    unacked: Option<UnackedDownlink>,
+}
+
+// The downlink queues, indexed by DevEUI
+static QUEUES: OnceLock<Mutex<HashMap<u64, DeviceQueue>>> = OnceLock::new();
+
+static NEXT_ID: AtomicU64 = AtomicU64::new(1);
+
+fn queues() -> &'static Mutex<HashMap<u64, DeviceQueue>> {
+    QUEUES.get_or_init(|| Mutex::new(HashMap::new()))
+}
+
+/// Queues an application downlink for a device
+///
+/// Returns the id of the downlink, the events of the Application Server refer to it.
+///
+/// # Arguments
+///
+/// * __`f_port`__\
+///   `FPort` of the downlink (1..223) \
+/// * __`ttl`__\
+///   The time the downlink is kept for, if limited \
+///
+pub fn enqueue(
+    dev_eui: u64,
+    f_port: u8,
+    frm_payload: Vec<u8>,
+    confirmed: bool,
+    ttl: Option<Duration>,
+) -> AnyResult<u64> {
+
+    if !(1..=223).contains(&f_port) {
+        return Err(anyhow!("invalid FPort: {} for an application downlink, DevEUI: 0x{:016x}", f_port, dev_eui));
+    }
+
+    let id = NEXT_ID.fetch_add(1, Ordering::Relaxed);
+    let expires_at = ttl.map(|ttl| SystemTime::now() + ttl);
+
+    queues()
+        .lock()
+        .unwrap()
+        .entry(dev_eui)
+        .or_default()
+        .queue
+        .push_back(AppDownlink { id, f_port, frm_payload, confirmed, expires_at });
+
+    Ok(id)
+
+}
+
+/// The AFCntDown of the confirmed downlink waiting for its acknowledgement
+///
+/// It is the `ConfFCnt` of the LoRaWAN 1.1+ MIC of an uplink with the ACK bit.
+///
+pub fn unacked_f_cnt_down(dev_eui: u64) -> Option<u32> {
+    queues()
+        .lock()
+        .unwrap()
+        .get(&dev_eui)
+        .and_then(|device_queue| device_queue.unacked.as_ref())
+        .map(|unacked| unacked.f_cnt_down)
+}
+
+/// Applies the ACK bit of an uplink to the confirmed downlink sent before it
+///
+/// An acknowledged downlink is done. An unacknowledged one is sent again in the next
+/// downlink, or dropped after `MAX_CONFIRMED_DOWNLINK_RETRIES`.
+///
+/// Returns the `Acked` or `Nacked` event of the downlink.
+///
+/// # Specification
+///
+/// LoRaWAN L2 1.0.4                                                             \
+/// 4.3.1.2 Message acknowledge bit and acknowledgment procedure (ACK in FCtrl) \
+///
+pub fn handle_uplink(dev_eui: u64, ack: bool) -> Option<DownlinkEvent> {
+
+    let mut queues = queues().lock().unwrap();
+    let device_queue = queues.get_mut(&dev_eui)?;
+    let unacked = device_queue.unacked.as_ref()?;
+
+    if ack {
+        let unacked = device_queue.unacked.take()?;
+        return Some(unacked.downlink.event(dev_eui, DownlinkStatus::Acked));
+    }
+
+    if unacked.retries >= MAX_CONFIRMED_DOWNLINK_RETRIES {
+        let unacked = device_queue.unacked.take()?;
+        log::warn!(
+            "Downlink: {} is dropped, no ACK after {} retries, DevEUI: 0x{:016x}",
+            unacked.downlink.id, unacked.retries, dev_eui,
+        );
+        return Some(unacked.downlink.event(dev_eui, DownlinkStatus::Nacked));
+    }
+
+    None
+
+}
+
+/// Takes the application downlink of the next Class A downlink
+///
+/// An unacknowledged confirmed downlink is sent again before the queued ones. The expired
+/// downlinks are dropped; a downlink larger than `max_frm_payload` stays queued for a faster
+/// data rate. A taken confirmed downlink waits for its acknowledgement.
+///
+/// Returns the downlink, the `FPending` bit (more downlinks are queued) and the `Expired` events.
+///
+/// # Arguments
+///
+/// * __`max_frm_payload`__\
+///   The room for the `FRMPayload` at the data rate of the downlink \
+/// * __`f_cnt_down`__\
+///   The AFCntDown of the downlink \
+///
+pub fn take_for_downlink(
+    dev_eui: u64,
+    max_frm_payload: usize,
+    f_cnt_down: u32,
+    now: SystemTime,
+) -> (Option<AppDownlink>, bool, Vec<DownlinkEvent>) {
+
+    let mut queues = queues().lock().unwrap();
+    let Some(device_queue) = queues.get_mut(&dev_eui) else {
+        return (None, false, Vec::new());
+    };
+
+    let mut events = Vec::new();
+
+    if device_queue.unacked.as_ref().is_some_and(|unacked| unacked.downlink.is_expired(now)) {
+        let unacked = device_queue.unacked.take().unwrap();
+        events.push(unacked.downlink.event(dev_eui, DownlinkStatus::Expired));
+    }
+    device_queue.queue.retain(|downlink| {
+        if downlink.is_expired(now) {
+            events.push(downlink.event(dev_eui, DownlinkStatus::Expired));
+        }
+        !downlink.is_expired(now)
+    });
+
+    let downlink = match &mut device_queue.unacked {
+        Some(unacked) if unacked.downlink.frm_payload.len() <= max_frm_payload => {
+            unacked.f_cnt_down = f_cnt_down;
+            unacked.retries += 1;
+            Some(unacked.downlink.clone())
+        },
+        Some(_) => None,
+        None if device_queue.queue.front().is_some_and(|d| d.frm_payload.len() <= max_frm_payload) => {
+            let downlink = device_queue.queue.pop_front().unwrap();
+            if downlink.confirmed {
+                device_queue.unacked = Some(UnackedDownlink { downlink: downlink.clone(), f_cnt_down, retries: 0 });
+            }
+            Some(downlink)
+        },
+        None => None,
+    };
+
+    let f_pending = !device_queue.queue.is_empty() || (downlink.is_none() && device_queue.unacked.is_some());
+
+    (downlink, f_pending, events)
+
+}
+
+/// Puts back an application downlink taken for a Class A downlink that could not be sent
+///
+/// A first transmission goes back to the front of the queue, a retransmission of the
+/// unacknowledged confirmed downlink is not counted.
+///
+pub fn restore(dev_eui: u64, downlink: AppDownlink) {
+
+    let mut queues = queues().lock().unwrap();
+    let device_queue = queues.entry(dev_eui).or_default();
+
+    match &mut device_queue.unacked {
+        Some(unacked) if unacked.downlink.id == downlink.id && unacked.retries > 0 => unacked.retries -= 1,
+        Some(unacked) if unacked.downlink.id == downlink.id => {
+            device_queue.unacked = None;
+            device_queue.queue.push_front(downlink);
+        },
+        _ => device_queue.queue.push_front(downlink),
+    }
+
+}
+
+
+#[cfg(test)]
+mod tests {
+
+    use super::*;
+
+    #[test]
+    fn test_confirmed_downlink() {
+
+        let dev_eui = 0x0102030405060708;
+        let now = SystemTime::now();
+
+        assert!(enqueue(dev_eui, 0, vec![1], false, None).is_err());
+        let id1 = enqueue(dev_eui, 10, vec![1, 2, 3], true, None).unwrap();
+        let id2 = enqueue(dev_eui, 11, vec![4; 20], false, None).unwrap();
+        let id3 = enqueue(dev_eui, 12, vec![5], false, Some(Duration::from_secs(60))).unwrap();
+
+        let (downlink, f_pending, events) = take_for_downlink(dev_eui, 51, 7, now);
+        assert_eq!(downlink.map(|d| d.id), Some(id1));
+        assert!(f_pending);
+        assert!(events.is_empty());
+        assert_eq!(unacked_f_cnt_down(dev_eui), Some(7));
+
+        // not acknowledged: sent again with a new AFCntDown
+        assert!(handle_uplink(dev_eui, false).is_none());
+        let (downlink, _, _) = take_for_downlink(dev_eui, 51, 8, now);
+        assert_eq!(downlink.map(|d| d.id), Some(id1));
+        assert_eq!(unacked_f_cnt_down(dev_eui), Some(8));
+        assert_eq!(handle_uplink(dev_eui, true).map(|e| e.status), Some(DownlinkStatus::Acked));
+        assert!(unacked_f_cnt_down(dev_eui).is_none());
+
+        // too large for the data rate: stays queued
+        let (downlink, f_pending, _) = take_for_downlink(dev_eui, 11, 9, now);
+        assert!(downlink.is_none());
+        assert!(f_pending);
+        let (downlink, _, _) = take_for_downlink(dev_eui, 51, 9, now);
+        assert_eq!(downlink.map(|d| d.id), Some(id2));
+
+        // expired
+        let (downlink, f_pending, events) = take_for_downlink(dev_eui, 51, 10, now + Duration::from_secs(61));
+        assert!(downlink.is_none());
+        assert!(!f_pending);
+        assert_eq!(events, vec![DownlinkEvent::new(dev_eui, id3, 12, DownlinkStatus::Expired)]);
+
+        // not acknowledged after MAX_CONFIRMED_DOWNLINK_RETRIES
+        let id4 = enqueue(dev_eui, 13, vec![6], true, None).unwrap();
+        take_for_downlink(dev_eui, 51, 11, now);
+        for i in 0..MAX_CONFIRMED_DOWNLINK_RETRIES as u32 {
+            assert!(handle_uplink(dev_eui, false).is_none());
+            take_for_downlink(dev_eui, 51, 12 + i, now);
+        }
+        let event = handle_uplink(dev_eui, false);
+        assert_eq!(event, Some(DownlinkEvent::new(dev_eui, id4, 13, DownlinkStatus::Nacked)));
+
+    }
+
+    #[test]
+    fn test_restore() {
+
+        let dev_eui = 0x0102030405060709;
+        let now = SystemTime::now();
+
+        let id1 = enqueue(dev_eui, 10, vec![1], true, None).unwrap();
+        let id2 = enqueue(dev_eui, 11, vec![2], false, None).unwrap();
+
+        // a first transmission goes back to the queue, it is not waiting for an ACK
+        let (downlink, _, _) = take_for_downlink(dev_eui, 51, 1, now);
+        restore(dev_eui, downlink.unwrap());
+        assert!(unacked_f_cnt_down(dev_eui).is_none());
+
+        // a retransmission is not counted
+        let (downlink, _, _) = take_for_downlink(dev_eui, 51, 2, now);
+        assert_eq!(downlink.as_ref().map(|d| d.id), Some(id1));
+        let (downlink, _, _) = take_for_downlink(dev_eui, 51, 3, now);
+        restore(dev_eui, downlink.unwrap());
+        for i in 0..MAX_CONFIRMED_DOWNLINK_RETRIES as u32 {
+            assert!(handle_uplink(dev_eui, false).is_none());
+            take_for_downlink(dev_eui, 51, 4 + i, now);
+        }
+        assert_eq!(handle_uplink(dev_eui, false).map(|e| e.id), Some(id1));
+
+        let (downlink, f_pending, _) = take_for_downlink(dev_eui, 51, 8, now);
+        assert_eq!(downlink.as_ref().map(|d| d.id), Some(id2));
+        assert!(!f_pending);
+        restore(dev_eui, downlink.unwrap());
+        let (downlink, _, _) = take_for_downlink(dev_eui, 51, 9, now);
+        assert_eq!(downlink.map(|d| d.id), Some(id2));
+
+    }
+
+}
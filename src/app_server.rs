@@ -0,0 +1,250 @@
+use std::{
+    sync::OnceLock,
+    thread,
+    time::Duration,
+};
+use serde::{Serialize, Deserialize};
+use anyhow::{ Result as AnyResult, anyhow };
+
+use crate::{
+    settings,
+    lorawan::mac_cmds::Battery,
+};
+
+/// The device status sent to the Application Server
+#[derive(Debug, Serialize)]
+pub struct DevStatusReport {
+    #[serde(rename = "DevEUI")]
+    pub dev_eui: String,
+    #[serde(rename = "Battery", skip_serializing_if = "Option::is_none")]
+    pub battery: Option<u8>,        // 0: external power, 1..254: level, 255: unknown
+    #[serde(rename = "BatteryLevel", skip_serializing_if = "Option::is_none")]
+    pub battery_level: Option<f32>, // %, for measured battery levels only
+    #[serde(rename = "Margin", skip_serializing_if = "Option::is_none")]
+    pub margin: Option<i8>,         // dB
+}
+impl DevStatusReport {
+    /// Builds the report of a DevStatusAns with the values the service profile allows
+    pub fn new(dev_eui: u64, battery: Option<Battery>, margin: Option<i8>) -> Self {
+        DevStatusReport {
+            dev_eui: format!("{:016X}", dev_eui),
+            battery: battery.map(Battery::value),
+            battery_level: battery.and_then(Battery::percent),
+            margin,
+        }
+    }
+}
+
+/// The outcome of an application downlink
+#[derive(Debug, Clone, Copy, PartialEq, Serialize)]
+pub enum DownlinkStatus {
+    Acked,    // the device acknowledged the confirmed downlink
+    Nacked,   // the confirmed downlink was not acknowledged after the retries
+    Expired,  // the downlink was not sent or acknowledged in time
+}
+
+/// The event of an application downlink sent to the Application Server
+#[derive(Debug, PartialEq, Serialize)]
+pub struct DownlinkEvent {
+    #[serde(rename = "DevEUI")]
+    pub dev_eui: String,
+    #[serde(rename = "DownlinkId")]
+    pub id: u64,
+    #[serde(rename = "FPort")]
+    pub f_port: u8,
+    #[serde(rename = "Status")]
+    pub status: DownlinkStatus,
+}
+impl DownlinkEvent {
+    pub fn new(dev_eui: u64, id: u64, f_port: u8, status: DownlinkStatus) -> Self {
+        DownlinkEvent { dev_eui: format!("{:016X}", dev_eui), id, f_port, status }
+    }
+}
+
+/// An uplink with application data sent to the Application Server
+#[derive(Debug, Serialize)]
+pub struct UplinkReport {
+    #[serde(rename = "DevEUI")]
+    pub dev_eui: String,
+    #[serde(rename = "FCnt")]
+    pub f_cnt: u32,
+    #[serde(rename = "FPort")]
+    pub f_port: u8,
+    #[serde(rename = "FRMPayload")]
+    pub frm_payload: String,        // hex, decrypted unless SessionKeyID is present
+    #[serde(rename = "Confirmed")]
+    pub confirmed: bool,
+    #[serde(rename = "FCntReset")]
+    pub f_cnt_reset: bool,          // the device has reset its frame counter, accepted by the policy of the NS
+    #[serde(rename = "SessionKeyID", skip_serializing_if = "Option::is_none")]
+    pub session_key_id: Option<String>,
+    #[serde(rename = "DevAddr", skip_serializing_if = "Option::is_none")]
+    pub dev_addr: Option<String>,
+}
+impl UplinkReport {
+    pub fn new(dev_eui: u64, f_cnt: u32, f_port: u8, frm_payload: &[u8], confirmed: bool, f_cnt_reset: bool) -> Self {
+        UplinkReport {
+            dev_eui: format!("{:016X}", dev_eui),
+            f_cnt,
+            f_port,
+            frm_payload: hex::encode_upper(frm_payload),
+            confirmed,
+            f_cnt_reset,
+            session_key_id: None,
+            dev_addr: None,
+        }
+    }
+
+    /// Marks the `FRMPayload` as encrypted with the AppSKey the Join Server keeps for the
+    /// Application Server, which fetches it with the SessionKeyID
+    ///
+    /// # Specification
+    ///
+    /// LoRaWAN Backend Interfaces 1.0 - 6.1.2 Application Server requesting the AppSKey
+    ///
+    pub fn encrypted(self, session_key_id: &str, dev_addr: u32) -> Self {
+        UplinkReport {
+            session_key_id: Some(session_key_id.to_string()),
+            dev_addr: Some(format!("{:08X}", dev_addr)),
+            .. self
+        }
+    }
+}
+
+/// A message posted to the Application Server, its `Type` tells the kind of the message
+#[derive(Debug, Serialize)]
+#[serde(tag = "Type")]
+enum AsMessage<'a> {
+    Uplink(&'a UplinkReport),
+    DevStatus(&'a DevStatusReport),
+    DownlinkEvent(&'a DownlinkEvent),
+}
+
+/// An application downlink requested by the Application Server
+#[derive(Debug, Deserialize)]
+pub struct DownlinkRequest {
+    #[serde(rename = "DevEUI")]
+    pub dev_eui: String,
+    #[serde(rename = "FPort")]
+    pub f_port: u8,
+    #[serde(rename = "FRMPayload")]
+    pub frm_payload: String,        // hex, plain
+    #[serde(rename = "Confirmed", default)]
+    pub confirmed: bool,
+    #[serde(rename = "TTL", default)]
+    pub ttl: Option<u64>,           // seconds, kept until sent if not set
+}
+
+/// The answer to a `DownlinkRequest`, the events of the downlink refer to its id
+#[derive(Debug, Serialize, Deserialize)]
+pub struct DownlinkAnswer {
+    #[serde(rename = "DownlinkId")]
+    pub id: u64,
+}
+
+/// Forwards an uplink to the Application Server
+///
+/// The uplink is posted in the background, failures are logged.
+///
+pub fn forward_uplink(report: UplinkReport) {
+    thread::spawn(move || {
+        if let Err(e) = post(&AsMessage::Uplink(&report)) {
+            log::warn!("Uplink FCnt: {} of DevEUI: {} is not forwarded: {}", report.f_cnt, report.dev_eui, e);
+        }
+    });
+}
+
+/// Forwards a device status report to the Application Server
+///
+/// The report is posted in the background, failures are logged.
+///
+pub fn forward_dev_status(report: DevStatusReport) {
+    thread::spawn(move || {
+        if let Err(e) = post(&AsMessage::DevStatus(&report)) {
+            log::warn!("DevStatus of DevEUI: {} is not forwarded: {}", report.dev_eui, e);
+        }
+    });
+}
+
+/// Forwards the event of an application downlink to the Application Server
+///
+/// The event is posted in the background, failures are logged.
+///
+pub fn forward_downlink_event(event: DownlinkEvent) {
+    thread::spawn(move || {
+        if let Err(e) = post(&AsMessage::DownlinkEvent(&event)) {
+            log::warn!("{:?} event of Downlink: {} of DevEUI: {} is not forwarded: {}", event.status, event.id, event.dev_eui, e);
+        }
+    });
+}
+
+/// Posts a JSON message to the Application Server (synchronous HTTP POST)
+fn post(msg: &AsMessage) -> AnyResult<()> {
+
+    let as_settings = &settings::get_or_init().remote_application_server;
+
+    let res = client()?
+        .post(&as_settings.url)
+        .json(msg)
+        .send()?;
+
+    if !res.status().is_success() {
+        return Err(anyhow!("HTTP error from Application Server {}: {}", as_settings.url, res.status()));
+    }
+
+    Ok(())
+
+}
+
+// The HTTP client of the Application Server, its connections are reused by the messages
+static CLIENT: OnceLock<reqwest::blocking::Client> = OnceLock::new();
+
+fn client() -> AnyResult<&'static reqwest::blocking::Client> {
+
+    if let Some(client) = CLIENT.get() {
+        return Ok(client);
+    }
+
+    let as_settings = &settings::get_or_init().remote_application_server;
+    let client = reqwest::blocking::Client::builder()
+        .timeout(Duration::from_secs(as_settings.timeout))
+        .build()?;
+
+    Ok(CLIENT.get_or_init(|| client))
+
+}
+
+
+#[cfg(test)]
+mod tests {
+
+    use super::*;
+
+    #[test]
+    fn test_message_type() {
+
+        let event = DownlinkEvent::new(1, 2, 3, DownlinkStatus::Acked);
+        let json = serde_json::to_value(AsMessage::DownlinkEvent(&event)).unwrap();
+        assert_eq!(json["Type"], "DownlinkEvent");
+        assert_eq!(json["DevEUI"], "0000000000000001");
+        assert_eq!(json["Status"], "Acked");
+
+        let report = UplinkReport::new(1, 5, 10, &[0xab], true, false);
+        let json = serde_json::to_value(AsMessage::Uplink(&report)).unwrap();
+        assert_eq!(json["Type"], "Uplink");
+        assert_eq!(json["FRMPayload"], "AB");
+        assert!(json.get("SessionKeyID").is_none());
+
+        let report = UplinkReport::new(1, 5, 10, &[0xab], true, false).encrypted("0a0b", 0x01020304);
+        let json = serde_json::to_value(AsMessage::Uplink(&report)).unwrap();
+        assert_eq!(json["SessionKeyID"], "0a0b");
+        assert_eq!(json["DevAddr"], "01020304");
+
+        let report = DevStatusReport::new(1, None, Some(-3));
+        let json = serde_json::to_value(AsMessage::DevStatus(&report)).unwrap();
+        assert_eq!(json["Type"], "DevStatus");
+        assert_eq!(json["Margin"], -3);
+
+    }
+
+}
@@ -0,0 +1,112 @@
+use std::{
+    convert::Infallible,
+    net::SocketAddr,
+    time::Duration,
+};
+use hyper::{
+    Body, Method, Request, Response, Server, StatusCode,
+    service::{make_service_fn, service_fn},
+};
+use anyhow::{ Result as AnyResult, anyhow };
+
+use crate::{
+    lorawan_config,
+    app_queue,
+    app_server::{DownlinkRequest, DownlinkAnswer},
+};
+
+/// Serves the downlink API of the Network Server for the Application Server
+///
+/// A `DownlinkRequest` is a JSON document POSTed to the root URL, the downlink is queued
+/// for the next Class A downlink of the device and its id is sent in the HTTP response.
+///
+pub async fn run(addr: &str) -> AnyResult<()> {
+
+    let addr: SocketAddr = addr.parse()
+        .map_err(|e| anyhow!("invalid downlink API address: {}: {}", addr, e))?;
+
+    let make_svc = make_service_fn(|_conn| async {
+        Ok::<_, Infallible>(service_fn(handle_http_request))
+    });
+
+    log::info!("Downlink API is listening on {}", addr);
+
+    Server::try_bind(&addr)?
+        .serve(make_svc)
+        .await?;
+
+    Ok(())
+
+}
+
+async fn handle_http_request(req: Request<Body>) -> Result<Response<Body>, Infallible> {
+
+    if req.method() != Method::POST {
+        return Ok(http_response(StatusCode::METHOD_NOT_ALLOWED, String::new()));
+    }
+
+    let body = match hyper::body::to_bytes(req.into_body()).await {
+        Ok(body) => body,
+        Err(e) => return Ok(http_response(StatusCode::BAD_REQUEST, e.to_string())),
+    };
+
+    match handle_message(&body) {
+        Ok(answer) => Ok(http_response(StatusCode::OK, answer)),
+        Err(e) => {
+            log::warn!("Downlink request is rejected: {:?}", e);
+            Ok(http_response(StatusCode::BAD_REQUEST, e.to_string()))
+        },
+    }
+
+}
+
+fn http_response(status: StatusCode, body: String) -> Response<Body> {
+    let mut res = Response::new(Body::from(body));
+    *res.status_mut() = status;
+    res.headers_mut().insert(hyper::header::CONTENT_TYPE, "application/json".parse().unwrap());
+    res
+}
+
+/// Queues the downlink of a `DownlinkRequest` and returns the JSON `DownlinkAnswer`
+pub fn handle_message(body: &[u8]) -> AnyResult<String> {
+
+    let req: DownlinkRequest = serde_json::from_slice(body)?;
+
+    let dev_eui = u64::from_str_radix(&req.dev_eui, 16)
+        .map_err(|e| anyhow!("invalid DevEUI: {}: {}", req.dev_eui, e))?;
+    if !lorawan_config::get_or_init().devices.contains_key(&dev_eui) {
+        return Err(anyhow!("unknown DevEUI: 0x{:016x}", dev_eui));
+    }
+    let frm_payload = hex::decode(&req.frm_payload)
+        .map_err(|e| anyhow!("invalid FRMPayload: {}: {}", req.frm_payload, e))?;
+
+    let id = app_queue::enqueue(
+        dev_eui, req.f_port, frm_payload, req.confirmed, req.ttl.map(Duration::from_secs),
+    )?;
+    log::info!("Downlink: {} FPort: {} is queued for DevEUI: 0x{:016x}", id, req.f_port, dev_eui);
+
+    Ok(serde_json::to_string(&DownlinkAnswer { id })?)
+
+}
+
+
+#[cfg(test)]
+mod tests {
+
+    use super::*;
+
+    #[test]
+    fn test_downlink_request() {
+
+        // DevEUI 0x0000000000000002 of devices.yaml
+        let answer = handle_message(br#"{"DevEUI":"0000000000000002","FPort":10,"FRMPayload":"0102","Confirmed":true,"TTL":60}"#).unwrap();
+        let answer: DownlinkAnswer = serde_json::from_str(&answer).unwrap();
+        assert!(answer.id > 0);
+
+        assert!(handle_message(br#"{"DevEUI":"00000000000000FF","FPort":10,"FRMPayload":"01"}"#).is_err());
+        assert!(handle_message(br#"{"DevEUI":"0000000000000002","FPort":0,"FRMPayload":"01"}"#).is_err());
+        assert!(handle_message(br#"{"DevEUI":"0000000000000002","FPort":10,"FRMPayload":"0x01"}"#).is_err());
+
+    }
+
+}
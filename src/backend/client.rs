@@ -0,0 +1,311 @@
+use std::{
+    time::Duration,
+    sync::{
+        OnceLock,
+        atomic::{AtomicU32, Ordering},
+    },
+};
+use anyhow::{ Result as AnyResult, anyhow };
+
+use crate::{
+    lorawan_config,
+    join_server::{JoinReqParams, JoinAnsParams, SessionKeys},
+};
+use super::messages::{self, JoinReq, JoinAns, KeyEnvelope};
+
+static TRANSACTION_ID: AtomicU32 = AtomicU32::new(1);
+
+/// Forwards a Join-Request to the external Join Server serving its JoinEUI
+///
+/// The session keys of the JoinAns are accepted in clear or wrapped with one of the
+/// configured KEKs.
+///
+/// # Arguments
+///
+/// * __`req`__ - the same parameters that an internal Join Server would get
+/// * __`mac_version`__ - the LoRaWAN version of the device (e.g. `1.0.4`, `1.1`)
+///
+/// # Specification
+///
+/// LoRaWAN Backend Interfaces 1.0 - 6.1 Activation of OTA End-Device
+///
+pub fn join_req(req: &JoinReqParams, mac_version: &str) -> AnyResult<JoinAnsParams> {
+
+    let phy_payload = req.phy_payload;
+    if phy_payload.len() != 23 {
+        return Err(anyhow!("invalid Join-Request length: {}", phy_payload.len()));
+    }
+    let join_eui = u64::from_le_bytes(phy_payload[1..9].try_into().unwrap());
+    let dev_eui = u64::from_le_bytes(phy_payload[9..17].try_into().unwrap());
+
+    forward_join_req(req, mac_version, "JoinReq", join_eui, dev_eui)
+
+}
+
+/// Forwards a Rejoin-Request to the external Join Server serving the JoinEUI of the device
+///
+/// # Arguments
+///
+/// * __`req`__ - the same parameters that an internal Join Server would get
+/// * __`mac_version`__ - the LoRaWAN version of the device (e.g. `1.1`)
+/// * __`join_eui`__ - carried by a type 1 Rejoin-Request, the JoinEUI of the last
+///   Join-Request for types 0 and 2
+/// * __`dev_eui`__ - the DevEUI of the Rejoin-Request
+///
+/// # Specification
+///
+/// LoRaWAN Backend Interfaces 1.0 - 6.1 Activation of OTA End-Device
+///
+pub fn rejoin_req(req: &JoinReqParams, mac_version: &str, join_eui: u64, dev_eui: u64) -> AnyResult<JoinAnsParams> {
+    forward_join_req(req, mac_version, "RejoinReq", join_eui, dev_eui)
+}
+
+fn forward_join_req(
+    req: &JoinReqParams,
+    mac_version: &str,
+    message_type: &str,
+    join_eui: u64,
+    dev_eui: u64,
+) -> AnyResult<JoinAnsParams> {
+
+    let phy_payload = req.phy_payload;
+
+    let bi_config = &lorawan_config::get_or_init().server_config.backend_interfaces;
+    let url = bi_config.join_server_url(join_eui)
+        .ok_or_else(|| anyhow!("no Join Server is configured for JoinEUI: 0x{:016x}", join_eui))?;
+
+    let join_req = JoinReq {
+        protocol_version: messages::PROTOCOL_VERSION.to_string(),
+        sender_id: format!("{:06X}", req.net_id),
+        receiver_id: format!("{:016X}", join_eui),
+        transaction_id: TRANSACTION_ID.fetch_add(1, Ordering::Relaxed),
+        message_type: message_type.to_string(),
+        mac_version: mac_version.to_string(),
+        phy_payload: hex::encode_upper(phy_payload),
+        dev_eui: format!("{:016X}", dev_eui),
+        dev_addr: format!("{:08X}", req.dev_addr),
+        dl_settings: format!("{:02X}", req.dl_settings),
+        rx_delay: req.rx_delay,
+        cf_list: req.cf_list.map(hex::encode_upper),
+    };
+
+    let join_ans = send_join_req(url, &join_req)?;
+
+    join_ans_to_params(&join_ans, |label| bi_config.kek(label))
+
+}
+
+/// Sends a JoinReq (RejoinReq) message and waits for the JoinAns (RejoinAns) (synchronous HTTP POST)
+pub fn send_join_req(url: &str, join_req: &JoinReq) -> AnyResult<JoinAns> {
+
+    log::trace!("JoinReq sent to {}: {:?}", url, join_req);
+
+    let res = client()?
+        .post(url)
+        .json(join_req)
+        .send()?;
+
+    if !res.status().is_success() {
+        return Err(anyhow!("HTTP error from Join Server {}: {}", url, res.status()));
+    }
+
+    let join_ans: JoinAns = res.json()?;
+
+    log::trace!("JoinAns received from {}: {:?}", url, join_ans);
+
+    let expected_type = if join_req.message_type == "RejoinReq" { "RejoinAns" } else { "JoinAns" };
+    if join_ans.message_type != expected_type || join_ans.transaction_id != join_req.transaction_id {
+        return Err(anyhow!(
+            "unexpected answer from Join Server: {} TransactionID: {}", join_ans.message_type, join_ans.transaction_id
+        ));
+    }
+
+    Ok(join_ans)
+
+}
+
+// The HTTP client of the external Join Servers, its connections are reused by the messages
+static CLIENT: OnceLock<reqwest::blocking::Client> = OnceLock::new();
+
+fn client() -> AnyResult<&'static reqwest::blocking::Client> {
+
+    if let Some(client) = CLIENT.get() {
+        return Ok(client);
+    }
+
+    let bi_config = &lorawan_config::get_or_init().server_config.backend_interfaces;
+    let client = reqwest::blocking::Client::builder()
+        .timeout(Duration::from_secs(bi_config.timeout))
+        .build()?;
+
+    Ok(CLIENT.get_or_init(|| client))
+
+}
+
+/// Extracts the Join-Accept and the session keys from a JoinAns
+///
+/// The presence of `NwkSKey` identifies a LoRaWAN 1.0.x session, otherwise
+/// `FNwkSIntKey`, `SNwkSIntKey` and `NwkSEncKey` are expected.
+///
+pub fn join_ans_to_params<'a, F>(join_ans: &JoinAns, get_kek: F) -> AnyResult<JoinAnsParams>
+where F: Fn(&str) -> Option<&'a [u8; 16]> + Copy {
+
+    if !join_ans.result.is_success() {
+        return Err(anyhow!(
+            "Join Server rejected the Join-Request: {} {}",
+            join_ans.result.result_code, join_ans.result.description.as_deref().unwrap_or(""),
+        ));
+    }
+
+    let phy_payload = hex::decode(
+        join_ans.phy_payload.as_ref().ok_or_else(|| anyhow!("PHYPayload is missing from JoinAns"))?
+    )?;
+
+    let get_key = |name: &str, key: &Option<KeyEnvelope>| -> AnyResult<[u8; 16]> {
+        key.as_ref()
+            .ok_or_else(|| anyhow!("{} is missing from JoinAns", name))?
+            .unwrap(get_kek)
+    };
+
+    // The AppSKey may be delivered to the Application Server only, it gets the SessionKeyID instead
+    let app_s_key = join_ans.app_s_key.as_ref().map(|key| key.unwrap(get_kek)).transpose()?;
+    if app_s_key.is_none() && join_ans.session_key_id.is_none() {
+        return Err(anyhow!("neither AppSKey nor SessionKeyID is in JoinAns, TransactionID: {}", join_ans.transaction_id));
+    }
+
+    let session_keys = if join_ans.nwk_s_key.is_some() {
+        SessionKeys::V10x {
+            nwk_s_key: get_key("NwkSKey", &join_ans.nwk_s_key)?,
+        }
+    } else {
+        SessionKeys::V12x {
+            f_nwk_s_int_key: get_key("FNwkSIntKey", &join_ans.f_nwk_s_int_key)?,
+            s_nwk_s_int_key: get_key("SNwkSIntKey", &join_ans.s_nwk_s_int_key)?,
+            nwk_s_enc_key: get_key("NwkSEncKey", &join_ans.nwk_s_enc_key)?,
+        }
+    };
+
+    Ok(JoinAnsParams { phy_payload, session_keys, app_s_key, session_key_id: join_ans.session_key_id.clone() })
+
+}
+
+
+#[cfg(test)]
+mod tests {
+
+    use super::*;
+    use std::{
+        io::{Read, Write},
+        net::TcpListener,
+        thread,
+    };
+    use crate::backend::messages::BackendResult;
+
+    // A mock Join Server answering a single JoinReq (RejoinReq) with a wrapped AppSKey and a clear NwkSKey
+    fn mock_join_server() -> String {
+
+        let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+        let addr = listener.local_addr().unwrap();
+
+        thread::spawn(move || {
+            let (mut stream, _) = listener.accept().unwrap();
+            let mut buf = Vec::new();
+            let mut chunk = [0_u8; 1024];
+            let join_req: JoinReq = loop {
+                let n = stream.read(&mut chunk).unwrap();
+                buf.extend_from_slice(&chunk[..n]);
+                let text = String::from_utf8_lossy(&buf);
+                if let Some(pos) = text.find("\r\n\r\n") {
+                    if let Ok(join_req) = serde_json::from_str(&text[pos + 4..]) {
+                        break join_req;
+                    }
+                }
+            };
+            let join_ans = JoinAns {
+                protocol_version: messages::PROTOCOL_VERSION.to_string(),
+                sender_id: join_req.receiver_id,
+                receiver_id: join_req.sender_id,
+                transaction_id: join_req.transaction_id,
+                message_type: if join_req.message_type == "RejoinReq" { "RejoinAns" } else { "JoinAns" }.to_string(),
+                result: BackendResult::success(),
+                phy_payload: Some("20".to_string() + &"00".repeat(16)),
+                lifetime: None,
+                s_nwk_s_int_key: None,
+                f_nwk_s_int_key: None,
+                nwk_s_enc_key: None,
+                nwk_s_key: Some(KeyEnvelope {
+                    kek_label: String::new(),
+                    aes_key: "000102030405060708090a0b0c0d0e0f".to_string(),
+                }),
+                app_s_key: Some(KeyEnvelope {
+                    kek_label: "as-kek".to_string(),
+                    aes_key: "1fa68b0a8112b447aef34bd8fb5a7b829d3e862371d2cfe5".to_string(),
+                }),
+                session_key_id: None,
+            };
+            let body = serde_json::to_string(&join_ans).unwrap();
+            write!(
+                stream,
+                "HTTP/1.1 200 OK\r\nContent-Type: application/json\r\nContent-Length: {}\r\nConnection: close\r\n\r\n{}",
+                body.len(), body,
+            ).unwrap();
+        });
+
+        format!("http://{}/", addr)
+
+    }
+
+    #[test]
+    fn test_join_req_with_mock_join_server() {
+
+        let url = mock_join_server();
+
+        let join_req = JoinReq {
+            protocol_version: messages::PROTOCOL_VERSION.to_string(),
+            sender_id: "B00001".to_string(),
+            receiver_id: "AABBCCDDAABBCCDD".to_string(),
+            transaction_id: 7,
+            message_type: "JoinReq".to_string(),
+            mac_version: "1.0.4".to_string(),
+            phy_payload: "00".repeat(23),
+            dev_eui: "0000000000000003".to_string(),
+            dev_addr: "00000003".to_string(),
+            dl_settings: "00".to_string(),
+            rx_delay: 1,
+            cf_list: None,
+        };
+
+        let join_ans = send_join_req(&url, &join_req).unwrap();
+        assert_eq!(join_ans.transaction_id, 7);
+
+        let kek: [u8; 16] = hex::decode("000102030405060708090a0b0c0d0e0f").unwrap().try_into().unwrap();
+        let params = join_ans_to_params(&join_ans, |label| (label == "as-kek").then_some(&kek)).unwrap();
+        assert_eq!(params.phy_payload.len(), 17);
+        let SessionKeys::V10x { nwk_s_key } = params.session_keys else { panic!("LoRaWAN 1.0.x keys expected") };
+        assert_eq!(hex::encode(nwk_s_key), "000102030405060708090a0b0c0d0e0f");
+        assert_eq!(params.app_s_key.map(hex::encode).as_deref(), Some("00112233445566778899aabbccddeeff"));
+
+        assert!(join_ans_to_params(&join_ans, |_| None).is_err());
+
+        // the AppSKey kept for the Application Server is absent from the session
+        let mut join_ans = join_ans;
+        join_ans.app_s_key = None;
+        assert!(join_ans_to_params(&join_ans, |_| None).is_err());
+        join_ans.session_key_id = Some("0000000000000003000001".to_string());
+        let params = join_ans_to_params(&join_ans, |_| None).unwrap();
+        assert!(params.app_s_key.is_none());
+
+        // a Rejoin-Request is answered with a RejoinAns
+        let url = mock_join_server();
+        let rejoin_req = JoinReq {
+            message_type: "RejoinReq".to_string(),
+            phy_payload: "C0".to_string() + &"00".repeat(18),
+            transaction_id: 8,
+            .. join_req
+        };
+        let rejoin_ans = send_join_req(&url, &rejoin_req).unwrap();
+        assert_eq!(rejoin_ans.message_type, "RejoinAns");
+
+    }
+
+}
@@ -0,0 +1,196 @@
+use serde::{Deserialize, Serialize};
+
+use anyhow::{ Result as AnyResult, anyhow };
+
+use crate::lorawan::crypto::{
+    key_wrap,
+    utils::key_from_string,
+};
+
+pub const PROTOCOL_VERSION: &str = "1.0";
+
+//********************************
+//* KeyEnvelope
+//********************************
+
+/// A session key in clear (empty `KEKLabel`) or wrapped with the KEK identified by `KEKLabel`
+#[derive(Debug, Serialize, Deserialize, Default, Clone)]
+pub struct KeyEnvelope {
+    #[serde(rename = "KEKLabel", default)]
+    pub kek_label: String,
+    #[serde(rename = "AESKey")]
+    pub aes_key: String,    // hex
+}
+impl KeyEnvelope {
+    /// A key sent in clear (the transport is expected to be secured)
+    pub fn clear(key: &[u8; 16]) -> Self {
+        KeyEnvelope { kek_label: String::new(), aes_key: hex::encode_upper(key) }
+    }
+    /// A key wrapped with the KEK identified by its label, or in clear if there is no KEK
+    pub fn new(key: &[u8; 16], kek: Option<(&str, &[u8; 16])>) -> Self {
+        match kek {
+            Some((kek_label, kek)) => KeyEnvelope {
+                kek_label: kek_label.to_string(),
+                aes_key: hex::encode_upper(key_wrap::wrap_key(kek, key)),
+            },
+            None => KeyEnvelope::clear(key),
+        }
+    }
+    /// Returns the session key, unwrapping it with the KEK returned by `get_kek` if needed
+    pub fn unwrap<'a, F>(&self, get_kek: F) -> AnyResult<[u8; 16]>
+    where F: Fn(&str) -> Option<&'a [u8; 16]> {
+        if self.kek_label.is_empty() {
+            return key_from_string(&self.aes_key);
+        }
+        let kek = get_kek(&self.kek_label)
+            .ok_or_else(|| anyhow!("unknown KEKLabel: {}", self.kek_label))?;
+        key_wrap::unwrap_key(kek, &hex::decode(&self.aes_key)?)
+    }
+}
+
+//********************************
+//* Result
+//********************************
+
+#[derive(Debug, Serialize, Deserialize, Clone)]
+pub struct BackendResult {
+    #[serde(rename = "ResultCode")]
+    pub result_code: String,
+    #[serde(rename = "Description", default, skip_serializing_if = "Option::is_none")]
+    pub description: Option<String>,
+}
+impl BackendResult {
+    pub fn success() -> Self {
+        BackendResult { result_code: "Success".to_string(), description: None }
+    }
+    pub fn failure(result_code: &str, description: String) -> Self {
+        BackendResult { result_code: result_code.to_string(), description: Some(description) }
+    }
+    pub fn is_success(&self) -> bool {
+        self.result_code == "Success"
+    }
+}
+
+//********************************
+//* JoinReq
+//********************************
+
+/// JoinReq, also used for RejoinReq with a Rejoin-Request in `PHYPayload`
+///
+/// # Specification
+///
+/// LoRaWAN Backend Interfaces 1.0 - 6.1 Activation of OTA End-Device \
+/// LoRaWAN Backend Interfaces 1.1 - 6.2 Rejoin Procedure             \
+///
+#[derive(Debug, Serialize, Deserialize, Clone)]
+pub struct JoinReq {
+    #[serde(rename = "ProtocolVersion")]
+    pub protocol_version: String,
+    #[serde(rename = "SenderID")]
+    pub sender_id: String,       // NetID (hex)
+    #[serde(rename = "ReceiverID")]
+    pub receiver_id: String,     // JoinEUI (hex)
+    #[serde(rename = "TransactionID")]
+    pub transaction_id: u32,
+    #[serde(rename = "MessageType")]
+    pub message_type: String,    // "JoinReq"
+    #[serde(rename = "MACVersion")]
+    pub mac_version: String,
+    #[serde(rename = "PHYPayload")]
+    pub phy_payload: String,     // hex
+    #[serde(rename = "DevEUI")]
+    pub dev_eui: String,         // hex
+    #[serde(rename = "DevAddr")]
+    pub dev_addr: String,        // hex
+    #[serde(rename = "DLSettings")]
+    pub dl_settings: String,     // hex
+    #[serde(rename = "RxDelay")]
+    pub rx_delay: u8,
+    #[serde(rename = "CFList", default, skip_serializing_if = "Option::is_none")]
+    pub cf_list: Option<String>, // hex
+}
+
+//********************************
+//* JoinAns
+//********************************
+
+/// JoinAns, also used for RejoinAns
+#[derive(Debug, Serialize, Deserialize, Clone)]
+pub struct JoinAns {
+    #[serde(rename = "ProtocolVersion")]
+    pub protocol_version: String,
+    #[serde(rename = "SenderID")]
+    pub sender_id: String,
+    #[serde(rename = "ReceiverID")]
+    pub receiver_id: String,
+    #[serde(rename = "TransactionID")]
+    pub transaction_id: u32,
+    #[serde(rename = "MessageType")]
+    pub message_type: String,    // "JoinAns"
+    #[serde(rename = "Result")]
+    pub result: BackendResult,
+    #[serde(rename = "PHYPayload", default, skip_serializing_if = "Option::is_none")]
+    pub phy_payload: Option<String>,
+    #[serde(rename = "Lifetime", default, skip_serializing_if = "Option::is_none")]
+    pub lifetime: Option<u32>,
+    #[serde(rename = "SNwkSIntKey", default, skip_serializing_if = "Option::is_none")]
+    pub s_nwk_s_int_key: Option<KeyEnvelope>,
+    #[serde(rename = "FNwkSIntKey", default, skip_serializing_if = "Option::is_none")]
+    pub f_nwk_s_int_key: Option<KeyEnvelope>,
+    #[serde(rename = "NwkSEncKey", default, skip_serializing_if = "Option::is_none")]
+    pub nwk_s_enc_key: Option<KeyEnvelope>,
+    #[serde(rename = "NwkSKey", default, skip_serializing_if = "Option::is_none")]
+    pub nwk_s_key: Option<KeyEnvelope>,
+    #[serde(rename = "AppSKey", default, skip_serializing_if = "Option::is_none")]
+    pub app_s_key: Option<KeyEnvelope>,
+    #[serde(rename = "SessionKeyID", default, skip_serializing_if = "Option::is_none")]
+    pub session_key_id: Option<String>,
+}
+
+//********************************
+//* AppSKeyReq / AppSKeyAns
+//********************************
+
+/// # Specification
+///
+/// LoRaWAN Backend Interfaces 1.0 - 6.1.2 Application Server requesting the AppSKey
+///
+#[derive(Debug, Serialize, Deserialize, Clone)]
+pub struct AppSKeyReq {
+    #[serde(rename = "ProtocolVersion")]
+    pub protocol_version: String,
+    #[serde(rename = "SenderID")]
+    pub sender_id: String,       // AS-ID
+    #[serde(rename = "ReceiverID")]
+    pub receiver_id: String,     // JoinEUI (hex)
+    #[serde(rename = "TransactionID")]
+    pub transaction_id: u32,
+    #[serde(rename = "MessageType")]
+    pub message_type: String,    // "AppSKeyReq"
+    #[serde(rename = "DevEUI")]
+    pub dev_eui: String,         // hex
+    #[serde(rename = "SessionKeyID")]
+    pub session_key_id: String,
+}
+
+#[derive(Debug, Serialize, Deserialize, Clone)]
+pub struct AppSKeyAns {
+    #[serde(rename = "ProtocolVersion")]
+    pub protocol_version: String,
+    #[serde(rename = "SenderID")]
+    pub sender_id: String,
+    #[serde(rename = "ReceiverID")]
+    pub receiver_id: String,
+    #[serde(rename = "TransactionID")]
+    pub transaction_id: u32,
+    #[serde(rename = "MessageType")]
+    pub message_type: String,    // "AppSKeyAns"
+    #[serde(rename = "Result")]
+    pub result: BackendResult,
+    #[serde(rename = "DevEUI")]
+    pub dev_eui: String,
+    #[serde(rename = "AppSKey", default, skip_serializing_if = "Option::is_none")]
+    pub app_s_key: Option<KeyEnvelope>,
+    #[serde(rename = "SessionKeyID")]
+    pub session_key_id: String,
+}
@@ -0,0 +1,5 @@
+/// LoRaWAN Backend Interfaces messages
+pub mod messages;
+
+/// HTTP client of the external Join Servers
+pub mod client;
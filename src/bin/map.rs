@@ -12,24 +12,33 @@ fn main() {
         collected_dd_data: vec![
             DDData {
                 gw_eui: 0x0000000011111111,
+                tmst: 0,
                 freq: 0.0,
                 sp_fact: 7,
                 rssi: 0,
                 snr: 0.0,
+                tmms: 0,
+                rx_time: SystemTime::now(),
             },
             DDData {
                 gw_eui: 0x0000000022222222,
+                tmst: 0,
                 freq: 0.0,
                 sp_fact: 7,
                 rssi: 0,
                 snr: 0.0,
+                tmms: 0,
+                rx_time: SystemTime::now(),
             },
             DDData {
                 gw_eui: 0x0000000033333333,
+                tmst: 0,
                 freq: 0.0,
                 sp_fact: 7,
                 rssi: 0,
                 snr: 0.0,
+                tmms: 0,
+                rx_time: SystemTime::now(),
             },
 
         ],
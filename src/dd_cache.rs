@@ -21,10 +21,13 @@ static DD_CACHE: OnceLock<Mutex<HashMap<DDSubject, CollectedDDDataWithTimestamp>
 #[derive(Debug)]
 pub struct DDData {
     pub gw_eui: u64,
+    pub tmst: i64,
     pub sp_fact: u8,
     pub freq: f32,
     pub rssi: i32,
     pub snr: f32,
+    pub tmms: i64,           // GPS time of the reception in ms, 0 if the gateway is not GPS synchronized
+    pub rx_time: SystemTime, // server time of the reception
 }
 impl fmt::Display for DDData {
     fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
@@ -119,14 +122,12 @@ pub fn take_collected_data(dd_subject: &DDSubject) -> Vec<DDData> {
         .lock()
         .unwrap();
 
+    // The caller has been waiting DD_PERIOD since the first copy arrived,
+    // so the entry is always older than DD_PERIOD at this point
     match dd_cache.remove(dd_subject)
     {
         Some(collected_dd_data_with_timestamp) => {
-            if collected_dd_data_with_timestamp.ts.elapsed().unwrap() > DD_PERIOD {
-                vec!()
-            } else {
-                collected_dd_data_with_timestamp.collected_dd_data
-            }
+            collected_dd_data_with_timestamp.collected_dd_data
         },
         None => {
             vec!()
@@ -7,7 +7,49 @@ use std::{
         HashMap,
         HashSet,
     },
+    time::SystemTime,
 };
+use anyhow::{ Result as AnyResult, anyhow };
+
+use crate::{
+    mac_state::MACState,
+    adr::ADRState,
+    lorawan::{MACVersion, mac_cmds::Battery},
+    lorawan_config::{self, ActivationType, DeviceRecord, LorawanConfig},
+};
+
+
+/// The Class A receive window parameters of a device
+#[derive(Default, Clone, Debug, PartialEq)]
+pub struct RxParams {
+    pub rx1_delay: u8,      // s
+    pub rx1_dr_offset: u8,
+    pub rx2_dr: u8,
+    pub rx2_freq: u32,      // Hz
+    pub rx1_dl_freqs: HashMap<u8, u32>, // Hz, RX1 frequencies set by DlChannelReq, indexed by ChIndex
+}
+
+/// The uplink parameters of a device, set by MAC commands
+#[derive(Default, Clone, Debug, PartialEq)]
+pub struct MACParams {
+    pub data_rate: u8,
+    pub tx_power: u8,
+    pub nb_trans: u8,
+    pub max_duty_cycle: u8,              // aggregated duty cycle = 1 / 2^max_duty_cycle
+    pub max_eirp: u8,                    // TXParamSetupReq MaxEIRP index
+    pub uplink_dwell_time: bool,
+    pub downlink_dwell_time: bool,
+    pub adr_ack_limit_exp: Option<u8>,   // ADR_ACK_LIMIT = 2^limit_exp
+    pub adr_ack_delay_exp: Option<u8>,   // ADR_ACK_DELAY = 2^delay_exp
+}
+
+/// The latest status reported by a device in DevStatusAns
+#[derive(Clone, Debug, PartialEq)]
+pub struct DevStatus {
+    pub battery: Battery,
+    pub margin: i8,              // dB
+    pub received_at: SystemTime,
+}
 
 
 #[derive(Default, Clone)]
@@ -19,17 +61,22 @@ pub struct DeviceContextV10x {
     // pub f_nwk_s_int_key: [u8; 16],
     // pub s_nwk_s_int_key: [u8; 16],
     // pub nwk_s_enc_key: [u8; 16],
-    pub app_s_key: [u8; 16],
+    pub app_s_key: Option<[u8; 16]>, // absent when the Join Server delivers it to the Application Server only
+    pub session_key_id: Option<String>,
     pub dev_addr: u32,
 
-    pub f_cnt_up: u32,
+    pub f_cnt_up: u32,             // the lowest acceptable FCntUp
     pub f_cnt_down: u32,           // = n_f_cnt_down + a_f_cnt_down
     // pub n_f_cnt_down: u32,
     // pub a_f_cnt_down: u32,
     // pub rj_cnt_02: u16,
 
-    pub active_channels: HashMap<u8, (u32, u8)>, // 00000000 00000000 00000000 00000007 
-    pub pending_mac_cmds: HashSet<u8>,
+    pub rx_params: RxParams,
+    pub mac_params: MACParams,
+    pub active_channels: HashMap<u8, (u32, u8)>, // ChIndex: (Hz, MaxDR << 4 | MinDR)
+    pub mac_state: MACState,
+    pub dev_status: Option<DevStatus>,
+    pub adr_state: ADRState,
     pub recent_gateways: HashSet<u64>,
     pub best_gateway: u64,
 
@@ -43,16 +90,22 @@ pub struct DeviceContextV12x {
     pub f_nwk_s_int_key: [u8; 16], // = nwk_s_key
     pub s_nwk_s_int_key: [u8; 16], // = nwk_s_key
     pub nwk_s_enc_key: [u8; 16],   // = nwk_s_key
-    pub app_s_key: [u8; 16],
+    pub app_s_key: Option<[u8; 16]>, // absent when the Join Server delivers it to the Application Server only
+    pub session_key_id: Option<String>,
     pub dev_addr: u32,
+    pub join_eui: u64,             // of the last Join-Request, type 0 and 2 Rejoin-Requests don't carry it
 
-    pub f_cnt_up: u32,
+    pub f_cnt_up: u32,             // the lowest acceptable FCntUp
     pub n_f_cnt_down: u32,
     pub a_f_cnt_down: u32,
-    pub rj_cnt_02: u16,
+    pub rj_cnt_02: u16,            // the lowest acceptable RJcount0
 
-    pub active_channels: HashMap<u8, (u32, u8)>, // 00000000 00000000 00000000 00000007 
-    pub pending_mac_cmds: HashSet<u8>,
+    pub rx_params: RxParams,
+    pub mac_params: MACParams,
+    pub active_channels: HashMap<u8, (u32, u8)>, // ChIndex: (Hz, MaxDR << 4 | MinDR)
+    pub mac_state: MACState,
+    pub dev_status: Option<DevStatus>,
+    pub adr_state: ADRState,
     pub recent_gateways: HashSet<u64>,
     pub best_gateway: u64,
 
@@ -64,6 +117,153 @@ pub enum DeviceContext {
     V12x(DeviceContextV12x),
 }
 
+impl DeviceContext {
+    pub fn dev_addr(&self) -> u32 {
+        match self {
+            DeviceContext::V10x(ctx) => ctx.dev_addr,
+            DeviceContext::V12x(ctx) => ctx.dev_addr,
+        }
+    }
+
+    /// The lowest acceptable FCntUp
+    pub fn f_cnt_up(&self) -> u32 {
+        match self {
+            DeviceContext::V10x(ctx) => ctx.f_cnt_up,
+            DeviceContext::V12x(ctx) => ctx.f_cnt_up,
+        }
+    }
+
+    /// The FCntDown of the next MAC command downlink (NFCntDown for LoRaWAN 1.1+)
+    pub fn n_f_cnt_down(&self) -> u32 {
+        match self {
+            DeviceContext::V10x(ctx) => ctx.f_cnt_down,
+            DeviceContext::V12x(ctx) => ctx.n_f_cnt_down,
+        }
+    }
+
+    pub fn n_f_cnt_down_mut(&mut self) -> &mut u32 {
+        match self {
+            DeviceContext::V10x(ctx) => &mut ctx.f_cnt_down,
+            DeviceContext::V12x(ctx) => &mut ctx.n_f_cnt_down,
+        }
+    }
+
+    /// The FCntDown of the next application downlink (AFCntDown for LoRaWAN 1.1+)
+    pub fn a_f_cnt_down(&self) -> u32 {
+        match self {
+            DeviceContext::V10x(ctx) => ctx.f_cnt_down,
+            DeviceContext::V12x(ctx) => ctx.a_f_cnt_down,
+        }
+    }
+
+    pub fn a_f_cnt_down_mut(&mut self) -> &mut u32 {
+        match self {
+            DeviceContext::V10x(ctx) => &mut ctx.f_cnt_down,
+            DeviceContext::V12x(ctx) => &mut ctx.a_f_cnt_down,
+        }
+    }
+
+    pub fn rx_params(&self) -> &RxParams {
+        match self {
+            DeviceContext::V10x(ctx) => &ctx.rx_params,
+            DeviceContext::V12x(ctx) => &ctx.rx_params,
+        }
+    }
+
+    pub fn rx_params_mut(&mut self) -> &mut RxParams {
+        match self {
+            DeviceContext::V10x(ctx) => &mut ctx.rx_params,
+            DeviceContext::V12x(ctx) => &mut ctx.rx_params,
+        }
+    }
+
+    pub fn mac_params(&self) -> &MACParams {
+        match self {
+            DeviceContext::V10x(ctx) => &ctx.mac_params,
+            DeviceContext::V12x(ctx) => &ctx.mac_params,
+        }
+    }
+
+    pub fn mac_params_mut(&mut self) -> &mut MACParams {
+        match self {
+            DeviceContext::V10x(ctx) => &mut ctx.mac_params,
+            DeviceContext::V12x(ctx) => &mut ctx.mac_params,
+        }
+    }
+
+    pub fn app_s_key(&self) -> Option<&[u8; 16]> {
+        match self {
+            DeviceContext::V10x(ctx) => ctx.app_s_key.as_ref(),
+            DeviceContext::V12x(ctx) => ctx.app_s_key.as_ref(),
+        }
+    }
+
+    pub fn session_key_id(&self) -> Option<&str> {
+        match self {
+            DeviceContext::V10x(ctx) => ctx.session_key_id.as_deref(),
+            DeviceContext::V12x(ctx) => ctx.session_key_id.as_deref(),
+        }
+    }
+
+    pub fn active_channels(&self) -> &HashMap<u8, (u32, u8)> {
+        match self {
+            DeviceContext::V10x(ctx) => &ctx.active_channels,
+            DeviceContext::V12x(ctx) => &ctx.active_channels,
+        }
+    }
+
+    pub fn active_channels_mut(&mut self) -> &mut HashMap<u8, (u32, u8)> {
+        match self {
+            DeviceContext::V10x(ctx) => &mut ctx.active_channels,
+            DeviceContext::V12x(ctx) => &mut ctx.active_channels,
+        }
+    }
+
+    pub fn mac_state_mut(&mut self) -> &mut MACState {
+        match self {
+            DeviceContext::V10x(ctx) => &mut ctx.mac_state,
+            DeviceContext::V12x(ctx) => &mut ctx.mac_state,
+        }
+    }
+
+    pub fn adr_state_mut(&mut self) -> &mut ADRState {
+        match self {
+            DeviceContext::V10x(ctx) => &mut ctx.adr_state,
+            DeviceContext::V12x(ctx) => &mut ctx.adr_state,
+        }
+    }
+
+    /// The gateways that received the recent uplinks of the device
+    pub fn recent_gateways(&self) -> &HashSet<u64> {
+        match self {
+            DeviceContext::V10x(ctx) => &ctx.recent_gateways,
+            DeviceContext::V12x(ctx) => &ctx.recent_gateways,
+        }
+    }
+
+    pub fn recent_gateways_mut(&mut self) -> &mut HashSet<u64> {
+        match self {
+            DeviceContext::V10x(ctx) => &mut ctx.recent_gateways,
+            DeviceContext::V12x(ctx) => &mut ctx.recent_gateways,
+        }
+    }
+
+    /// The gateway that received the last uplink with the best SNR
+    pub fn best_gateway_mut(&mut self) -> &mut u64 {
+        match self {
+            DeviceContext::V10x(ctx) => &mut ctx.best_gateway,
+            DeviceContext::V12x(ctx) => &mut ctx.best_gateway,
+        }
+    }
+
+    pub fn dev_status_mut(&mut self) -> &mut Option<DevStatus> {
+        match self {
+            DeviceContext::V10x(ctx) => &mut ctx.dev_status,
+            DeviceContext::V12x(ctx) => &mut ctx.dev_status,
+        }
+    }
+}
+
 static DB: OnceLock<Mutex<HashMap<u64, DeviceContext>>> = OnceLock::new();
 
 pub fn init_db() {
@@ -73,7 +273,7 @@ pub fn init_db() {
     let dev_eui_10: u64 = 0xaabbccddaabbcc10;
     let device_context_10 = DeviceContextV10x{
         nwk_s_key: hex::decode("aabbccddaabbccddaabbccddaabbccdd").unwrap().try_into().unwrap(),
-        app_s_key: hex::decode("aabbccddaabbccddaabbccddaabbccdd").unwrap().try_into().unwrap(),
+        app_s_key: Some(hex::decode("aabbccddaabbccddaabbccddaabbccdd").unwrap().try_into().unwrap()),
         dev_addr: 0x11223344, 
         .. DeviceContextV10x::default()
     };
@@ -82,7 +282,7 @@ pub fn init_db() {
         f_nwk_s_int_key: hex::decode("aabbccddaabbccddaabbccddaabbccdd").unwrap().try_into().unwrap(),
         s_nwk_s_int_key: hex::decode("aabbccddaabbccddaabbccddaabbccdd").unwrap().try_into().unwrap(),
         nwk_s_enc_key: hex::decode("aabbccddaabbccddaabbccddaabbccdd").unwrap().try_into().unwrap(),
-        app_s_key: hex::decode("aabbccddaabbccddaabbccddaabbccdd").unwrap().try_into().unwrap(),
+        app_s_key: Some(hex::decode("aabbccddaabbccddaabbccddaabbccdd").unwrap().try_into().unwrap()),
         dev_addr: 0x11223344, 
         .. DeviceContextV12x::default()
     };
@@ -91,17 +291,177 @@ pub fn init_db() {
     // db.insert(dev_eui_10, DeviceContext::V10x(device_context_10));
     // db.insert(dev_eui_12, DeviceContext::V12x(device_context_12));
     
-    let db = HashMap::from([
+    let mut db = HashMap::from([
         (dev_eui_10, DeviceContext::V10x(device_context_10)),
         (dev_eui_12, DeviceContext::V12x(device_context_12)),
     ]);
 
+    // ABP devices start with the session preset in the device registry
+    let lorawan_config = lorawan_config::get_or_init();
+    for (dev_eui, device_record) in &lorawan_config.devices {
+        if device_record.ns.x_activation_type != ActivationType::ABP || !device_record.ns.x_is_enabled {
+            continue;
+        }
+        match abp_device_context(lorawan_config, device_record) {
+            Ok(ctx) => { db.insert(*dev_eui, ctx); },
+            Err(e) => log::error!("ABP session of DevEUI: 0x{:016x} is not loaded: {}", dev_eui, e),
+        }
+    }
+
 
     
     let _ = DB.set(Mutex::new(db));
 
 }
 
+/// The RX parameters a device uses right after its activation
+///
+/// Joined devices use the global parameters of the Join-Accept DLSettings and the RX2
+/// defaults of the RF region, ABP devices use the parameters of their device profile.
+///
+pub fn default_rx_params(lorawan_config: &LorawanConfig, device_record: &DeviceRecord) -> RxParams {
+
+    let global_params = &lorawan_config.server_config.ns.global_params_for_all_rf_regions;
+    let rf_region = lorawan_config.rf_region(device_record);
+
+    let mut rx_params = RxParams {
+        rx1_delay: global_params.receive_delay1 as u8,
+        rx1_dr_offset: global_params.rx1_dr_offset,
+        rx2_dr: rf_region.map(|r| r.default_rx2_dr).unwrap_or(0),
+        rx2_freq: rf_region.map(|r| (r.default_rx2_freq * 1_000_000.0).round() as u32).unwrap_or(0),
+        .. RxParams::default()
+    };
+
+    if device_record.ns.x_activation_type == ActivationType::ABP {
+        if let Some(device_profile) = lorawan_config.device_profile(device_record) {
+            if let Some(v) = device_profile.rx_delay1 { rx_params.rx1_delay = v.max(1); }
+            if let Some(v) = device_profile.rx_dr_offset1 { rx_params.rx1_dr_offset = v; }
+            if let Some(v) = device_profile.rx_data_rate2 { rx_params.rx2_dr = v; }
+            if let Some(v) = device_profile.rx_freq2 { rx_params.rx2_freq = (v * 1_000_000.0).round() as u32; }
+        }
+    }
+
+    rx_params
+
+}
+
+/// The uplink channels of a device after a Join-Accept
+///
+/// They are the default channels of the region completed by the CFList of the Join-Accept:
+/// the optional channels 3..7 of dynamic channel plans are added, the channels of fixed
+/// channel plans are limited to the enabled ones.
+///
+pub fn join_channels(lorawan_config: &LorawanConfig, device_record: &DeviceRecord) -> HashMap<u8, (u32, u8)> {
+
+    let Some(region) = lorawan_config.region(device_record) else {
+        return HashMap::new();
+    };
+
+    let mut channels: HashMap<u8, (u32, u8)> = region.default_channels()
+        .iter()
+        .enumerate()
+        .map(|(i, ch)| (i as u8, (ch.freq, ch.max_dr << 4 | ch.min_dr)))
+        .collect();
+
+    let Some(rf_region) = lorawan_config.rf_region(device_record).filter(|r| r.cf_list().is_some()) else {
+        return channels;
+    };
+    match rf_region.cf_list_type {
+        0 => channels.extend(rf_region.optional_channels().iter().map(|ch| {
+            (ch.ch_index, ((ch.freq * 1_000_000.0).round() as u32, ch.dr_range[1] << 4 | ch.dr_range[0]))
+        })),
+        _ => {
+            let enabled_channels = rf_region.enabled_channels();
+            channels.retain(|ch_index, _| enabled_channels.contains(ch_index));
+        },
+    }
+
+    channels
+
+}
+
+/// The session context of an ABP device, as preset in the device registry
+///
+/// The MACVersion of the device profile selects the LoRaWAN 1.0.x or 1.1+ context. The
+/// channels are the FactoryPresetFreqs of the device profile, their DR ranges are taken
+/// from the RF region.
+///
+pub fn abp_device_context(lorawan_config: &LorawanConfig, device_record: &DeviceRecord) -> AnyResult<DeviceContext> {
+
+    let session = device_record.ns.session_context.as_ref()
+        .ok_or_else(|| anyhow!("no session_context"))?;
+    let device_profile = lorawan_config.device_profile(device_record)
+        .ok_or_else(|| anyhow!("unknown device profile: {}", device_record.ns.device_profile_id))?;
+    let rf_region = lorawan_config.rf_region(device_record);
+
+    let dev_addr = device_record.ns.x_dev_addr.unwrap_or(session.dev_addr);
+    let rx_params = default_rx_params(lorawan_config, device_record);
+
+    let active_channels: HashMap<u8, (u32, u8)> = device_profile.factory_preset_freqs
+        .iter()
+        .flatten()
+        .enumerate()
+        .map(|(i, freq)| {
+            let freq = (freq * 1_000_000.0).round() as u32;
+            let dr_range = rf_region
+                .and_then(|r| r.channels.iter().find(|ch| (ch.freq * 1_000_000.0).round() as u32 == freq))
+                .map(|ch| ch.dr_range[1] << 4 | ch.dr_range[0])
+                .unwrap_or(0x50); // DR0..DR5
+            (i as u8, (freq, dr_range))
+        })
+        .collect();
+
+    match MACVersion::from_version_str(&device_profile.mac_version)? {
+        MACVersion::V10x => {
+            Ok(DeviceContext::V10x(DeviceContextV10x {
+                nwk_s_key: session.nwk_s_key.ok_or_else(|| anyhow!("no nwk_s_key"))?,
+                app_s_key: Some(session.app_s_key.ok_or_else(|| anyhow!("no app_s_key"))?),
+                dev_addr,
+                f_cnt_up: session.f_cnt_up,
+                f_cnt_down: session.f_cnt_down,
+                rx_params,
+                active_channels,
+                .. DeviceContextV10x::default()
+            }))
+        },
+        MACVersion::V12x => {
+            let nwk_s_key = session.nwk_s_key;
+            let key = |key: Option<[u8; 16]>, name: &str| {
+                key.or(nwk_s_key).ok_or_else(|| anyhow!("no {} nor nwk_s_key", name))
+            };
+            Ok(DeviceContext::V12x(DeviceContextV12x {
+                cipher_id: session.cipher_id.clone(),
+                f_nwk_s_int_key: key(session.f_nwk_s_int_key, "f_nwk_s_int_key")?,
+                s_nwk_s_int_key: key(session.s_nwk_s_int_key, "s_nwk_s_int_key")?,
+                nwk_s_enc_key: key(session.nwk_s_enc_key, "nwk_s_enc_key")?,
+                app_s_key: Some(session.app_s_key.ok_or_else(|| anyhow!("no app_s_key"))?),
+                dev_addr,
+                f_cnt_up: session.f_cnt_up,
+                n_f_cnt_down: session.n_f_cnt_down,
+                a_f_cnt_down: session.a_f_cnt_down,
+                rj_cnt_02: session.rj_count_02,
+                rx_params,
+                active_channels,
+                .. DeviceContextV12x::default()
+            }))
+        },
+    }
+
+}
+
+/// The devices that use a DevAddr, several devices MAY share the same DevAddr
+pub fn find_device_contexts(dev_addr: u32) -> Vec<(u64, DeviceContext)> {
+    DB
+        .get()
+        .unwrap()
+        .lock()
+        .unwrap()
+        .iter()
+        .filter(|(_, ctx)| ctx.dev_addr() == dev_addr)
+        .map(|(dev_eui, ctx)| (*dev_eui, ctx.clone()))
+        .collect()
+}
+
 pub fn get_device_context(dev_eui: u64) -> Option<DeviceContext> {
     match DB
         .get()
@@ -118,6 +478,26 @@ pub fn get_device_context(dev_eui: u64) -> Option<DeviceContext> {
         }
     }
 }
+pub fn set_device_context(dev_eui: u64, ctx: DeviceContext) {
+    DB
+        .get()
+        .unwrap()
+        .lock()
+        .unwrap()
+        .insert(dev_eui, ctx);
+}
+
+/// Updates the context of a device in place, `None` if the device has no context
+pub fn update_device_context<R>(dev_eui: u64, f: impl FnOnce(&mut DeviceContext) -> R) -> Option<R> {
+    DB
+        .get()
+        .unwrap()
+        .lock()
+        .unwrap()
+        .get_mut(&dev_eui)
+        .map(f)
+}
+
 pub fn set_f_cnt_up(dev_eui: u64, val: u32) {
     match
         DB
@@ -215,3 +595,73 @@ pub fn set_a_f_cnt_down(dev_eui: u64, val: u32) {
     };
 }
 
+/// Checks the RJcount0 of an authenticated type 0 or 2 Rejoin-Request
+///
+/// RJcount0 SHALL be strictly increasing within a session. The counter is not used up,
+/// `commit_rj_cnt_02` stores it once the Join Server has answered.
+///
+pub fn check_rj_cnt_02(dev_eui: u64, rj_count: u16) -> AnyResult<()> {
+    rj_cnt_02(dev_eui, rj_count, false)
+}
+
+/// Checks the RJcount0 of a type 0 or 2 Rejoin-Request again and stores the next acceptable
+/// value in `rj_cnt_02`
+pub fn commit_rj_cnt_02(dev_eui: u64, rj_count: u16) -> AnyResult<()> {
+    rj_cnt_02(dev_eui, rj_count, true)
+}
+
+fn rj_cnt_02(dev_eui: u64, rj_count: u16, is_commit: bool) -> AnyResult<()> {
+
+    let mut db = DB
+        .get()
+        .unwrap()
+        .lock()
+        .unwrap();
+
+    match db.get_mut(&dev_eui) {
+        Some(DeviceContext::V12x(ctx)) => {
+            if rj_count < ctx.rj_cnt_02 {
+                return Err(anyhow!(
+                    "replayed RJcount0: 0x{:04x}, expected at least: 0x{:04x}", rj_count, ctx.rj_cnt_02
+                ));
+            }
+            let next = rj_count.checked_add(1)
+                .ok_or_else(|| anyhow!("RJcount0 is exhausted for DevEUI: 0x{:016x}", dev_eui))?;
+            if is_commit {
+                ctx.rj_cnt_02 = next;
+            }
+            Ok(())
+        },
+        Some(DeviceContext::V10x(_)) => {
+            Err(anyhow!("Rejoin-Request from a LoRaWAN 1.0.x device, DevEUI: 0x{:016x}", dev_eui))
+        },
+        None => {
+            Err(anyhow!("no session context for DevEUI: 0x{:016x}", dev_eui))
+        },
+    }
+
+}
+
+
+#[cfg(test)]
+mod tests {
+
+    use super::*;
+
+    #[test]
+    fn test_join_channels() {
+
+        // DevEUI 0x0000000000000001 of devices.yaml, EU868 with the optional channels 3..7 in the CFList
+        let lorawan_config = lorawan_config::get_or_init();
+        let device_record = lorawan_config.devices.get(&1).unwrap();
+
+        let channels = join_channels(lorawan_config, device_record);
+        let mut ch_indexes: Vec<u8> = channels.keys().copied().collect();
+        ch_indexes.sort();
+        assert_eq!(ch_indexes, vec![0, 1, 2, 3, 4, 5, 6, 7]);
+        assert_eq!(channels[&0], (868_100_000, 0x50));
+        assert_eq!(channels[&7], (867_900_000, 0x50));
+
+    }
+
+}
@@ -0,0 +1,476 @@
+use std::{
+    net::{SocketAddr, UdpSocket},
+    time::{Duration, SystemTime},
+    sync::{
+        Mutex,
+        OnceLock,
+        atomic::{AtomicU16, Ordering},
+    },
+    collections::{HashMap, VecDeque},
+};
+use base64::engine::{ Engine as _, general_purpose::STANDARD as BASE64 };
+use anyhow::{ Result as AnyResult, anyhow };
+
+use crate::{
+    lorawan_config::{self, DeviceRecord, GlobalParams, RfRegionRecord},
+    devctx::DeviceContext,
+    duty_cycle,
+    scheduler::{self, TxClass},
+    dd_cache::DDData,
+    pktf::{self, RXPacket, TXPacket, PullResp},
+    lorawan::{
+        data_rate::DataRate,
+        region::Region,
+    },
+};
+
+static SOCKET: OnceLock<UdpSocket> = OnceLock::new();
+
+// The address of the last PULL_DATA message received from each gateway
+static GW_PULL_ADDRS: OnceLock<Mutex<HashMap<u64, SocketAddr>>> = OnceLock::new();
+
+static TOKEN: AtomicU16 = AtomicU16::new(0);
+
+/// The longest time the `tmst` counter of a gateway is extrapolated from its last reception
+pub const MAX_GW_CLOCK_AGE: Duration = Duration::from_secs(60);
+
+// The `tmst` and the server time of the last reception of each gateway
+static GW_CLOCKS: OnceLock<Mutex<HashMap<u64, (u32, SystemTime)>>> = OnceLock::new();
+
+// The Class A downlinks sent to the gateways until their windows start, indexed by (GwEUI, token)
+static SENT_DOWNLINKS: OnceLock<Mutex<HashMap<(u64, u16), SentDownlink>>> = OnceLock::new();
+
+/// A gateway that can send a Class A downlink, with its `tmst` at the end of the uplink
+#[derive(Debug, Clone, Copy)]
+struct TxGateway {
+    gw_eui: u64,
+    tmst: u32,
+    rx_time: SystemTime,   // server time of the end of the uplink
+    snr: f32,
+}
+
+/// A Class A downlink with the receive windows left to try
+struct ClassADownlink {
+    region: Region,
+    rf_region: Option<&'static RfRegionRecord>,
+    windows: [RxWindow; 2],                      // RX1, RX2
+    attempts: VecDeque<(TxGateway, usize)>,      // gateway, index of the window
+    phy_payload: Vec<u8>,
+    is_data_frame: bool,
+}
+
+/// A Class A downlink sent to a gateway, kept until its window starts
+struct SentDownlink {
+    sub_band_id: Option<u8>,
+    start: SystemTime,
+    downlink: ClassADownlink,
+}
+
+fn sent_downlinks() -> &'static Mutex<HashMap<(u64, u16), SentDownlink>> {
+    SENT_DOWNLINKS.get_or_init(|| Mutex::new(HashMap::new()))
+}
+
+fn gw_clocks() -> &'static Mutex<HashMap<u64, (u32, SystemTime)>> {
+    GW_CLOCKS.get_or_init(|| Mutex::new(HashMap::new()))
+}
+
+/// Records the `tmst` of a reception of a gateway
+pub fn set_gw_clock(gw_eui: u64, tmst: u32, rx_time: SystemTime) {
+    gw_clocks().lock().unwrap().insert(gw_eui, (tmst, rx_time));
+}
+
+/// The `tmst` of a gateway at a server time, extrapolated from its last reception
+///
+/// `None` if the gateway has not received anything within `MAX_GW_CLOCK_AGE`.
+///
+pub fn gw_tmst_at(gw_eui: u64, at: SystemTime) -> Option<u32> {
+    let (tmst, rx_time) = gw_clocks().lock().unwrap().get(&gw_eui).copied()?;
+    match at.duration_since(rx_time) {
+        Ok(elapsed) if elapsed <= MAX_GW_CLOCK_AGE => Some(tmst.wrapping_add(elapsed.as_micros() as u32)),
+        Err(e) if e.duration() <= MAX_GW_CLOCK_AGE => Some(tmst.wrapping_sub(e.duration().as_micros() as u32)),
+        _ => None,
+    }
+}
+
+pub fn init_downlink(socket: &UdpSocket) {
+    let _ = SOCKET.set(socket.try_clone().expect("UdpSocket::try_clone() must work"));
+    let _ = GW_PULL_ADDRS.set(Mutex::new(HashMap::new()));
+}
+
+pub fn set_gw_pull_addr(gw_eui: u64, addr: SocketAddr) {
+    GW_PULL_ADDRS
+        .get()
+        .unwrap()
+        .lock()
+        .unwrap()
+        .insert(gw_eui, addr);
+}
+
+pub fn get_gw_pull_addr(gw_eui: u64) -> Option<SocketAddr> {
+    GW_PULL_ADDRS
+        .get()
+        .unwrap()
+        .lock()
+        .unwrap()
+        .get(&gw_eui)
+        .copied()
+}
+
+/// Sends a PULL_RESP message with a single `txpk` to a gateway, returns its token
+pub fn send_pull_resp(gw_eui: u64, txpk: TXPacket) -> AnyResult<u16> {
+
+    let addr = get_gw_pull_addr(gw_eui)
+        .ok_or_else(|| anyhow!("no PULL_DATA has been received from Gateway: x{:016x}", gw_eui))?;
+
+    let token = TOKEN.fetch_add(1, Ordering::Relaxed);
+    let token_bytes = token.to_be_bytes();
+    let mut msg: Vec<u8> = vec![
+        pktf::ProtocolVersion::V2 as u8, token_bytes[0], token_bytes[1], pktf::MType::PullResp as u8,
+    ];
+    msg.extend_from_slice(serde_json::to_string(&PullResp { txpk })?.as_bytes());
+
+    SOCKET
+        .get()
+        .unwrap()
+        .send_to(&msg, addr)?;
+
+    log::trace!("PULL_RESP sent to Gateway: x{:016x} {}: {}", gw_eui, addr, String::from_utf8_lossy(&msg[4..]));
+
+    Ok(token)
+
+}
+
+/// Returns the gateway that received the uplink with the best SNR
+pub fn best_gateway(collected_dd_data: &[DDData]) -> Option<&DDData> {
+    collected_dd_data
+        .iter()
+        .max_by(|a, b| a.snr.total_cmp(&b.snr))
+}
+
+/// The transmission parameters of a Class A receive window
+#[derive(Debug, Clone, PartialEq)]
+pub struct RxWindow {
+    pub freq: u32,          // Hz
+    pub dr: u8,
+    pub modu: String,       // LORA|FSK
+    pub datr: String,
+    pub codr: String,
+    pub powe: i8,           // dBm EIRP
+    pub tmst_offset: u32,   // µs after the end of the uplink
+    pub max_dwell_time: Option<Duration>,
+    pub max_mac_payload: Option<usize>, // the maximum MACPayload size (M) at the data rate
+}
+impl RxWindow {
+    fn new(
+        region: Region,
+        rf_region: Option<&RfRegionRecord>,
+        freq: u32,
+        dr: u8,
+        delay: u32,
+        max_dwell_time: Option<Duration>,
+    ) -> AnyResult<Self> {
+        let (modu, datr, codr) = match region.data_rate(dr) {
+            Some(data_rate @ DataRate::LoRa { .. }) => ("LORA", data_rate.to_string(), "4/5"),
+            Some(data_rate @ DataRate::Fsk { .. }) => ("FSK", data_rate.to_string(), ""),
+            _ => return Err(anyhow!("DR{} is not a downlink data rate in {:?}", dr, region)),
+        };
+        Ok(RxWindow {
+            freq,
+            dr,
+            modu: modu.to_string(),
+            datr,
+            codr: codr.to_string(),
+            powe: max_tx_power(region, rf_region, freq),
+            tmst_offset: delay * 1_000_000,
+            max_dwell_time,
+            max_mac_payload: region.max_mac_payload(dr, max_dwell_time.is_some()),
+        })
+    }
+
+    /// The time-on-air of a downlink in the window
+    pub fn time_on_air(&self, region: Region, phy_payload_len: usize) -> Duration {
+        region.data_rate(self.dr)
+            .map(|data_rate| data_rate.time_on_air(phy_payload_len, 1, false))
+            .unwrap_or_default()
+    }
+}
+
+/// Converts a packet forwarder frequency (MHz) to Hz, channel frequencies are multiples of 100 Hz
+pub fn freq_hz(freq: f32) -> u32 {
+    (freq as f64 * 10_000.0).round() as u32 * 100
+}
+
+/// The maximum downlink EIRP at a frequency, limited by its sub-band
+fn max_tx_power(region: Region, rf_region: Option<&RfRegionRecord>, freq: u32) -> i8 {
+    rf_region
+        .and_then(|r| r.sub_band(freq))
+        .map(|sb| sb.max_tx_power as i8)
+        .or_else(|| rf_region.map(|r| r.default_max_eirp as i8).filter(|eirp| *eirp > 0))
+        .unwrap_or(region.default_max_eirp() as i8)
+}
+
+/// Computes the RX1 and RX2 windows of an uplink
+///
+/// RX1 uses the data rate of the uplink shifted by RX1DROffset, on the uplink frequency for
+/// dynamic channel plans (or the frequency set by DlChannelReq) and on the corresponding
+/// downlink channel for fixed channel plans. RX2 uses the RX2 parameters of the device.
+///
+/// A Join-Accept (no device context) is sent with RX1DROffset=0 and the RX2 defaults of the RF
+/// region, `JOIN_ACCEPT_DELAY1/2` after the uplink; data frames `RECEIVE_DELAY1/2` after it.
+///
+/// # Arguments
+///
+/// * __`ctx`__\
+///   The device context, `None` for a Join-Accept \
+///
+/// # Specification
+///
+/// LoRaWAN L2 1.0.4 - line #1069          \
+/// 3.3 Receive Windows                    \
+///
+pub fn rx_windows(
+    rx_packet: &RXPacket,
+    ctx: Option<&DeviceContext>,
+    region: Region,
+    rf_region: Option<&RfRegionRecord>,
+    global_params: &GlobalParams,
+) -> AnyResult<(RxWindow, RxWindow)> {
+
+    let uplink_freq = freq_hz(rx_packet.freq);
+    let uplink_dr = region.uplink_dr(&DataRate::from_datr(&rx_packet.datr)?)
+        .ok_or_else(|| anyhow!("{} is not an uplink data rate in {:?}", rx_packet.datr, region))?;
+
+    let (rx1_dr_offset, downlink_dwell_time, rx1_delay, rx2_delay) = match ctx {
+        Some(ctx) => {
+            let rx1_delay = ctx.rx_params().rx1_delay.max(1) as u32;
+            (ctx.rx_params().rx1_dr_offset, ctx.mac_params().downlink_dwell_time, rx1_delay, rx1_delay + 1)
+        },
+        None => (0, global_params.downlink_dwell_time != 0, global_params.join_accept_delay1, global_params.join_accept_delay2),
+    };
+
+    let rx1_freq = match ctx {
+        Some(ctx) if !region.is_fixed_plan() => ctx.active_channels()
+            .iter()
+            .find(|(_, (ch_freq, _))| *ch_freq == uplink_freq)
+            .map(|(ch_index, _)| *ch_index)
+            .or_else(|| rf_region.and_then(|r| r.ch_index(uplink_freq)))
+            .and_then(|ch_index| ctx.rx_params().rx1_dl_freqs.get(&ch_index).copied()),
+        _ => None,
+    };
+    let rx1_freq = rx1_freq
+        .or_else(|| region.rx1_freq(uplink_freq))
+        .ok_or_else(|| anyhow!("{} Hz is not an uplink channel in {:?}", uplink_freq, region))?;
+    let rx1_dr = region.rx1_dr(uplink_dr, rx1_dr_offset, downlink_dwell_time)
+        .ok_or_else(|| anyhow!("no RX1 data rate for DR{} RX1DROffset: {} in {:?}", uplink_dr, rx1_dr_offset, region))?;
+
+    let (rx2_freq, rx2_dr) = match (ctx, rf_region) {
+        (Some(ctx), _) if ctx.rx_params().rx2_freq != 0 => (ctx.rx_params().rx2_freq, ctx.rx_params().rx2_dr),
+        (_, Some(rf_region)) if rf_region.default_rx2_freq != 0.0 => {
+            ((rf_region.default_rx2_freq * 1_000_000.0).round() as u32, rf_region.default_rx2_dr)
+        },
+        _ => region.default_rx2(),
+    };
+
+    let max_dwell_time = (region.is_as923() && downlink_dwell_time).then_some(duty_cycle::AS923_MAX_DWELL_TIME);
+
+    Ok((
+        RxWindow::new(region, rf_region, rx1_freq, rx1_dr, rx1_delay, max_dwell_time)?,
+        RxWindow::new(region, rf_region, rx2_freq, rx2_dr, rx2_delay, max_dwell_time)?,
+    ))
+
+}
+
+/// Sends a Join-Accept in the RX1 window of the Join-Request
+pub fn send_join_accept(
+    collected_dd_data: &[DDData],
+    rx_packet: &RXPacket,
+    device_record: &DeviceRecord,
+    phy_payload: &[u8],
+) -> AnyResult<()> {
+    send_class_a(collected_dd_data, rx_packet, None, device_record, phy_payload)
+}
+
+/// Sends a Class A downlink Data frame in the RX1 window of an uplink
+pub fn send_data_frame(
+    collected_dd_data: &[DDData],
+    rx_packet: &RXPacket,
+    ctx: &DeviceContext,
+    device_record: &DeviceRecord,
+    phy_payload: &[u8],
+) -> AnyResult<()> {
+    send_class_a(collected_dd_data, rx_packet, Some(ctx), device_record, phy_payload)
+}
+
+/// Sends a Class A downlink in the first receive window that the gateways can serve
+///
+/// The gateways that received the uplink are tried by decreasing SNR, then the other recent
+/// gateways of the device whose `tmst` counter is known, RX1 first then RX2. A window is
+/// skipped when a data frame is larger than the maximum `MACPayload` size at its data rate,
+/// when the downlink would exceed the dwell-time limit or the duty-cycle budget of the gateway
+/// in the sub-band of the window, or when it overlaps a downlink already planned on the gateway.
+///
+/// The windows left are kept until the gateway acknowledges the downlink, a TX_ACK error
+/// sends it in the next one (see `handle_tx_ack_error`).
+///
+fn send_class_a(
+    collected_dd_data: &[DDData],
+    rx_packet: &RXPacket,
+    ctx: Option<&DeviceContext>,
+    device_record: &DeviceRecord,
+    phy_payload: &[u8],
+) -> AnyResult<()> {
+
+    let lorawan_config = lorawan_config::get_or_init();
+    let global_params = &lorawan_config.server_config.ns.global_params_for_all_rf_regions;
+    let region = lorawan_config.region(device_record)
+        .ok_or_else(|| anyhow!("unknown RF region of device profile: {}", device_record.ns.device_profile_id))?;
+    let rf_region = lorawan_config.rf_region(device_record);
+    let (rx1, rx2) = rx_windows(rx_packet, ctx, region, rf_region, global_params)?;
+
+    let mut gateways: Vec<TxGateway> = collected_dd_data
+        .iter()
+        .map(|dd_data| TxGateway { gw_eui: dd_data.gw_eui, tmst: dd_data.tmst as u32, rx_time: dd_data.rx_time, snr: dd_data.snr })
+        .collect();
+    gateways.sort_by(|a, b| b.snr.total_cmp(&a.snr));
+
+    if let (Some(ctx), Some(uplink)) = (ctx, gateways.first().copied()) {
+        let other_gateways: Vec<TxGateway> = ctx.recent_gateways()
+            .iter()
+            .filter(|gw_eui| !gateways.iter().any(|gw| gw.gw_eui == **gw_eui))
+            .filter_map(|gw_eui| Some(TxGateway {
+                gw_eui: *gw_eui,
+                tmst: gw_tmst_at(*gw_eui, uplink.rx_time)?,
+                rx_time: uplink.rx_time,
+                snr: f32::MIN,
+            }))
+            .collect();
+        gateways.extend(other_gateways);
+    }
+
+    transmit(ClassADownlink {
+        region,
+        rf_region,
+        windows: [rx1, rx2],
+        attempts: gateways.into_iter().flat_map(|gw| [(gw, 0), (gw, 1)]).collect(),
+        phy_payload: phy_payload.to_vec(),
+        is_data_frame: ctx.is_some(),
+    })
+
+}
+
+/// Sends a Class A downlink in its next possible window
+fn transmit(mut downlink: ClassADownlink) -> AnyResult<()> {
+
+    let mut send_error = None;
+
+    while let Some((gw, i)) = downlink.attempts.pop_front() {
+
+        let window = &downlink.windows[i];
+        let phy_payload = &downlink.phy_payload;
+
+        // The frame is built for RX1, RX2 may use a slower data rate (MHDR and MIC excluded)
+        if downlink.is_data_frame && window.max_mac_payload.is_some_and(|m| phy_payload.len().saturating_sub(5) > m) {
+            log::debug!("the downlink of {} bytes does not fit in DR{} at {} Hz", phy_payload.len(), window.dr, window.freq);
+            continue;
+        }
+        let toa = window.time_on_air(downlink.region, phy_payload.len());
+        if window.max_dwell_time.is_some_and(|max| toa > max) {
+            continue;
+        }
+        let start = gw.rx_time + Duration::from_micros(window.tmst_offset as u64);
+        if start <= SystemTime::now() {
+            continue;
+        }
+        let sub_band = downlink.rf_region.and_then(|r| r.sub_band(window.freq));
+        if sub_band.is_some_and(|sb| !duty_cycle::reserve(gw.gw_eui, sb, start, toa)) {
+            log::debug!("duty cycle of Gateway: x{:016x} is used up at {} Hz", gw.gw_eui, window.freq);
+            continue;
+        }
+        let release_airtime = || if let Some(sub_band) = sub_band {
+            duty_cycle::release(gw.gw_eui, sub_band.id, start);
+        };
+
+        let tmst = gw.tmst.wrapping_add(window.tmst_offset);
+        if !scheduler::reserve(gw.gw_eui, gw.tmst, tmst, toa, TxClass::ClassA) {
+            log::debug!("Gateway: x{:016x} has a planned downlink at tmst: {}", gw.gw_eui, tmst);
+            release_airtime();
+            continue;
+        }
+
+        let txpk = TXPacket {
+            imme: false,
+            tmst: Some(tmst),
+            freq: (window.freq as f64 / 1_000_000.0) as f32,
+            rfch: 0,
+            powe: window.powe,
+            modu: window.modu.clone(),
+            datr: window.datr.clone(),
+            codr: window.codr.clone(),
+            ipol: true,
+            size: phy_payload.len() as u16,
+            data: BASE64.encode(phy_payload),
+            .. TXPacket::default()
+        };
+        match send_pull_resp(gw.gw_eui, txpk) {
+            Ok(token) => {
+                scheduler::confirm(gw.gw_eui, tmst, token);
+                let mut sent = sent_downlinks().lock().unwrap();
+                sent.retain(|_, sent| sent.start > SystemTime::now());
+                sent.insert((gw.gw_eui, token), SentDownlink { sub_band_id: sub_band.map(|sb| sb.id), start, downlink });
+                return Ok(());
+            },
+            Err(e) => {
+                log::warn!("downlink cannot be sent to Gateway: x{:016x}: {}", gw.gw_eui, e);
+                scheduler::cancel(gw.gw_eui, tmst);
+                release_airtime();
+                send_error = Some(e);
+            },
+        }
+
+    }
+
+    Err(send_error.unwrap_or_else(|| anyhow!("no gateway can send the downlink within the payload size, duty-cycle and dwell-time limits")))
+
+}
+
+/// Sends a downlink the gateway has rejected (TX_ACK with an error) in its next possible window
+///
+/// The airtime of the rejected transmission is released. The downlink is lost, and reported
+/// as such, when no other window or gateway can send it.
+///
+pub fn handle_tx_ack_error(gw_eui: u64, token: u16) {
+
+    let Some(sent) = sent_downlinks().lock().unwrap().remove(&(gw_eui, token)) else {
+        return;
+    };
+    if let Some(sub_band_id) = sent.sub_band_id {
+        duty_cycle::release(gw_eui, sub_band_id, sent.start);
+    }
+
+    if let Err(e) = transmit(sent.downlink) {
+        log::error!("Downlink rejected by Gateway: x{:016x} token: {} is lost: {}", gw_eui, token, e);
+    }
+
+}
+
+
+#[cfg(test)]
+mod tests {
+
+    use super::*;
+
+    #[test]
+    fn test_gw_tmst_at() {
+
+        let gw_eui = 0x0a0b0c0d0e0f0101;
+        let t0 = SystemTime::now();
+        assert_eq!(gw_tmst_at(gw_eui, t0), None);
+
+        set_gw_clock(gw_eui, u32::MAX - 1_000, t0);
+        assert_eq!(gw_tmst_at(gw_eui, t0 + Duration::from_millis(2)), Some(999));
+        assert_eq!(gw_tmst_at(gw_eui, t0 - Duration::from_millis(1)), Some(u32::MAX - 2_000));
+        assert_eq!(gw_tmst_at(gw_eui, t0 + MAX_GW_CLOCK_AGE + Duration::from_secs(1)), None);
+
+    }
+
+}
@@ -0,0 +1,129 @@
+use std::{
+    collections::{HashMap, VecDeque},
+    sync::{Mutex, OnceLock},
+    time::{Duration, SystemTime},
+};
+
+use crate::lorawan_config::SubBandRecord;
+
+/// The sliding observation window of the duty-cycle limits
+pub const DUTY_CYCLE_WINDOW: Duration = Duration::from_secs(3600);
+
+/// The maximum dwell time of a transmission when the AS923 dwell time limitation is in force
+pub const AS923_MAX_DWELL_TIME: Duration = Duration::from_millis(400);
+
+// The downlinks of each gateway in each sub-band: (start, time-on-air), indexed by (GwEUI, sub-band id)
+type Ledger = HashMap<(u64, u8), VecDeque<(SystemTime, Duration)>>;
+
+static LEDGER: OnceLock<Mutex<Ledger>> = OnceLock::new();
+
+fn ledger() -> &'static Mutex<Ledger> {
+    LEDGER.get_or_init(|| Mutex::new(HashMap::new()))
+}
+
+/// The airtime a gateway has used in a sub-band during the window that ends at `at`
+pub fn used_airtime(gw_eui: u64, sub_band_id: u8, at: SystemTime) -> Duration {
+    airtime(&ledger().lock().unwrap(), gw_eui, sub_band_id, at)
+}
+
+fn airtime(ledger: &Ledger, gw_eui: u64, sub_band_id: u8, at: SystemTime) -> Duration {
+    let window_start = at.checked_sub(DUTY_CYCLE_WINDOW).unwrap_or(SystemTime::UNIX_EPOCH);
+    ledger
+        .get(&(gw_eui, sub_band_id))
+        .map(|txs| txs
+            .iter()
+            .filter(|(start, _)| *start >= window_start && *start <= at)
+            .map(|(_, toa)| *toa)
+            .sum()
+        )
+        .unwrap_or_default()
+}
+
+/// Reserves the airtime of a downlink if it fits in the duty-cycle budget and the dwell-time
+/// limit of a sub-band
+///
+/// The check and the record are done under the same lock, so concurrent downlinks cannot
+/// exceed the budget together. The transmissions that left the window are dropped, and so
+/// are the gateways without transmissions in the window.
+///
+/// # Arguments
+///
+/// * __`start`__\
+///   The planned start of the transmission \
+/// * __`toa`__\
+///   The time-on-air of the downlink \
+///
+pub fn reserve(gw_eui: u64, sub_band: &SubBandRecord, start: SystemTime, toa: Duration) -> bool {
+
+    if sub_band.max_dwell_time.is_some_and(|max| toa > Duration::from_millis(max as u64)) {
+        return false;
+    }
+    let budget = DUTY_CYCLE_WINDOW.mul_f64(sub_band.max_duty_cycle as f64 / 100.0);
+
+    let mut ledger = ledger().lock().unwrap();
+    if airtime(&ledger, gw_eui, sub_band.id, start) + toa > budget {
+        return false;
+    }
+
+    let window_start = start.checked_sub(DUTY_CYCLE_WINDOW).unwrap_or(SystemTime::UNIX_EPOCH);
+    ledger.retain(|_, txs| {
+        txs.retain(|(tx_start, _)| *tx_start >= window_start);
+        !txs.is_empty()
+    });
+    ledger.entry((gw_eui, sub_band.id)).or_default().push_back((start, toa));
+
+    true
+
+}
+
+/// Releases the airtime of a reserved downlink that is not sent
+pub fn release(gw_eui: u64, sub_band_id: u8, start: SystemTime) {
+    let mut ledger = ledger().lock().unwrap();
+    if let Some(txs) = ledger.get_mut(&(gw_eui, sub_band_id)) {
+        txs.retain(|(tx_start, _)| *tx_start != start);
+        if txs.is_empty() {
+            ledger.remove(&(gw_eui, sub_band_id));
+        }
+    }
+}
+
+
+#[cfg(test)]
+mod tests {
+
+    use super::*;
+
+    #[test]
+    fn test_duty_cycle() {
+
+        let sub_band = SubBandRecord {
+            id: 0, freq_range: vec![868.0, 868.6], max_duty_cycle: 1.0, max_tx_power: 16, max_dwell_time: None, line: 0,
+        };
+        let gw_eui = 0x0102030405060708;
+        let t0 = SystemTime::UNIX_EPOCH + Duration::from_secs(1_000_000);
+
+        // 1% of an hour: 36 s of airtime
+        for i in 0..35 {
+            assert!(reserve(gw_eui, &sub_band, t0 + Duration::from_secs(i * 10), Duration::from_secs(1)));
+        }
+        let t1 = t0 + Duration::from_secs(400);
+        assert!(!reserve(gw_eui, &sub_band, t1, Duration::from_secs(2)));
+        assert!(reserve(gw_eui + 1, &sub_band, t1, Duration::from_secs(2)));
+        assert!(reserve(gw_eui, &sub_band, t1, Duration::from_secs(1)));
+        assert_eq!(used_airtime(gw_eui, sub_band.id, t1), Duration::from_secs(36));
+
+        // A released downlink gives its airtime back
+        release(gw_eui, sub_band.id, t1);
+        assert_eq!(used_airtime(gw_eui, sub_band.id, t1), Duration::from_secs(35));
+
+        // The first transmissions leave the window, the idle gateway is evicted
+        assert!(reserve(gw_eui, &sub_band, t0 + DUTY_CYCLE_WINDOW + Duration::from_secs(15), Duration::from_secs(2)));
+        assert!(reserve(gw_eui, &sub_band, t1 + DUTY_CYCLE_WINDOW + Duration::from_secs(1), Duration::from_secs(1)));
+        assert!(!ledger().lock().unwrap().contains_key(&(gw_eui + 1, sub_band.id)));
+
+        let sub_band = SubBandRecord { max_dwell_time: Some(400), ..sub_band };
+        assert!(!reserve(gw_eui + 1, &sub_band, t1, Duration::from_millis(401)));
+
+    }
+
+}
@@ -0,0 +1,693 @@
+use std::{
+    collections::HashSet,
+    time::{Duration, SystemTime},
+};
+use anyhow::{ Result as AnyResult, anyhow };
+
+use crate::{
+    lorawan_config::{self, ActivationType, FCntResetPolicy, LorawanConfig},
+    devctx::{self, DeviceContext},
+    mac_state,
+    adr,
+    app_server::{self, DevStatusReport, UplinkReport},
+    app_queue::{self, AppDownlink},
+    dd_cache::DDData,
+    pktf::RXPacket,
+    downlink,
+    lorawan::{
+        MType, Major, Dir,
+        gps_time,
+        link_check,
+        data_rate::DataRate,
+        mac_cmds::{DownlinkMACCmd, PackedMACCmds, UplinkMACCmd},
+        crypto::{
+            crypto10,
+            crypto12::{self, FCntType},
+        },
+    },
+};
+
+/// An uplink Data frame authenticated with the session context of a device
+pub struct UplinkSession {
+    pub dev_eui: u64,
+    pub ctx: DeviceContext,
+    pub f_cnt32: u32,
+    pub is_f_cnt_reset: bool,  // FCnt is lower than the lowest acceptable FCntUp
+    pub is_retransmission: bool, // FCnt is the one of the last accepted uplink
+    pub f_ctrl: u8,
+    pub confirmed: bool,       // Confirmed Data Up, to be acknowledged
+}
+impl UplinkSession {
+    /// The ADR bit of `FCtrl`
+    pub fn adr(&self) -> bool {
+        self.f_ctrl & 0b10000000 != 0
+    }
+
+    /// The ADRACKReq bit of `FCtrl`
+    pub fn adr_ack_req(&self) -> bool {
+        self.f_ctrl & 0b01000000 != 0
+    }
+
+    /// The ACK bit of `FCtrl`: the confirmed downlink sent before is acknowledged
+    pub fn ack(&self) -> bool {
+        self.f_ctrl & 0b00100000 != 0
+    }
+
+    /// The FCntUp to acknowledge in the next downlink, for confirmed uplinks only
+    pub fn ack_f_cnt(&self) -> Option<u32> {
+        self.confirmed.then_some(self.f_cnt32)
+    }
+}
+
+/// Finds the session context that authenticates an uplink Data frame
+///
+/// The devices sharing the DevAddr are tried until the MIC matches. The 32-bit FCnt is
+/// tried as the FCnt of the last accepted uplink (a retransmission), then rebuilt from the
+/// 16 LSBs of the frame and the lowest acceptable FCntUp; if the MIC does not match, the
+/// FCnt is tried as if the device had reset its counter.
+///
+pub fn authenticate_uplink(phy_payload: &[u8], rx_packet: &RXPacket) -> AnyResult<UplinkSession> {
+
+    let dev_addr = u32::from_le_bytes(phy_payload[1..5].try_into().unwrap());
+    let f_cnt = u16::from_le_bytes(phy_payload[6..8].try_into().unwrap()) as u32;
+    let f_ctrl = phy_payload[5];
+    let confirmed = phy_payload[0] >> 5 == MType::ConfirmedDataUp as u8;
+    let mic: [u8; 4] = phy_payload[phy_payload.len() - 4..].try_into().unwrap();
+
+    let lorawan_config = lorawan_config::get_or_init();
+
+    for (dev_eui, ctx) in devctx::find_device_contexts(dev_addr) {
+
+        for (f_cnt32, kind) in f_cnt_candidates(ctx.f_cnt_up(), f_cnt) {
+            if uplink_mic(lorawan_config, dev_eui, &ctx, phy_payload, rx_packet, f_cnt32) == mic {
+                return Ok(UplinkSession {
+                    dev_eui,
+                    ctx,
+                    f_cnt32,
+                    is_f_cnt_reset: kind == FCntKind::Reset,
+                    is_retransmission: kind == FCntKind::Retransmission,
+                    f_ctrl,
+                    confirmed,
+                });
+            }
+        }
+
+    }
+
+    Err(anyhow!("no session context matches DevAddr: 0x{:08x}", dev_addr))
+
+}
+
+/// How the FCnt of an uplink relates to the lowest acceptable FCntUp
+#[derive(Debug, Clone, Copy, PartialEq)]
+enum FCntKind {
+    Next,            // a new uplink
+    Retransmission,  // the FCnt of the last accepted uplink
+    Reset,           // the device has reset its counter
+}
+
+/// The 32-bit FCnt values of an uplink with the 16 LSBs `f_cnt`, in the order they are tried
+fn f_cnt_candidates(f_cnt_up: u32, f_cnt: u32) -> Vec<(u32, FCntKind)> {
+
+    let mut candidates = Vec::with_capacity(3);
+
+    let last_f_cnt = f_cnt_up.checked_sub(1).filter(|last| last & 0xffff == f_cnt);
+    if let Some(last_f_cnt) = last_f_cnt {
+        candidates.push((last_f_cnt, FCntKind::Retransmission));
+    }
+
+    let mut f_cnt32 = (f_cnt_up & 0xffff0000) | f_cnt;
+    if f_cnt32 < f_cnt_up {
+        f_cnt32 = f_cnt32.wrapping_add(0x10000);
+    }
+    candidates.push((f_cnt32, FCntKind::Next));
+
+    if f_cnt < f_cnt_up && last_f_cnt != Some(f_cnt) {
+        candidates.push((f_cnt, FCntKind::Reset));
+    }
+
+    candidates
+
+}
+
+fn uplink_mic(
+    lorawan_config: &LorawanConfig,
+    dev_eui: u64,
+    ctx: &DeviceContext,
+    phy_payload: &[u8],
+    rx_packet: &RXPacket,
+    f_cnt32: u32,
+) -> [u8; 4] {
+    match ctx {
+        DeviceContext::V10x(ctx) => {
+            crypto10::data_frame_calculate_mic(phy_payload, &ctx.nwk_s_key, Dir::Uplink, ctx.dev_addr, f_cnt32)
+        },
+        DeviceContext::V12x(ctx) => {
+            let rf_region = lorawan_config.devices.get(&dev_eui)
+                .and_then(|device_record| lorawan_config.rf_region(device_record));
+            let freq = (rx_packet.freq as f64 * 1_000_000.0).round() as u32;
+            let tx_dr = rf_region
+                .and_then(|r| r.data_rate(&rx_packet.datr))
+                .unwrap_or(0);
+            let tx_ch = ctx.active_channels
+                .iter()
+                .find(|(_, (ch_freq, _))| *ch_freq == freq)
+                .map(|(ch_index, _)| *ch_index)
+                .or_else(|| rf_region.and_then(|r| r.ch_index(freq)))
+                .unwrap_or(0);
+            // An uplink with the ACK bit acknowledges the confirmed downlink sent before
+            let conf_f_cnt = match phy_payload[5] & 0b00100000 != 0 {
+                true => app_queue::unacked_f_cnt_down(dev_eui).map_or(0, |f_cnt| f_cnt as u16),
+                false => 0,
+            };
+            crypto12::data_frame_ul_calculate_mic(
+                phy_payload,
+                &ctx.s_nwk_s_int_key,
+                &ctx.f_nwk_s_int_key,
+                conf_f_cnt,
+                tx_dr,
+                tx_ch,
+                ctx.dev_addr,
+                f_cnt32,
+            )
+        },
+    }
+}
+
+/// Decrypts the `FOpts` (LoRaWAN 1.1+) and the `FRMPayload` of an authenticated uplink
+pub fn decrypt_uplink(
+    session: &UplinkSession,
+    f_port: Option<u8>,
+    f_opts: &mut [u8],
+    frm_payload: &mut [u8],
+) -> AnyResult<()> {
+    match &session.ctx {
+        DeviceContext::V10x(ctx) => {
+            let key = match f_port {
+                Some(0) => &ctx.nwk_s_key,
+                // Without AppSKey the FRMPayload is forwarded encrypted to the Application Server
+                _ => match &ctx.app_s_key { Some(key) => key, None => return Ok(()) },
+            };
+            crypto10::frm_payload_crypt(frm_payload, key, Dir::Uplink, ctx.dev_addr, session.f_cnt32)
+        },
+        DeviceContext::V12x(ctx) => {
+            crypto12::f_opts_crypt(f_opts, &ctx.nwk_s_enc_key, ctx.dev_addr, session.f_cnt32, FCntType::FCntUp)?;
+            let key = match f_port {
+                Some(0) => &ctx.nwk_s_enc_key,
+                _ => match &ctx.app_s_key { Some(key) => key, None => return Ok(()) },
+            };
+            crypto12::frm_payload_crypt(frm_payload, key, Dir::Uplink, ctx.dev_addr, session.f_cnt32)
+        },
+    }
+}
+
+/// Handles an authenticated uplink Data frame on the Network Server side
+///
+/// A lower than expected FCnt is accepted or rejected according to `ns.f_cnt_reset`,
+/// except when a LoRaWAN 1.1+ ABP device signals its reset with ResetInd. ResetInd is
+/// answered with ResetConf, along with the other answers of the uplink, and the session
+/// restarts with the preset keys and RX parameters.
+/// The application data of an accepted uplink is forwarded to the Application Server with
+/// the FCnt reset flag. A retransmitted confirmed uplink only gets its ACK sent again, a
+/// repeated unconfirmed uplink is ignored.
+///
+/// The answers of the device are applied to its pending MAC requests, the requests of the
+/// device are answered in the next downlink. DevStatusReq is queued at the rate of the service
+/// profile and the reported status is forwarded to the Application Server if the profile allows.
+/// The ADR engine queues LinkADRReq when the device parameters are to be changed and
+/// ADRParamSetupReq when the service profile sets other ADR_ACK_LIMIT/ADR_ACK_DELAY values.
+/// The ACK bit of the uplink is applied to the confirmed application downlink sent before.
+/// The queued commands and application data are sent in the Class A receive windows; a
+/// confirmed uplink or an uplink with ADRACKReq always gets a downlink.
+///
+/// # Arguments
+///
+/// * __`mac_cmds`__\
+///   The MAC commands of the decrypted `FOpts` or `FPort` = 0 `FRMPayload` \
+/// * __`app_payload`__\
+///   `FPort` (> 0) and the decrypted `FRMPayload`, forwarded to the Application Server
+///   together with the FCnt reset flag \
+///
+/// # Specification
+///
+/// LoRaWAN 1.1 - line #1062          \
+/// 5.1 Reset indication commands     \
+///
+pub fn handle_uplink(
+    collected_dd_data: &[DDData],
+    rx_packet: &RXPacket,
+    session: &UplinkSession,
+    mac_cmds: &[UplinkMACCmd],
+    app_payload: Option<(u8, &[u8])>,
+) -> AnyResult<()> {
+
+    let dev_eui = session.dev_eui;
+    let lorawan_config = lorawan_config::get_or_init();
+    let device_record = lorawan_config.devices.get(&dev_eui);
+
+    let region = device_record.and_then(|r| lorawan_config.region(r));
+    let uplink_dr = DataRate::from_datr(&rx_packet.datr)
+        .ok()
+        .and_then(|data_rate| region?.uplink_dr(&data_rate));
+    let uplink_dwell_time = session.ctx.mac_params().uplink_dwell_time;
+    let downlink_dwell_time = session.ctx.mac_params().downlink_dwell_time;
+    let rx1_dr_offset = session.ctx.rx_params().rx1_dr_offset;
+
+    // Downlinks are sized for the RX1 data rate
+    let max_mac_payload = region
+        .zip(uplink_dr)
+        .and_then(|(region, uplink_dr)| {
+            let rx1_dr = region.rx1_dr(uplink_dr, rx1_dr_offset, downlink_dwell_time)?;
+            region.max_mac_payload(rx1_dr, downlink_dwell_time)
+        })
+        .or_else(|| device_record
+            .and_then(|r| lorawan_config.rf_region(r))
+            .and_then(|r| r.data_rate(&rx_packet.datr).map(|dr| r.max_mac_payload(dr)))
+        )
+        .unwrap_or(59);
+
+    if let (Some(region), Some(uplink_dr)) = (region, uplink_dr) {
+        let max_phy_payload = region.max_mac_payload(uplink_dr, uplink_dwell_time).map(|m| m + 5);
+        if max_phy_payload.is_none_or(|max| rx_packet.size as usize > max) {
+            return Err(anyhow!(
+                "uplink of {} bytes exceeds the maximum of {:?} at DR{}, DevEUI: 0x{:016x}",
+                rx_packet.size, max_phy_payload, uplink_dr, dev_eui,
+            ));
+        }
+    }
+
+    // The downlink of a confirmed uplink has been lost: the retransmission is acknowledged
+    // again, its content has been processed already
+    if session.is_retransmission {
+        if !session.confirmed {
+            log::debug!("repeated uplink is ignored, DevEUI: 0x{:016x} FCnt: {}", dev_eui, session.f_cnt32);
+            return Ok(());
+        }
+        log::debug!("retransmitted uplink is acknowledged again, DevEUI: 0x{:016x} FCnt: {}", dev_eui, session.f_cnt32);
+        return send_class_a_downlink(
+            collected_dd_data, rx_packet, dev_eui, max_mac_payload, session.ack_f_cnt(), false,
+        );
+    }
+
+    let is_abp_v12x = matches!(session.ctx, DeviceContext::V12x(_))
+        && device_record.is_some_and(|r| r.ns.x_activation_type == ActivationType::ABP);
+    let reset_ind = mac_cmds
+        .iter()
+        .find_map(|cmd| match cmd {
+            UplinkMACCmd::ResetInd { minor } if is_abp_v12x => Some(*minor),
+            _ => None,
+        });
+
+    if session.is_f_cnt_reset && reset_ind.is_none() {
+        match lorawan_config.server_config.ns.f_cnt_reset {
+            FCntResetPolicy::Reject => {
+                return Err(anyhow!("FCnt reset rejected, DevEUI: 0x{:016x} FCnt: {}", dev_eui, session.f_cnt32));
+            },
+            FCntResetPolicy::Accept => {
+                log::warn!("FCnt reset accepted, DevEUI: 0x{:016x} FCnt: {}", dev_eui, session.f_cnt32);
+            },
+        }
+    }
+
+    devctx::set_f_cnt_up(dev_eui, session.f_cnt32.wrapping_add(1));
+    devctx::update_device_context(dev_eui, |ctx| update_recent_gateways(ctx, collected_dd_data));
+
+    if let Some((f_port, frm_payload)) = app_payload {
+        let report = UplinkReport::new(
+            dev_eui, session.f_cnt32, f_port, frm_payload, session.confirmed, session.is_f_cnt_reset,
+        );
+        app_server::forward_uplink(match (session.ctx.app_s_key(), session.ctx.session_key_id()) {
+            (None, Some(session_key_id)) => report.encrypted(session_key_id, session.ctx.dev_addr()),
+            _ => report,
+        });
+    }
+
+    if let Some(event) = app_queue::handle_uplink(dev_eui, session.ack()) {
+        app_server::forward_downlink_event(event);
+    }
+
+    let service_profile = device_record.and_then(|r| lorawan_config.service_profile(r));
+
+    for (req, ans) in mac_state::handle_answers(dev_eui, mac_cmds) {
+        log::debug!("DevEUI: 0x{:016x} {:?} is answered with {:?}", dev_eui, req, ans);
+        if let (UplinkMACCmd::DevStatusAns { battery, margin }, Some(service_profile)) = (ans, service_profile) {
+            let battery = service_profile.report_dev_status_battery.unwrap_or(false).then_some(battery);
+            let margin = service_profile.report_dev_status_margin.unwrap_or(false).then_some(margin);
+            if battery.is_some() || margin.is_some() {
+                app_server::forward_dev_status(DevStatusReport::new(dev_eui, battery, margin));
+            }
+        }
+    }
+
+    if let Some(rf_region) = device_record.and_then(|r| lorawan_config.rf_region(r)) {
+        adr::handle_uplink(
+            dev_eui, rf_region, service_profile, collected_dd_data, &rx_packet.datr, session.f_cnt32, session.adr(),
+        );
+    }
+
+    if let Some(dev_status_req_freq) = service_profile.and_then(|p| p.dev_status_req_freq) {
+        devctx::update_device_context(dev_eui, |ctx| ctx.mac_state_mut().schedule_dev_status_req(dev_status_req_freq));
+    }
+
+    if let Some(service_profile) = service_profile {
+        let global_params = &lorawan_config.server_config.ns.global_params_for_all_rf_regions;
+        devctx::update_device_context(dev_eui, |ctx| adr::schedule_adr_param_setup(ctx, service_profile, global_params));
+    }
+
+    let answers: Vec<DownlinkMACCmd> = mac_cmds
+        .iter()
+        .filter_map(|cmd| answer_request(collected_dd_data, cmd))
+        .collect();
+
+    let (Some(dev_lorawan_version), Some(device_record)) = (reset_ind, device_record) else {
+        devctx::update_device_context(dev_eui, |ctx| answers.into_iter().for_each(|ans| ctx.mac_state_mut().enqueue(ans)));
+        return send_class_a_downlink(
+            collected_dd_data, rx_packet, dev_eui, max_mac_payload, session.ack_f_cnt(), session.adr_ack_req(),
+        );
+    };
+
+    let DeviceContext::V12x(mut ctx) = devctx::abp_device_context(lorawan_config, device_record)? else {
+        return Err(anyhow!("ResetInd from a LoRaWAN 1.0.x device profile, DevEUI: 0x{:016x}", dev_eui));
+    };
+    ctx.f_cnt_up = session.f_cnt32.wrapping_add(1);
+    ctx.n_f_cnt_down = 0;
+    ctx.a_f_cnt_down = 0;
+    ctx.rj_cnt_02 = 0;
+
+    // The Network Server serves LoRaWAN 1.1 (Minor=1), the other answers of the uplink go along
+    let serv_lorawan_version = dev_lorawan_version.min(1);
+    ctx.mac_state.enqueue(DownlinkMACCmd::ResetConf { minor: serv_lorawan_version });
+    answers.into_iter().for_each(|ans| ctx.mac_state.enqueue(ans));
+    let packed = ctx.mac_state.take_for_downlink(max_mac_payload, None);
+    let mut ctx = DeviceContext::V12x(ctx);
+    let phy_payload = data_frame_down(&ctx, &packed, session.ack_f_cnt(), None, false);
+
+    *ctx.n_f_cnt_down_mut() += 1;
+    devctx::set_device_context(dev_eui, ctx.clone());
+
+    log::info!("ResetConf for DevEUI: 0x{:016x} Minor: {}", dev_eui, serv_lorawan_version);
+
+    downlink::send_data_frame(collected_dd_data, rx_packet, &ctx, device_record, &phy_payload)
+
+}
+
+/// Sends the Class A downlink of a device in the receive windows of its uplink
+///
+/// A downlink is sent when application data or commands are queued, when a sticky answer is
+/// to be stopped, when the uplink is confirmed or when the device asks for one with ADRACKReq;
+/// the latter two may carry no data at all. The ACK bit acknowledges a confirmed uplink, the
+/// FPending bit tells the device that more application data is queued.
+///
+/// # Arguments
+///
+/// * __`ack_f_cnt`__\
+///   The FCntUp of the uplink if it is a Confirmed Data Up \
+///
+/// # Specification
+///
+/// LoRaWAN L2 1.0.4 - line #622                   \
+/// 4.3.1.1 Adaptive data rate control in frame header (ADR, ADRACKReq in FCtrl) \
+/// 4.3.1.2 Message acknowledge bit and acknowledgment procedure (ACK in FCtrl) \
+///
+fn send_class_a_downlink(
+    collected_dd_data: &[DDData],
+    rx_packet: &RXPacket,
+    dev_eui: u64,
+    max_mac_payload: usize,
+    ack_f_cnt: Option<u32>,
+    adr_ack_req: bool,
+) -> AnyResult<()> {
+
+    let downlink = devctx::update_device_context(dev_eui, |ctx| {
+        // MACPayload = FHDR (7 + FOpts) | FPort (1) | FRMPayload
+        // The application data can't be encrypted without AppSKey, it stays queued
+        let (app_downlink, f_pending, events) = match ctx.app_s_key() {
+            Some(_) => app_queue::take_for_downlink(
+                dev_eui, max_mac_payload.saturating_sub(8), ctx.a_f_cnt_down(), SystemTime::now(),
+            ),
+            None => (None, false, Vec::new()),
+        };
+        events.into_iter().for_each(app_server::forward_downlink_event);
+
+        let mac_state = ctx.mac_state_mut();
+        if app_downlink.is_none() && ack_f_cnt.is_none() && !adr_ack_req
+            && !mac_state.downlink_required && mac_state.queue.is_empty() {
+            return None;
+        }
+        let app_payload_len = app_downlink.as_ref().map(|downlink| downlink.frm_payload.len());
+        let packed = mac_state.take_for_downlink(max_mac_payload, app_payload_len);
+        let phy_payload = data_frame_down(ctx, &packed, ack_f_cnt, app_downlink.as_ref(), f_pending);
+        match app_downlink {
+            Some(_) => *ctx.a_f_cnt_down_mut() += 1,
+            None => *ctx.n_f_cnt_down_mut() += 1,
+        }
+        Some((ctx.clone(), packed.packed, app_downlink, phy_payload))
+    });
+    let Some(Some((ctx, mac_cmds, app_downlink, phy_payload))) = downlink else {
+        return Ok(());
+    };
+    let device_record = lorawan_config::get_or_init().devices.get(&dev_eui)
+        .ok_or_else(|| anyhow!("unknown DevEUI: 0x{:016x}", dev_eui))?;
+
+    if let Some(app_downlink) = &app_downlink {
+        log::info!(
+            "Downlink: {} FPort: {} Confirmed: {} for DevEUI: 0x{:016x}",
+            app_downlink.id, app_downlink.f_port, app_downlink.confirmed, dev_eui,
+        );
+    }
+    match ack_f_cnt {
+        Some(f_cnt) => log::info!("ACK of FCnt: {} for DevEUI: 0x{:016x} MAC commands: {:?}", f_cnt, dev_eui, mac_cmds),
+        None => log::info!("MAC commands for DevEUI: 0x{:016x} {:?}", dev_eui, mac_cmds),
+    }
+
+    let result = downlink::send_data_frame(collected_dd_data, rx_packet, &ctx, device_record, &phy_payload);
+    if let (Err(e), Some(app_downlink)) = (&result, app_downlink) {
+        log::warn!("Downlink: {} is queued again for DevEUI: 0x{:016x}: {}", app_downlink.id, dev_eui, e);
+        app_queue::restore(dev_eui, app_downlink);
+    }
+    result
+
+}
+
+/// Adds the gateways of an uplink to the recent gateways of a device
+///
+/// The gateways whose `tmst` counter can no longer be extrapolated are dropped, they cannot
+/// send a Class A downlink without an uplink of their own.
+///
+fn update_recent_gateways(ctx: &mut DeviceContext, collected_dd_data: &[DDData]) {
+    let recent_gateways = ctx.recent_gateways_mut();
+    recent_gateways.retain(|gw_eui| downlink::gw_tmst_at(*gw_eui, SystemTime::now()).is_some());
+    recent_gateways.extend(collected_dd_data.iter().map(|dd_data| dd_data.gw_eui));
+    if let Some(best) = downlink::best_gateway(collected_dd_data) {
+        *ctx.best_gateway_mut() = best.gw_eui;
+    }
+}
+
+/// The answer to a MAC command requested by the device, queued for the next downlink
+fn answer_request(collected_dd_data: &[DDData], cmd: &UplinkMACCmd) -> Option<DownlinkMACCmd> {
+    match cmd {
+        UplinkMACCmd::LinkCheckReq => {
+            let best = downlink::best_gateway(collected_dd_data)?;
+            let gw_cnt = collected_dd_data
+                .iter()
+                .map(|dd_data| dd_data.gw_eui)
+                .collect::<HashSet<u64>>()
+                .len();
+            Some(link_check::link_check_ans(best.sp_fact, best.snr, gw_cnt))
+        },
+        UplinkMACCmd::DeviceTimeReq => {
+            uplink_end_gps_time(collected_dd_data).map(gps_time::device_time_ans)
+        },
+        _ => None,
+    }
+}
+
+/// The GPS time of the end of an uplink transmission
+///
+/// The GPS timestamp of a synchronized gateway is used when available, otherwise the
+/// earliest server reception time. A frame is forwarded by the gateway once it has been
+/// received completely, so the server time lags the end of the transmission only by the
+/// backhaul latency.
+///
+fn uplink_end_gps_time(collected_dd_data: &[DDData]) -> Option<Duration> {
+
+    if let Some(dd_data) = collected_dd_data.iter().find(|dd_data| dd_data.tmms > 0) {
+        return Some(Duration::from_millis(dd_data.tmms as u64));
+    }
+
+    let rx_time = collected_dd_data.iter().map(|dd_data| dd_data.rx_time).min()?;
+    gps_time::utc_to_gps(rx_time)
+
+}
+
+/// Builds a Data Down frame carrying MAC commands and application data
+///
+/// Confirmed application data is sent in a Confirmed Data Down frame.
+/// With application data the commands are sent in `FOpts`, otherwise in `FOpts` or in the
+/// `FRMPayload` of an `FPort` = 0 frame. LoRaWAN 1.0.x frames use `NwkSKey` and `FCntDown`;
+/// LoRaWAN 1.1+ frames are encrypted with `NwkSEncKey` and their MIC is calculated with
+/// `SNwkSIntKey`, with `AFCntDown` when `FPort` > 0 and `NFCntDown` otherwise.
+///
+/// # Arguments
+///
+/// * __`ack_f_cnt`__\
+///   The FCntUp of the confirmed uplink acknowledged with the ACK bit, it is the `ConfFCnt`
+///   of the LoRaWAN 1.1+ MIC \
+/// * __`app_downlink`__\
+///   The application data, its `FRMPayload` is encrypted with `AppSKey` \
+/// * __`f_pending`__\
+///   The FPending bit: more application data is waiting \
+///
+fn data_frame_down(
+    ctx: &DeviceContext,
+    packed: &PackedMACCmds,
+    ack_f_cnt: Option<u32>,
+    app_downlink: Option<&AppDownlink>,
+    f_pending: bool,
+) -> Vec<u8> {
+
+    let dev_addr = ctx.dev_addr();
+    let (f_cnt_down, f_cnt_type) = match app_downlink {
+        Some(_) => (ctx.a_f_cnt_down(), FCntType::AFCntDown),
+        None => (ctx.n_f_cnt_down(), FCntType::NFCntDown),
+    };
+
+    let mut mac_cmds = packed.bytes.clone();
+    match (ctx, packed.in_f_opts) {
+        (DeviceContext::V10x(_), true) => (),
+        (DeviceContext::V10x(ctx), false) => {
+            crypto10::frm_payload_crypt(&mut mac_cmds, &ctx.nwk_s_key, Dir::Downlink, dev_addr, f_cnt_down)
+                .unwrap();
+        },
+        (DeviceContext::V12x(ctx), true) => {
+            crypto12::f_opts_crypt(&mut mac_cmds, &ctx.nwk_s_enc_key, dev_addr, f_cnt_down, f_cnt_type)
+                .unwrap();
+        },
+        (DeviceContext::V12x(ctx), false) => {
+            crypto12::frm_payload_crypt(&mut mac_cmds, &ctx.nwk_s_enc_key, Dir::Downlink, dev_addr, f_cnt_down)
+                .unwrap();
+        },
+    }
+
+    let (f_opts, port_payload) = match app_downlink {
+        Some(app_downlink) => {
+            let mut frm_payload = app_downlink.frm_payload.clone();
+            let app_s_key = ctx.app_s_key().expect("application data is taken only with AppSKey");
+            match ctx {
+                DeviceContext::V10x(_) => {
+                    crypto10::frm_payload_crypt(&mut frm_payload, app_s_key, Dir::Downlink, dev_addr, f_cnt_down)
+                },
+                DeviceContext::V12x(_) => {
+                    crypto12::frm_payload_crypt(&mut frm_payload, app_s_key, Dir::Downlink, dev_addr, f_cnt_down)
+                },
+            }.unwrap();
+            (mac_cmds, Some((app_downlink.f_port, frm_payload)))
+        },
+        None if packed.in_f_opts => (mac_cmds, None),
+        None => (Vec::new(), Some((0, mac_cmds))),
+    };
+
+    let mut f_ctrl = f_opts.len() as u8; // FOptsLen
+    if ack_f_cnt.is_some() {
+        f_ctrl |= 0b00100000;
+    }
+    if f_pending {
+        f_ctrl |= 0b00010000;
+    }
+
+    let mut phy_payload: Vec<u8> = Vec::with_capacity(13 + f_opts.len() + port_payload.as_ref().map_or(0, |(_, p)| p.len()));
+    let m_type = match app_downlink {
+        Some(app_downlink) if app_downlink.confirmed => MType::ConfirmedDataDown,
+        _ => MType::UnconfirmedDataDown,
+    };
+    phy_payload.push((m_type as u8) << 5 | Major::LoRaWanR1 as u8);
+    phy_payload.extend_from_slice(&dev_addr.to_le_bytes());
+    phy_payload.push(f_ctrl);
+    phy_payload.extend_from_slice(&(f_cnt_down as u16).to_le_bytes());
+    phy_payload.extend_from_slice(&f_opts);
+    if let Some((f_port, frm_payload)) = &port_payload {
+        phy_payload.push(*f_port);
+        phy_payload.extend_from_slice(frm_payload);
+    }
+    phy_payload.extend_from_slice(&[0; 4]);
+
+    let mic = match ctx {
+        DeviceContext::V10x(ctx) => {
+            crypto10::data_frame_calculate_mic(&phy_payload, &ctx.nwk_s_key, Dir::Downlink, dev_addr, f_cnt_down)
+        },
+        DeviceContext::V12x(ctx) => {
+            let conf_f_cnt = ack_f_cnt.map_or(0, |f_cnt| f_cnt as u16);
+            crypto12::data_frame_dl_calculate_mic_sc(&phy_payload, &ctx.s_nwk_s_int_key, conf_f_cnt, dev_addr, f_cnt_down)
+        },
+    };
+    let len = phy_payload.len();
+    phy_payload[len - 4..].copy_from_slice(&mic);
+
+    phy_payload
+
+}
+
+
+#[cfg(test)]
+mod tests {
+
+    use super::*;
+    use crate::{
+        devctx::{DeviceContextV10x, DeviceContextV12x},
+        lorawan::mac_cmds,
+    };
+
+    #[test]
+    fn test_f_cnt_candidates() {
+
+        use FCntKind::*;
+
+        // the last accepted uplink is FCnt 20
+        assert_eq!(f_cnt_candidates(21, 20), vec![(20, Retransmission), (0x10014, Next)]);
+        assert_eq!(f_cnt_candidates(21, 21), vec![(21, Next)]);
+        assert_eq!(f_cnt_candidates(21, 3), vec![(0x10003, Next), (3, Reset)]);
+        assert_eq!(f_cnt_candidates(0, 0), vec![(0, Next)]);
+
+        // the 16 MSBs come from the lowest acceptable FCntUp
+        assert_eq!(f_cnt_candidates(0x10005, 4), vec![(0x10004, Retransmission), (0x20004, Next), (4, Reset)]);
+        assert_eq!(f_cnt_candidates(0x10000, 0xffff), vec![(0xffff, Retransmission), (0x1ffff, Next)]);
+
+    }
+
+    #[test]
+    fn test_data_frame_down() {
+
+        let key = [0x2b; 16];
+        let packed = mac_cmds::pack(&[DownlinkMACCmd::DevStatusReq], 51, Some(2));
+
+        // LoRaWAN 1.0.x: ACK without application data
+        let ctx = DeviceContext::V10x(DeviceContextV10x {
+            nwk_s_key: key, app_s_key: Some(key), dev_addr: 0x01020304, f_cnt_down: 7, ..Default::default()
+        });
+        let empty = mac_cmds::pack(&[], 51, None);
+        let phy_payload = data_frame_down(&ctx, &empty, Some(3), None, false);
+        assert_eq!(hex::encode(&phy_payload[..8]), "6004030201200700");
+        assert_eq!(phy_payload.len(), 12);
+        let mic = crypto10::data_frame_calculate_mic(&phy_payload, &key, Dir::Downlink, 0x01020304, 7);
+        assert_eq!(phy_payload[8..], mic);
+
+        // LoRaWAN 1.1: Confirmed Data Down, AFCntDown, FPending, MAC commands in FOpts and ConfFCnt in the MIC
+        let ctx = DeviceContext::V12x(DeviceContextV12x {
+            s_nwk_s_int_key: key, nwk_s_enc_key: key, app_s_key: Some([0x3c; 16]), dev_addr: 0x01020304,
+            n_f_cnt_down: 7, a_f_cnt_down: 2, ..Default::default()
+        });
+        let app_downlink = AppDownlink {
+            id: 1, f_port: 10, frm_payload: vec![0xca, 0xfe], confirmed: true, expires_at: None,
+        };
+        let phy_payload = data_frame_down(&ctx, &packed, Some(0x10005), Some(&app_downlink), true);
+        assert_eq!(hex::encode(&phy_payload[..8]), "a004030201310200");
+        assert_eq!(phy_payload[9], 10);
+        let mut frm_payload = phy_payload[10..12].to_vec();
+        crypto12::frm_payload_crypt(&mut frm_payload, &[0x3c; 16], Dir::Downlink, 0x01020304, 2).unwrap();
+        assert_eq!(frm_payload, [0xca, 0xfe]);
+        let mic = crypto12::data_frame_dl_calculate_mic_sc(&phy_payload, &key, 5, 0x01020304, 2);
+        assert_eq!(phy_payload[12..], mic);
+
+    }
+
+}
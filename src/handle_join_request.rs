@@ -0,0 +1,112 @@
+use anyhow::{ Result as AnyResult, anyhow };
+
+use crate::{
+    lorawan_config::{self, ActivationType, DeviceRecord, LorawanConfig},
+    devctx::{self, DeviceContext, DeviceContextV10x, DeviceContextV12x},
+    dd_cache::DDData,
+    pktf::RXPacket,
+    downlink, backend,
+    join_server::{self, JoinAnsParams, JoinReqParams, SessionKeys},
+};
+
+/// Handles a Join-Request on the Network Server side
+///
+/// Resolves the device, asks the Join Server for a Join-Accept and the session keys,
+/// sends the Join-Accept to the device and creates the new session context once it is sent.
+///
+pub fn handle_join_request(collected_dd_data: &[DDData], rx_packet: &RXPacket, phy_payload: &[u8]) -> AnyResult<()> {
+
+    let join_eui = u64::from_le_bytes(phy_payload[1..9].try_into().unwrap());
+    let dev_eui = u64::from_le_bytes(phy_payload[9..17].try_into().unwrap());
+
+    let lorawan_config = lorawan_config::get_or_init();
+    let ns_config = &lorawan_config.server_config.ns;
+
+    let device_record = lorawan_config.devices.get(&dev_eui)
+        .ok_or_else(|| anyhow!("unknown DevEUI: 0x{:016x}", dev_eui))?;
+
+    if !device_record.ns.x_is_enabled {
+        return Err(anyhow!("disabled DevEUI: 0x{:016x}", dev_eui));
+    }
+
+    let white_list = &device_record.ns.x_join_eui_white_list;
+    if !white_list.is_empty() && !white_list.contains(&join_eui) {
+        return Err(anyhow!("JoinEUI: 0x{:016x} is not allowed for DevEUI: 0x{:016x}", join_eui, dev_eui));
+    }
+
+    let dev_addr = device_record.ns.x_dev_addr
+        .ok_or_else(|| anyhow!("no DevAddr is configured for DevEUI: 0x{:016x}", dev_eui))?;
+
+    let rx_params = devctx::default_rx_params(lorawan_config, device_record);
+
+    let req = JoinReqParams {
+        phy_payload,
+        net_id: ns_config.net_id(),
+        dev_addr,
+        dl_settings: (rx_params.rx1_dr_offset & 0b111) << 4 | (rx_params.rx2_dr & 0b1111),
+        rx_delay: rx_params.rx1_delay,
+        cf_list: lorawan_config.cf_list(device_record),
+    };
+
+    let join_ans = match device_record.ns.x_activation_type {
+        ActivationType::OTAInternalJS => join_server::handle_join_req(&req)?,
+        ActivationType::OTA => backend::client::join_req(&req, &device_record.ns.x_lora_wan_version)?,
+        ActivationType::ABP => {
+            return Err(anyhow!("Join-Request from an ABP device, DevEUI: 0x{:016x}", dev_eui));
+        },
+    };
+
+    let phy_payload = join_ans.phy_payload.clone();
+    let mut ctx = join_context(lorawan_config, device_record, dev_addr, join_ans);
+    if let DeviceContext::V12x(ctx) = &mut ctx {
+        ctx.join_eui = join_eui;
+    }
+
+    // The new session replaces the working one only when the device can learn about it
+    downlink::send_join_accept(collected_dd_data, rx_packet, device_record, &phy_payload)?;
+    devctx::set_device_context(dev_eui, ctx);
+
+    log::info!("Join-Accept for DevEUI: 0x{:016x} DevAddr: 0x{:08x}", dev_eui, dev_addr);
+
+    Ok(())
+
+}
+
+/// The session context of a device after a Join-Accept
+///
+/// The device starts with the default RX parameters and the channels of the CFList.
+///
+pub fn join_context(
+    lorawan_config: &LorawanConfig,
+    device_record: &DeviceRecord,
+    dev_addr: u32,
+    join_ans: JoinAnsParams,
+) -> DeviceContext {
+
+    let rx_params = devctx::default_rx_params(lorawan_config, device_record);
+    let active_channels = devctx::join_channels(lorawan_config, device_record);
+
+    match join_ans.session_keys {
+        SessionKeys::V10x { nwk_s_key } => DeviceContext::V10x(DeviceContextV10x {
+            nwk_s_key,
+            app_s_key: join_ans.app_s_key,
+            session_key_id: join_ans.session_key_id,
+            dev_addr,
+            rx_params,
+            active_channels,
+            .. DeviceContextV10x::default()
+        }),
+        SessionKeys::V12x { f_nwk_s_int_key, s_nwk_s_int_key, nwk_s_enc_key } => DeviceContext::V12x(DeviceContextV12x {
+            f_nwk_s_int_key,
+            s_nwk_s_int_key,
+            nwk_s_enc_key,
+            app_s_key: join_ans.app_s_key,
+            session_key_id: join_ans.session_key_id,
+            dev_addr,
+            rx_params,
+            active_channels,
+            .. DeviceContextV12x::default()
+        }),
+    }
+
+}
@@ -0,0 +1,165 @@
+use anyhow::{ Result as AnyResult, anyhow };
+
+use crate::{
+    lorawan_config::{self, ActivationType},
+    devctx::{self, DeviceContext, DeviceContextV12x},
+    dd_cache::DDData,
+    pktf::RXPacket,
+    downlink, backend,
+    lorawan::{
+        RJType,
+        crypto::crypto12,
+    },
+    join_server::{self, JoinReqParams, SessionKeys},
+};
+
+/// Handles a Rejoin-Request on the Network Server side
+///
+/// Type 0 and 2 Rejoin-Requests are authenticated with the `SNwkSIntKey` of the current
+/// session and RJcount0 is checked here. Type 1 Rejoin-Requests are authenticated by the
+/// Join Server.
+///
+/// Type 0 and 1 Rejoin-Requests start a new session with default radio parameters,
+/// type 2 Rejoin-Requests only rekey the session and keep the radio parameters.
+///
+/// # Specification
+///
+/// LoRaWAN 1.2.0 Draft 47 - line #2606 \
+/// 6.2.4 Rejoin-Request frame          \
+///
+pub fn handle_rejoin_request(
+    collected_dd_data: &[DDData],
+    rx_packet: &RXPacket,
+    rj_type: RJType,
+    phy_payload: &[u8],
+) -> AnyResult<()> {
+
+    let dev_eui = match rj_type {
+        RJType::Type0 | RJType::Type2 => u64::from_le_bytes(phy_payload[5..13].try_into().unwrap()),
+        RJType::Type1 => u64::from_le_bytes(phy_payload[10..18].try_into().unwrap()),
+    };
+
+    let lorawan_config = lorawan_config::get_or_init();
+    let ns_config = &lorawan_config.server_config.ns;
+
+    let device_record = lorawan_config.devices.get(&dev_eui)
+        .ok_or_else(|| anyhow!("unknown DevEUI: 0x{:016x}", dev_eui))?;
+
+    if !device_record.ns.x_is_enabled {
+        return Err(anyhow!("disabled DevEUI: 0x{:016x}", dev_eui));
+    }
+
+    let current_ctx = match devctx::get_device_context(dev_eui) {
+        Some(DeviceContext::V12x(ctx)) => Some(ctx),
+        Some(DeviceContext::V10x(_)) => {
+            return Err(anyhow!("Rejoin-Request from a LoRaWAN 1.0.x device, DevEUI: 0x{:016x}", dev_eui));
+        },
+        None => None,
+    };
+
+    let mut rj_count_0 = None;
+    if let RJType::Type0 | RJType::Type2 = rj_type {
+
+        let ctx = current_ctx.as_ref()
+            .ok_or_else(|| anyhow!("no session context for DevEUI: 0x{:016x}", dev_eui))?;
+
+        let mut net_id_array = [0_u8; 4];
+        net_id_array[0..3].copy_from_slice(&phy_payload[2..5]);
+        let net_id = u32::from_le_bytes(net_id_array);
+        if !ns_config.net_ids.contains(&net_id) {
+            return Err(anyhow!("unknown NetID: 0x{:06x}, DevEUI: 0x{:016x}", net_id, dev_eui));
+        }
+
+        let mic: [u8; 4] = phy_payload[15..19].try_into().unwrap();
+        if crypto12::join_request_calculate_mic(&ctx.s_nwk_s_int_key, phy_payload) != mic {
+            return Err(anyhow!("invalid Rejoin-Request MIC, DevEUI: 0x{:016x}", dev_eui));
+        }
+
+        let rj_count = u16::from_le_bytes(phy_payload[13..15].try_into().unwrap());
+        devctx::check_rj_cnt_02(dev_eui, rj_count)?;
+        rj_count_0 = Some(rj_count);
+
+    }
+
+    // Type 0 and 2 Rejoin-Requests refer to the JoinEUI of the current session
+    let join_eui = match (rj_type, &current_ctx) {
+        (RJType::Type1, _) => u64::from_le_bytes(phy_payload[2..10].try_into().unwrap()),
+        (_, Some(ctx)) => ctx.join_eui,
+        (_, None) => return Err(anyhow!("no session context for DevEUI: 0x{:016x}", dev_eui)),
+    };
+
+    // A type 2 Rejoin-Request keeps the DevAddr of the current session
+    let dev_addr = match (rj_type, &current_ctx) {
+        (RJType::Type2, Some(ctx)) => ctx.dev_addr,
+        _ => device_record.ns.x_dev_addr
+            .ok_or_else(|| anyhow!("no DevAddr is configured for DevEUI: 0x{:016x}", dev_eui))?,
+    };
+
+    let rx_params = devctx::default_rx_params(lorawan_config, device_record);
+
+    let req = JoinReqParams {
+        phy_payload,
+        net_id: ns_config.net_id(),
+        dev_addr,
+        dl_settings: (rx_params.rx1_dr_offset & 0b111) << 4 | (rx_params.rx2_dr & 0b1111),
+        rx_delay: rx_params.rx1_delay,
+        cf_list: lorawan_config.cf_list(device_record),
+    };
+
+    let join_ans = match device_record.ns.x_activation_type {
+        ActivationType::OTAInternalJS => join_server::handle_rejoin_req(&req)?,
+        ActivationType::OTA => {
+            backend::client::rejoin_req(&req, &device_record.ns.x_lora_wan_version, join_eui, dev_eui)?
+        },
+        ActivationType::ABP => {
+            return Err(anyhow!("Rejoin-Request from an ABP device, DevEUI: 0x{:016x}", dev_eui));
+        },
+    };
+
+    // RJcount0 is used up only by a Rejoin-Request the Join Server has answered
+    if let Some(rj_count) = rj_count_0 {
+        devctx::commit_rj_cnt_02(dev_eui, rj_count)?;
+    }
+
+    let SessionKeys::V12x { f_nwk_s_int_key, s_nwk_s_int_key, nwk_s_enc_key } = join_ans.session_keys else {
+        return Err(anyhow!("LoRaWAN 1.0.x session keys for a Rejoin-Request, DevEUI: 0x{:016x}", dev_eui));
+    };
+
+    let ctx = match (rj_type, current_ctx) {
+        (RJType::Type2, Some(current_ctx)) => DeviceContextV12x {
+            f_nwk_s_int_key,
+            s_nwk_s_int_key,
+            nwk_s_enc_key,
+            app_s_key: join_ans.app_s_key,
+            session_key_id: join_ans.session_key_id,
+            dev_addr,
+            join_eui,
+            rx_params: current_ctx.rx_params,
+            active_channels: current_ctx.active_channels,
+            recent_gateways: current_ctx.recent_gateways,
+            best_gateway: current_ctx.best_gateway,
+            .. DeviceContextV12x::default()
+        },
+        _ => DeviceContextV12x {
+            f_nwk_s_int_key,
+            s_nwk_s_int_key,
+            nwk_s_enc_key,
+            app_s_key: join_ans.app_s_key,
+            session_key_id: join_ans.session_key_id,
+            dev_addr,
+            join_eui,
+            rx_params,
+            active_channels: devctx::join_channels(lorawan_config, device_record),
+            .. DeviceContextV12x::default()
+        },
+    };
+
+    // The new session replaces the working one only when the device can learn about it
+    downlink::send_join_accept(collected_dd_data, rx_packet, device_record, &join_ans.phy_payload)?;
+    devctx::set_device_context(dev_eui, DeviceContext::V12x(ctx));
+
+    log::info!("Join-Accept for {:?} Rejoin-Request, DevEUI: 0x{:016x} DevAddr: 0x{:08x}", rj_type, dev_eui, dev_addr);
+
+    Ok(())
+
+}
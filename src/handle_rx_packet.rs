@@ -2,12 +2,16 @@
 use base64::engine::{ Engine as _, general_purpose::STANDARD as BASE64 };
 use crate::{
     settings,
+    handle_join_request::handle_join_request,
+    handle_rejoin_request::handle_rejoin_request,
+    handle_data_frame,
     dd_cache::DDData,
     pktf::RXPacket,
     lorawan::{
         self,
-        crypto::{crypto10, crypto12},
+        crypto::crypto10,
         enums::{RJType, Dir},
+        mac_cmds,
     }
 };
 
@@ -82,57 +86,10 @@ pub fn handle_rx_packet(collected_dd_data: Vec<DDData>, rx_packet: &RXPacket) {
                 }
                 let join_eui = u64::from_le_bytes(phy_payload[1..9].try_into().unwrap());
                 let dev_eui = u64::from_le_bytes(phy_payload[9..17].try_into().unwrap());
-                let dev_nonce = u16::from_le_bytes(phy_payload[17..20].try_into().unwrap());
+                let dev_nonce = u16::from_le_bytes(phy_payload[17..19].try_into().unwrap());
 
                 let mic: [u8; 4] = (&phy_payload[phy_payload_len - 4..]).try_into().unwrap();
 
-                // TODO: to get the appropriate key from Deevice Context based on dev_eui
-                // this is just a mockup
-                let mut nwk_key_array = [0_u8; 16];
-                hex::decode_to_slice(&settings.default_key, &mut nwk_key_array)
-                    .expect("decoding of default_key from config file has failed");
-                // let nwk_key = Some(nwk_key_array);
-                
-
-                // let calculated_mic = crypto::aes128_cmac(
-                //     &nwk_key_array, &phy_payload[0..19]
-                // );
-                let calculated_mic = crypto10::join_frame_calculate_mic(&nwk_key_array, &phy_payload);
-
-                let is_mic_ok = Some( mic == calculated_mic );
-
-
-
-
-
-/*
-                let app_key_array = nwk_key_array.clone();
-                let join_nonce: u32 = 0x000001;
-                let net_id: u32 = 0x000001; 
-
-
-                let app_s_key = crypto::derive_s_key(
-                    &app_key_array,
-                    KeyType::AppSKey, 
-                    join_nonce,
-                    net_id as u64, // Workaround to add net_id instead of join_eui for the case of LoRaWAN1.0.x
-                    dev_nonce
-                );
-
-                let nwk_s_key = crypto::derive_s_key(
-                    &nwk_key_array,
-                    KeyType::FNwkSIntKey, 
-                    join_nonce,
-                    net_id as u64, // Workaround to add net_id instead of join_eui for the case of LoRaWAN1.0.x
-                    dev_nonce
-                );
-*/
-
-
-
-
-
-
                 let print_record = format!( 
 "
 JoinRequest: {}
@@ -145,7 +102,6 @@ JoinRequest: {}
         DevEUI:   {}
         DevNonce: {}
         MIC:         {}
-        MIC_OK:      {}
     MetaData:
         SpFact:   {}
         Freq:     {}
@@ -160,8 +116,6 @@ JoinRequest: {}
                     format!("0x{:016x}", dev_eui),
                     format!("0x{:02x}", dev_nonce),
                     hex::encode(&mic),
-                    match is_mic_ok { Some(v) => format!("{}", v), None => "".to_owned() },
-
                     if &rx_packet.datr[3..4] == "B" { &rx_packet.datr[..3] } else { &rx_packet.datr[..4] },
                     rx_packet.freq, rx_packet.rssi, rx_packet.lsnr,
 
@@ -170,10 +124,9 @@ JoinRequest: {}
                 println!("{}", print_record);
                     
                 let log_record = format!(
-                    r#"{{"MType":"{:?}", "JoinEUI":"0x{:016x}", "DevEUI":"0x{:016x}", "DevNonce":"0x{:04x}", "MIC":"{}", "MIC_OK":"{}", "SpFact":"{}", "Freq":"{}", "RSSI":"{}", "SNR":"{}"}}"#,
+                    r#"{{"MType":"{:?}", "JoinEUI":"0x{:016x}", "DevEUI":"0x{:016x}", "DevNonce":"0x{:04x}", "MIC":"{}", "SpFact":"{}", "Freq":"{}", "RSSI":"{}", "SNR":"{}"}}"#,
                     mhdr_m_type, join_eui, dev_eui, dev_nonce,
                     hex::encode(&mic),
-                    match is_mic_ok { Some(v) => format!("{}", v), None => "".to_owned() },
                     if &rx_packet.datr[3..4] == "B" { &rx_packet.datr[..3] } else { &rx_packet.datr[..4] },
                     rx_packet.freq, rx_packet.rssi, rx_packet.lsnr,
                 );
@@ -181,6 +134,10 @@ JoinRequest: {}
                 log::info!("{}", log_record);
                 // println!("{}", log_record);
 
+                if let Err(e) = handle_join_request(&collected_dd_data, rx_packet, &phy_payload) {
+                    log::error!("handle_join_request() error: {:?}", e);
+                }
+
             },
 
             lorawan::MType::JoinAccept => {
@@ -356,23 +313,20 @@ JoinAccept: {}
 
                 let dev_addr = u32::from_le_bytes(phy_payload[1..5].try_into().unwrap());
 
-                // TODO: Implement advanced filtering
-                if dev_addr != 0x04000f20 {
-                    log::debug!("Unknown DevAddr: 0x{:08x}", dev_addr);
+                let dir = mhdr_m_type.get_dir();
+
+                if let Dir::Downlink = dir {
+                    log::debug!("Downlink Data frame received, DevAddr: 0x{:08x}", dev_addr);
                     return;
                 }
 
-                let dir = mhdr_m_type.get_dir();
-
-                // TODO: to get the appropriate keys and f_cnt from Deevice Context based on dev_addr and dir
-                // this is just a mockup
-                let mut app_s_key_array = [0_u8; 16];
-                hex::decode_to_slice(&settings.default_key, &mut app_s_key_array)
-                    .expect("decoding of default_key from config file has failed");
-                let nwk_s_key_array = app_s_key_array.clone();
-                let app_s_key = Some(app_s_key_array); // TODO: get it from device conttext
-                let nwk_s_key = Some(nwk_s_key_array); // TODO: get it from device conttext
-                let f_cnt32_last_known = 0; // TODO: get it from device conttext
+                let session = match handle_data_frame::authenticate_uplink(&phy_payload, rx_packet) {
+                    Ok(v) => v,
+                    Err(e) => {
+                        log::debug!("{}", e);
+                        return;
+                    }
+                };
 
                 // let f_ctrl_value = phy_payload[5];
                 let f_ctrl_adr = (f_ctrl_value & 0b10000000) == 0b10000000;
@@ -386,48 +340,28 @@ JoinAccept: {}
                 let mut f_opts: Vec<u8> = Vec::with_capacity(f_ctrl_f_opts_len);
                 f_opts.extend_from_slice(&phy_payload[8..8+f_ctrl_f_opts_len]);
 
-                let f_port = if phy_payload_len > 0 {
+                let f_port = if phy_payload_len > 12 + f_ctrl_f_opts_len {
                     Some(phy_payload[8 + f_ctrl_f_opts_len])
                 } else {
                     None
                 };
 
                 let mut frm_payload: Vec<u8> = Vec::with_capacity(phy_payload_len);
-                if phy_payload_len > 0 {
+                if f_port.is_some() {
                     frm_payload.extend_from_slice(&phy_payload[9 + f_ctrl_f_opts_len .. phy_payload_len - 4]);
                 }
 
                 let mic: [u8; 4] = phy_payload[phy_payload_len - 4..].try_into().unwrap();
 
-                let is_mic_ok: Option<bool>; 
-
-                let f_cnt32 = (f_cnt32_last_known & 0xffff0000) + (f_cnt as u32);
-
-                if let Some(nwk_s_key) = nwk_s_key {
-
-                    let calculated_mic = crypto10::data_frame_calculate_mic(
-                        phy_payload.as_ref(),
-                        &nwk_s_key,
-                        dev_addr,
-                        f_cnt32,
-                    );
-                    is_mic_ok = Some(calculated_mic == mic);
-
-                } else {
-                    is_mic_ok = None;
+                if let Err(e) = handle_data_frame::decrypt_uplink(&session, f_port, &mut f_opts, &mut frm_payload) {
+                    log::error!("handle_data_frame::decrypt_uplink() error: {:?}", e);
+                    return;
                 }
 
-                if let Some(app_s_key) = app_s_key {
-
-                    crypto10::frm_payload_crypt(
-                        frm_payload.as_mut(), 
-                        &app_s_key,
-                        dir,
-                        dev_addr,
-                        f_cnt32,
-                    ).unwrap();
-
-                };
+                let mac_cmds = mac_cmds::parse_ul(if f_port == Some(0) { &frm_payload } else { &f_opts });
+                if let Some(e) = &mac_cmds.error {
+                    log::warn!("DevEUI: 0x{:016x} {}", session.dev_eui, e);
+                }
 
                 let print_record = format!( 
 "
@@ -449,8 +383,10 @@ PHYPayload: {}
         FOpts:          {}
         FPort:          {}
         FRMPayload:     {}
+        MACCmds:        {:?}
         MIC:         {}
-        MIC_OK:      {}
+        DevEUI:      {}
+        FCntReset:   {}
     MetaData:
         SpFact:   {}
         Freq:     {}
@@ -475,8 +411,10 @@ PHYPayload: {}
                     hex::encode(&f_opts),
                     match f_port { Some(v) => format!("{}", v), None => "".to_owned() },
                     hex::encode(&frm_payload),
+                    mac_cmds.cmds,
                     hex::encode(mic),
-                    match is_mic_ok { Some(v) => format!("{}", v), None => "".to_owned() },
+                    format!("0x{:016x}", session.dev_eui),
+                    session.is_f_cnt_reset,
                     if &rx_packet.datr[3..4] == "B" { &rx_packet.datr[..3] } else { &rx_packet.datr[..4] },
                     rx_packet.freq, rx_packet.rssi, rx_packet.lsnr,
 
@@ -485,7 +423,7 @@ PHYPayload: {}
                 println!("{}", print_record);
 
                 let log_record = format!(
-                    r#"{{"MType":"{:?}", "DevAddr":"0x{:08x}", "FCtrl_ADR":"{}", "FCtrl_{}":"{}", "FCtrl_ACK":"{}", "FCtrl_{}":"{}", "FCtrl_FOptsLen":"{}", "FCnt":"{}", "FOpts":"{}", "FPort":"{}", "FRMPayload":"{}", "MIC":"{}", "MIC_OK":"{}", "SpFact":"{}", "Freq":"{}", "RSSI":"{}", "SNR":"{}"}}"#,
+                    r#"{{"MType":"{:?}", "DevAddr":"0x{:08x}", "FCtrl_ADR":"{}", "FCtrl_{}":"{}", "FCtrl_ACK":"{}", "FCtrl_{}":"{}", "FCtrl_FOptsLen":"{}", "FCnt":"{}", "FOpts":"{}", "FPort":"{}", "FRMPayload":"{}", "MIC":"{}", "DevEUI":"0x{:016x}", "FCntReset":"{}", "SpFact":"{}", "Freq":"{}", "RSSI":"{}", "SNR":"{}"}}"#,
                     mhdr_m_type, dev_addr, f_ctrl_adr,
                     match dir { Dir::Uplink => "ADRAckReq", Dir::Downlink => "RFU" },
                     f_ctrl_adr_ack_req_or_rfu, f_ctrl_ack,
@@ -495,7 +433,7 @@ PHYPayload: {}
                     match f_port { Some(v) => format!("{}", v), None => "".to_owned() },
                     hex::encode(&frm_payload),
                     hex::encode(mic),
-                    match is_mic_ok { Some(v) => format!("{}", v), None => "".to_owned() },
+                    session.dev_eui, session.is_f_cnt_reset,
                     if &rx_packet.datr[3..4] == "B" { &rx_packet.datr[..3] } else { &rx_packet.datr[..4] },
                     rx_packet.freq, rx_packet.rssi, rx_packet.lsnr,
                 );
@@ -503,6 +441,11 @@ PHYPayload: {}
                 log::info!("{}", log_record);
                 // println!("{}", log_record);
 
+                let app_payload = f_port.filter(|f_port| *f_port > 0).map(|f_port| (f_port, frm_payload.as_slice()));
+                if let Err(e) = handle_data_frame::handle_uplink(&collected_dd_data, rx_packet, &session, &mac_cmds.cmds, app_payload) {
+                    log::warn!("handle_data_frame::handle_uplink() error: {:?}", e);
+                }
+
             },
             lorawan::MType::RejoinRequest => {
 
@@ -524,12 +467,6 @@ PHYPayload: {}
                     }
                 };
 
-                let is_mic_ok: Option<bool>;
-
-                // TODO: get key from db
-                let s_nwk_s_int_key = Some(&[0_u8; 16]);
-                let js_int_key = Some(&[0_u8; 16]);
-
                 match rj_type {
                     RJType::Type0 | RJType::Type2 => {
 
@@ -541,26 +478,13 @@ PHYPayload: {}
                         let mut net_id_array = [0_u8; 4];
                         net_id_array[0..3].copy_from_slice(&phy_payload[2..5]);
                         let net_id = u32::from_le_bytes(net_id_array);
-                        let dev_eui = u64::from_le_bytes(phy_payload[10..18].try_into().unwrap());
-                        let rj_count_02 = u16::from_le_bytes(phy_payload[18..21].try_into().unwrap());
+                        let dev_eui = u64::from_le_bytes(phy_payload[5..13].try_into().unwrap());
+                        let rj_count_02 = u16::from_le_bytes(phy_payload[13..15].try_into().unwrap());
                         let mic: [u8; 4] = (&phy_payload[phy_payload_len - 4..]).try_into().unwrap();
 
-                        is_mic_ok = match s_nwk_s_int_key {
-                            Some(s_nwk_s_int_key) => {
-                                let calculated_mic = crypto12::join_request_calculate_mic(
-                                    s_nwk_s_int_key,
-                                    phy_payload.as_mut(), 
-                                );
-                                Some( mic == calculated_mic )
-                            },
-                            None => {
-                                None
-                            }
-                        };
-
                         let print_record = format!( 
 "
-JoinRequest: {}
+RejoinRequest: {}
     MHDR: {}
         MType: {:?}
         RFU:   {}
@@ -571,7 +495,6 @@ JoinRequest: {}
         DevEUI:   {}
         RJCount02:{}
         MIC:         {}
-        MIC_OK:      {}
     MetaData:
         SpFact:   {}
         Freq:     {}
@@ -584,11 +507,10 @@ JoinRequest: {}
                             mhdr_m_type, mhdr_rfu, mhdr_major,
                             hex::encode(&phy_payload[1..phy_payload_len-4]),
                             rj_type,
-                            format!("0x{:016x}", net_id),
+                            format!("0x{:06x}", net_id),
                             format!("0x{:016x}", dev_eui),
                             format!("0x{:02x}", rj_count_02),
                             hex::encode(&mic),
-                            match is_mic_ok { Some(v) => format!("{}", v), None => "".to_owned() },
                             if &rx_packet.datr[3..4] == "B" { &rx_packet.datr[..3] } else { &rx_packet.datr[..4] },
                             rx_packet.freq, rx_packet.rssi, rx_packet.lsnr,
                         );
@@ -596,10 +518,9 @@ JoinRequest: {}
                         println!("{}", print_record);
                             
                         let log_record = format!(
-                            r#"{{"MType":"{:?}", "RJType":"{:?}", "NetID":"0x{:016x}", "DevEUI":"0x{:016x}", "RJCount02":"0x{:04x}", "MIC":"{}", "MIC_OK":"{}", "SpFact":"{}", "Freq":"{}", "RSSI":"{}", "SNR":"{}"}}"#,
+                            r#"{{"MType":"{:?}", "RJType":"{:?}", "NetID":"0x{:06x}", "DevEUI":"0x{:016x}", "RJCount02":"0x{:04x}", "MIC":"{}", "SpFact":"{}", "Freq":"{}", "RSSI":"{}", "SNR":"{}"}}"#,
                             mhdr_m_type, rj_type, net_id, dev_eui, rj_count_02,
                             hex::encode(&mic),
-                            match is_mic_ok { Some(v) => format!("{}", v), None => "".to_owned() },
                             if &rx_packet.datr[3..4] == "B" { &rx_packet.datr[..3] } else { &rx_packet.datr[..4] },
                             rx_packet.freq, rx_packet.rssi, rx_packet.lsnr,
                         );
@@ -616,25 +537,12 @@ JoinRequest: {}
 
                         let join_eui = u64::from_le_bytes(phy_payload[2..10].try_into().unwrap());
                         let dev_eui = u64::from_le_bytes(phy_payload[10..18].try_into().unwrap());
-                        let rj_count_1 = u16::from_le_bytes(phy_payload[18..21].try_into().unwrap());
+                        let rj_count_1 = u16::from_le_bytes(phy_payload[18..20].try_into().unwrap());
                         let mic: [u8; 4] = (&phy_payload[phy_payload_len - 4..]).try_into().unwrap();
 
-                        is_mic_ok = match js_int_key {
-                            Some(js_int_key) => {
-                                let calculated_mic = crypto12::join_request_calculate_mic(
-                                    js_int_key,
-                                    phy_payload.as_mut(), 
-                                );
-                                Some( mic == calculated_mic )
-                            },
-                            None => {
-                                None
-                            }
-                        };
-
                         let print_record = format!( 
 "
-JoinRequest: {}
+RejoinRequest: {}
     MHDR: {}
         MType: {:?}
         RFU:   {}
@@ -645,7 +553,6 @@ JoinRequest: {}
         DevEUI:   {}
         RJCount1: {}
         MIC:         {}
-        MIC_OK:      {}
     MetaData:
         SpFact:   {}
         Freq:     {}
@@ -661,7 +568,6 @@ JoinRequest: {}
                             format!("0x{:016x}", dev_eui),
                             format!("0x{:02x}", rj_count_1),
                             hex::encode(&mic),
-                            match is_mic_ok { Some(v) => format!("{}", v), None => "".to_owned() },
                             if &rx_packet.datr[3..4] == "B" { &rx_packet.datr[..3] } else { &rx_packet.datr[..4] },
                             rx_packet.freq, rx_packet.rssi, rx_packet.lsnr,
                         );
@@ -669,10 +575,9 @@ JoinRequest: {}
                         println!("{}", print_record);
                             
                         let log_record = format!(
-                            r#"{{"MType":"{:?}", "RJType":"{:?}", "JoinEUI":"0x{:016x}", "DevEUI":"0x{:016x}", "RJCount1":"0x{:04x}", "MIC":"{}", "MIC_OK":"{}", "SpFact":"{}", "Freq":"{}", "RSSI":"{}", "SNR":"{}"}}"#,
+                            r#"{{"MType":"{:?}", "RJType":"{:?}", "JoinEUI":"0x{:016x}", "DevEUI":"0x{:016x}", "RJCount1":"0x{:04x}", "MIC":"{}", "SpFact":"{}", "Freq":"{}", "RSSI":"{}", "SNR":"{}"}}"#,
                             mhdr_m_type, rj_type, join_eui, dev_eui, rj_count_1,
                             hex::encode(&mic),
-                            match is_mic_ok { Some(v) => format!("{}", v), None => "".to_owned() },
                             if &rx_packet.datr[3..4] == "B" { &rx_packet.datr[..3] } else { &rx_packet.datr[..4] },
                             rx_packet.freq, rx_packet.rssi, rx_packet.lsnr,
                         );
@@ -681,6 +586,11 @@ JoinRequest: {}
                         // println!("{}", log_record);
                     }
                 }
+
+                if let Err(e) = handle_rejoin_request(&collected_dd_data, rx_packet, rj_type, &phy_payload) {
+                    log::error!("handle_rejoin_request() error: {:?}", e);
+                }
+
             },
         }
 
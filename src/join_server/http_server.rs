@@ -0,0 +1,356 @@
+use std::{
+    convert::Infallible,
+    net::SocketAddr,
+};
+use hyper::{
+    Body, Method, Request, Response, Server, StatusCode,
+    service::{make_service_fn, service_fn},
+};
+use anyhow::{ Result as AnyResult, anyhow };
+
+use crate::{
+    lorawan_config,
+    backend::messages::{self, JoinReq, JoinAns, AppSKeyReq, AppSKeyAns, KeyEnvelope, BackendResult},
+    join_server::{self, jsctx, JoinReqParams, SessionKeys},
+};
+
+/// Serves the Backend Interfaces API of the Join Server (JoinReq, RejoinReq, AppSKeyReq)
+///
+/// Every message is a JSON document POSTed to the root URL, the answer is sent in the
+/// HTTP response (synchronous mode).
+///
+pub async fn run(addr: &str) -> AnyResult<()> {
+
+    let addr: SocketAddr = addr.parse()
+        .map_err(|e| anyhow!("invalid Join Server address: {}: {}", addr, e))?;
+
+    let make_svc = make_service_fn(|_conn| async {
+        Ok::<_, Infallible>(service_fn(handle_http_request))
+    });
+
+    log::info!("Join Server is listening on {}", addr);
+
+    Server::try_bind(&addr)?
+        .serve(make_svc)
+        .await?;
+
+    Ok(())
+
+}
+
+async fn handle_http_request(req: Request<Body>) -> Result<Response<Body>, Infallible> {
+
+    if req.method() != Method::POST {
+        return Ok(http_response(StatusCode::METHOD_NOT_ALLOWED, String::new()));
+    }
+
+    let body = match hyper::body::to_bytes(req.into_body()).await {
+        Ok(body) => body,
+        Err(e) => return Ok(http_response(StatusCode::BAD_REQUEST, e.to_string())),
+    };
+
+    // The Join Server state is locked and written to the disk synchronously
+    let answer = tokio::task::spawn_blocking(move || handle_message(&body))
+        .await
+        .unwrap_or_else(|e| Err(anyhow!("Join Server task failed: {}", e)));
+
+    match answer {
+        Ok(answer) => Ok(http_response(StatusCode::OK, answer)),
+        Err(e) => {
+            log::error!("Join Server error: {:?}", e);
+            Ok(http_response(StatusCode::BAD_REQUEST, e.to_string()))
+        },
+    }
+
+}
+
+fn http_response(status: StatusCode, body: String) -> Response<Body> {
+    let mut res = Response::new(Body::from(body));
+    *res.status_mut() = status;
+    res.headers_mut().insert(hyper::header::CONTENT_TYPE, "application/json".parse().unwrap());
+    res
+}
+
+/// Dispatches a Backend Interfaces message by its `MessageType` and returns the JSON answer
+pub fn handle_message(body: &[u8]) -> AnyResult<String> {
+
+    let msg: serde_json::Value = serde_json::from_slice(body)?;
+    let message_type = msg["MessageType"].as_str()
+        .ok_or_else(|| anyhow!("MessageType is missing"))?;
+
+    log::trace!("{} received: {}", message_type, msg);
+
+    let answer = match message_type {
+        "JoinReq" | "RejoinReq" => serde_json::to_string(&answer_join_req(serde_json::from_value(msg)?))?,
+        "AppSKeyReq" => serde_json::to_string(&answer_app_s_key_req(serde_json::from_value(msg)?))?,
+        _ => return Err(anyhow!("unsupported MessageType: {}", message_type)),
+    };
+
+    log::trace!("answer sent: {}", answer);
+
+    Ok(answer)
+
+}
+
+fn answer_join_req(join_req: JoinReq) -> JoinAns {
+
+    let is_rejoin = join_req.message_type == "RejoinReq";
+
+    let mut join_ans = JoinAns {
+        protocol_version: messages::PROTOCOL_VERSION.to_string(),
+        sender_id: join_req.receiver_id.clone(),
+        receiver_id: join_req.sender_id.clone(),
+        transaction_id: join_req.transaction_id,
+        message_type: if is_rejoin { "RejoinAns" } else { "JoinAns" }.to_string(),
+        result: BackendResult::success(),
+        phy_payload: None,
+        lifetime: None,
+        s_nwk_s_int_key: None,
+        f_nwk_s_int_key: None,
+        nwk_s_enc_key: None,
+        nwk_s_key: None,
+        app_s_key: None,
+        session_key_id: None,
+    };
+
+    let join_eui = match parse_join_eui(&join_req.receiver_id) {
+        Ok(join_eui) => join_eui,
+        Err(e) => {
+            join_ans.result = BackendResult::failure("UnknownReceiverID", e.to_string());
+            return join_ans;
+        }
+    };
+
+    if let Err(e) = check_sender_id(&join_req) {
+        join_ans.result = BackendResult::failure("UnknownSender", e.to_string());
+        return join_ans;
+    }
+
+    let join_ans_params = parse_join_req_params(&join_req, join_eui, is_rejoin)
+        .and_then(|(phy_payload, net_id, dev_addr, dl_settings, cf_list)| {
+            let req = JoinReqParams {
+                phy_payload: &phy_payload,
+                net_id,
+                dev_addr,
+                dl_settings,
+                rx_delay: join_req.rx_delay,
+                cf_list,
+            };
+            if is_rejoin {
+                join_server::handle_rejoin_req(&req)
+            } else {
+                join_server::handle_join_req(&req)
+            }
+        });
+
+    match join_ans_params {
+        Ok(params) => {
+            // The network session keys are wrapped for the NS, the AppSKey for the AS of the device
+            let lorawan_config = lorawan_config::get_or_init();
+            let bi_config = &lorawan_config.server_config.backend_interfaces;
+            let ns_kek = bi_config.kek_for_peer(&join_req.sender_id);
+            let as_kek = u64::from_str_radix(&join_req.dev_eui, 16).ok()
+                .and_then(|dev_eui| lorawan_config.devices.get(&dev_eui))
+                .and_then(|device_record| device_record.js.as_ref())
+                .and_then(|js| bi_config.kek_for_peer(&js.as_id));
+            join_ans.phy_payload = Some(hex::encode_upper(&params.phy_payload));
+            join_ans.lifetime = Some(0);
+            match params.session_keys {
+                SessionKeys::V10x { nwk_s_key } => {
+                    join_ans.nwk_s_key = Some(KeyEnvelope::new(&nwk_s_key, ns_kek));
+                },
+                SessionKeys::V12x { f_nwk_s_int_key, s_nwk_s_int_key, nwk_s_enc_key } => {
+                    join_ans.f_nwk_s_int_key = Some(KeyEnvelope::new(&f_nwk_s_int_key, ns_kek));
+                    join_ans.s_nwk_s_int_key = Some(KeyEnvelope::new(&s_nwk_s_int_key, ns_kek));
+                    join_ans.nwk_s_enc_key = Some(KeyEnvelope::new(&nwk_s_enc_key, ns_kek));
+                },
+            }
+            join_ans.app_s_key = params.app_s_key.map(|app_s_key| KeyEnvelope::new(&app_s_key, as_kek));
+            join_ans.session_key_id = params.session_key_id;
+        },
+        Err(e) => {
+            let result_code = if is_rejoin { "RejoinReqFailed" } else { "JoinReqFailed" };
+            join_ans.result = BackendResult::failure(result_code, e.to_string());
+        },
+    }
+
+    join_ans
+
+}
+
+type JoinReqFields = (Vec<u8>, u32, u32, u8, Option<[u8; 16]>);
+
+fn parse_join_req_params(join_req: &JoinReq, join_eui: u64, is_rejoin: bool) -> AnyResult<JoinReqFields> {
+
+    let phy_payload = hex::decode(&join_req.phy_payload)?;
+
+    // Type 0 and 2 Rejoin-Requests do not carry the JoinEUI
+    if !is_rejoin && (phy_payload.len() < 9 || phy_payload[1..9] != join_eui.to_le_bytes()) {
+        return Err(anyhow!("the JoinEUI of the PHYPayload does not match ReceiverID: {}", join_req.receiver_id));
+    }
+
+    let net_id = u32::from_str_radix(&join_req.sender_id, 16)?;
+    let dev_addr = u32::from_str_radix(&join_req.dev_addr, 16)?;
+    let dl_settings = u8::from_str_radix(&join_req.dl_settings, 16)?;
+    let cf_list = match &join_req.cf_list {
+        Some(cf_list) => Some(
+            hex::decode(cf_list)?
+                .try_into()
+                .map_err(|_| anyhow!("invalid CFList: {}", cf_list))?
+        ),
+        None => None,
+    };
+
+    Ok((phy_payload, net_id, dev_addr, dl_settings, cf_list))
+
+}
+
+fn answer_app_s_key_req(app_s_key_req: AppSKeyReq) -> AppSKeyAns {
+
+    let mut app_s_key_ans = AppSKeyAns {
+        protocol_version: messages::PROTOCOL_VERSION.to_string(),
+        sender_id: app_s_key_req.receiver_id.clone(),
+        receiver_id: app_s_key_req.sender_id.clone(),
+        transaction_id: app_s_key_req.transaction_id,
+        message_type: "AppSKeyAns".to_string(),
+        result: BackendResult::success(),
+        dev_eui: app_s_key_req.dev_eui.clone(),
+        app_s_key: None,
+        session_key_id: app_s_key_req.session_key_id.clone(),
+    };
+
+    if let Err(e) = parse_join_eui(&app_s_key_req.receiver_id) {
+        app_s_key_ans.result = BackendResult::failure("UnknownReceiverID", e.to_string());
+        return app_s_key_ans;
+    }
+
+    let app_s_key = u64::from_str_radix(&app_s_key_req.dev_eui, 16)
+        .ok()
+        .and_then(|dev_eui| jsctx::get_app_s_key(dev_eui, &app_s_key_req.session_key_id));
+
+    match app_s_key {
+        Some(app_s_key) => {
+            let bi_config = &lorawan_config::get_or_init().server_config.backend_interfaces;
+            let as_kek = bi_config.kek_for_peer(&app_s_key_req.sender_id);
+            app_s_key_ans.app_s_key = Some(KeyEnvelope::new(&app_s_key, as_kek));
+        },
+        None => {
+            app_s_key_ans.result = BackendResult::failure(
+                "UnknownDevEUI",
+                format!("no session for DevEUI: {} SessionKeyID: {}", app_s_key_req.dev_eui, app_s_key_req.session_key_id),
+            );
+        },
+    }
+
+    app_s_key_ans
+
+}
+
+/// Checks that the SenderID (NetID) of a JoinReq is the home network of the device
+fn check_sender_id(join_req: &JoinReq) -> AnyResult<()> {
+    let net_id = u32::from_str_radix(&join_req.sender_id, 16)
+        .map_err(|e| anyhow!("invalid SenderID: {}: {}", join_req.sender_id, e))?;
+    let dev_eui = u64::from_str_radix(&join_req.dev_eui, 16)
+        .map_err(|e| anyhow!("invalid DevEUI: {}: {}", join_req.dev_eui, e))?;
+    let ctx = jsctx::get_js_device_context(dev_eui)
+        .ok_or_else(|| anyhow!("unknown DevEUI: 0x{:016x}", dev_eui))?;
+    if ctx.home_net_id != net_id {
+        return Err(anyhow!("NetID: 0x{:06x} is not the home network of DevEUI: 0x{:016x}", net_id, dev_eui));
+    }
+    Ok(())
+}
+
+/// Parses the ReceiverID and checks that the JoinEUI is served by this Join Server
+fn parse_join_eui(receiver_id: &str) -> AnyResult<u64> {
+    let join_eui = u64::from_str_radix(receiver_id, 16)?;
+    let js_config = &lorawan_config::get_or_init().server_config.js;
+    if !js_config.join_euis.contains(&join_eui) {
+        return Err(anyhow!("JoinEUI: 0x{:016x} is not served by this Join Server", join_eui));
+    }
+    Ok(join_eui)
+}
+
+
+#[cfg(test)]
+mod tests {
+
+    use super::*;
+    use crate::lorawan::crypto::{crypto12, utils::key_from_string};
+
+    #[test]
+    fn test_join_req_and_app_s_key_req() {
+
+        // A state file of its own, the test must not depend on (or modify) the state of earlier runs
+        let state_dir = std::env::temp_dir().join(format!("lws_http_server_{}", std::process::id()));
+        jsctx::init_js_db(state_dir.join("js_state.json").to_str().unwrap());
+
+        // DevEUI 0x0000000000000002 of devices.yaml, JoinEUI served according to server_config.yaml
+        let nwk_key = key_from_string("ffeeddccbbaa99887766554433221100").unwrap();
+        let join_eui: u64 = 0xaa00000000000001;
+        let mut phy_payload = vec![0x00];
+        phy_payload.extend_from_slice(&join_eui.to_le_bytes());
+        phy_payload.extend_from_slice(&2_u64.to_le_bytes());
+        phy_payload.extend_from_slice(&0x0100_u16.to_le_bytes());
+        phy_payload.extend_from_slice(&[0; 4]);
+        let mic = crypto12::join_request_calculate_mic(&nwk_key, &phy_payload);
+        phy_payload[19..23].copy_from_slice(&mic);
+
+        let mut join_req = JoinReq {
+            protocol_version: messages::PROTOCOL_VERSION.to_string(),
+            sender_id: "B00001".to_string(),
+            receiver_id: format!("{:016X}", join_eui),
+            transaction_id: 1,
+            message_type: "JoinReq".to_string(),
+            mac_version: "1.1".to_string(),
+            phy_payload: hex::encode_upper(&phy_payload),
+            dev_eui: "0000000000000002".to_string(),
+            dev_addr: "00000002".to_string(),
+            dl_settings: "00".to_string(),
+            rx_delay: 1,
+            cf_list: None,
+        };
+
+        // Only the home network of the device is served
+        let answer = handle_message(serde_json::to_string(&join_req).unwrap().as_bytes()).unwrap();
+        let join_ans: JoinAns = serde_json::from_str(&answer).unwrap();
+        assert_eq!(join_ans.result.result_code, "UnknownSender");
+
+        join_req.sender_id = "AABBCC".to_string();
+        let answer = handle_message(serde_json::to_string(&join_req).unwrap().as_bytes()).unwrap();
+        let join_ans: JoinAns = serde_json::from_str(&answer).unwrap();
+        assert!(join_ans.result.is_success(), "{:?}", join_ans.result);
+        assert_eq!(join_ans.phy_payload.unwrap().len(), 2 * 17);
+        let app_s_key = join_ans.app_s_key.unwrap();
+
+        // The same DevNonce is rejected
+        let answer = handle_message(serde_json::to_string(&join_req).unwrap().as_bytes()).unwrap();
+        let replayed_join_ans: JoinAns = serde_json::from_str(&answer).unwrap();
+        assert_eq!(replayed_join_ans.result.result_code, "JoinReqFailed");
+
+        let app_s_key_req = AppSKeyReq {
+            protocol_version: messages::PROTOCOL_VERSION.to_string(),
+            sender_id: "as-1".to_string(),
+            receiver_id: format!("{:016X}", join_eui),
+            transaction_id: 2,
+            message_type: "AppSKeyReq".to_string(),
+            dev_eui: "0000000000000002".to_string(),
+            session_key_id: join_ans.session_key_id.unwrap(),
+        };
+        let answer = handle_message(serde_json::to_string(&app_s_key_req).unwrap().as_bytes()).unwrap();
+        let app_s_key_ans: AppSKeyAns = serde_json::from_str(&answer).unwrap();
+        assert!(app_s_key_ans.result.is_success());
+
+        // The AppSKey is wrapped for "as-1" according to server_config.yaml
+        let bi_config = &lorawan_config::get_or_init().server_config.backend_interfaces;
+        let wrapped_app_s_key = app_s_key_ans.app_s_key.unwrap();
+        assert_eq!(wrapped_app_s_key.kek_label, "js-kek");
+        assert_eq!(
+            wrapped_app_s_key.unwrap(|label| bi_config.kek(label)).unwrap(),
+            app_s_key.unwrap(|label| bi_config.kek(label)).unwrap(),
+        );
+
+        std::fs::remove_dir_all(state_dir).unwrap();
+
+    }
+
+}
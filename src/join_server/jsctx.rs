@@ -0,0 +1,354 @@
+use std::{
+    path::PathBuf,
+    sync::{
+        Mutex,
+        OnceLock,
+    },
+    collections::{HashMap, VecDeque},
+};
+use anyhow::{ Result as AnyResult, anyhow };
+
+use crate::{
+    settings,
+    lorawan_config,
+    join_server::nonce_store::{self, JoinNonceScope, NonceState, DeviceNonces, DEV_NONCE_HISTORY_LEN},
+    lorawan::{
+        MACVersion, RJType,
+        crypto::crypto12,
+    },
+};
+
+/// The device related data kept by the Join Server
+#[derive(Clone)]
+pub struct JSDeviceContext {
+    pub mac_version: MACVersion,
+    pub app_key: [u8; 16],
+    pub nwk_key: [u8; 16],         // = app_key for LoRaWAN 1.0.x devices
+    pub js_int_key: [u8; 16],      // LoRaWAN 1.1+ only
+    pub js_enc_key: [u8; 16],      // LoRaWAN 1.1+ only
+    pub home_net_id: u32,
+    pub join_eui: u64,             // the JoinEUI of the last accepted Join-Request
+    pub last_dev_nonce: Option<u16>, // LoRaWAN 1.1+: the DevNonce high-water mark
+    pub dev_nonce_history: VecDeque<u16>, // LoRaWAN 1.0.x: the recently used DevNonces
+    pub rj_count_1: u16,           // the lowest acceptable RJcount1
+    pub join_nonce: u32,           // the last JoinNonce sent to the device (3 bytes)
+    pub session_key_id: String,    // the SessionKeyID of the last session
+    pub app_s_key: Option<[u8; 16]>, // the AppSKey of the last session, delivered to the AS on request
+}
+
+static JS_DB: OnceLock<Mutex<HashMap<u64, JSDeviceContext>>> = OnceLock::new();
+
+// The last JoinNonce per JoinEUI (used with JoinNonceScope::JoinEUI)
+static JOIN_EUI_NONCES: OnceLock<Mutex<HashMap<u64, u32>>> = OnceLock::new();
+
+// The file keeping the nonces across restarts (None: the nonces are kept in memory only)
+static NONCE_FILE: OnceLock<Option<PathBuf>> = OnceLock::new();
+
+/// Loads the device contexts of the Join Server
+///
+/// # Arguments
+///
+/// * __`nonce_file`__\
+///   The file keeping the nonces across restarts, if empty the nonces are kept in memory only \
+///
+pub fn init_js_db(nonce_file: &str) {
+
+    let lorawan_config = lorawan_config::get_or_init();
+
+    let nonce_file = (!nonce_file.is_empty()).then(|| PathBuf::from(nonce_file));
+    let state = match &nonce_file {
+        Some(path) => nonce_store::load(path).unwrap_or_else(|e| {
+            eprintln!("error: {:?}", e);
+            std::process::exit(1);
+        }),
+        None => NonceState::default(),
+    };
+
+    let mut db: HashMap<u64, JSDeviceContext> = HashMap::with_capacity(lorawan_config.devices.len());
+
+    for (dev_eui, device_record) in &lorawan_config.devices {
+
+        let Some(js) = &device_record.js else { continue };
+
+        let mac_version = match MACVersion::from_version_str(&js.lora_wan_version) {
+            Ok(v) => v,
+            Err(e) => {
+                log::error!("DevEUI: 0x{:016x} is skipped: {:?}", dev_eui, e);
+                continue;
+            }
+        };
+
+        let nwk_key = js.nwk_key.unwrap_or(js.app_key);
+        let js_keys = crypto12::derive_js_keys(&nwk_key, *dev_eui);
+
+        db.insert(*dev_eui, JSDeviceContext {
+            mac_version,
+            app_key: js.app_key,
+            nwk_key,
+            js_int_key: js_keys.js_int_key,
+            js_enc_key: js_keys.js_enc_key,
+            home_net_id: js.home_net_id,
+            join_eui: 0,
+            // DevNonce 0 is the first value a LoRaWAN 1.1+ device uses, so it does not count as used
+            last_dev_nonce: if js.dev_nonce == 0 && js.join_nonce == 0 { None } else { Some(js.dev_nonce) },
+            dev_nonce_history: VecDeque::new(),
+            rj_count_1: js.rj_count_1,
+            join_nonce: js.join_nonce,
+            session_key_id: String::new(),
+            app_s_key: None,
+        });
+
+    }
+
+    // The persisted state overrides devices.yaml, the counters never go backwards
+    for (dev_eui, nonces) in &state.devices {
+        let Ok(dev_eui) = u64::from_str_radix(dev_eui, 16) else { continue };
+        let Some(ctx) = db.get_mut(&dev_eui) else { continue };
+        ctx.join_nonce = ctx.join_nonce.max(nonces.join_nonce);
+        ctx.last_dev_nonce = ctx.last_dev_nonce.max(nonces.dev_nonce_high_water);
+        ctx.dev_nonce_history = nonces.dev_nonce_history.clone();
+        ctx.rj_count_1 = ctx.rj_count_1.max(nonces.rj_count_1);
+        // Rejoin-Requests of type 0 and 2 do not carry the JoinEUI
+        if let Some(join_eui) = nonces.join_eui.as_ref().and_then(|v| u64::from_str_radix(v, 16).ok()) {
+            ctx.join_eui = join_eui;
+        }
+        // The AS may request the AppSKey of the last session after a restart
+        if let (Some(session_key_id), Some(app_s_key)) = (&nonces.session_key_id, &nonces.app_s_key) {
+            match lorawan_config::registry_key_from_string(app_s_key) {
+                Ok(app_s_key) => {
+                    ctx.session_key_id = session_key_id.clone();
+                    ctx.app_s_key = Some(app_s_key);
+                },
+                Err(e) => log::error!("the AppSKey of DevEUI: 0x{:016x} is not loaded: {:?}", dev_eui, e),
+            }
+        }
+    }
+    let join_eui_nonces: HashMap<u64, u32> = state.join_euis
+        .iter()
+        .filter_map(|(join_eui, join_nonce)| Some((u64::from_str_radix(join_eui, 16).ok()?, *join_nonce)))
+        .collect();
+
+    let _ = NONCE_FILE.set(nonce_file);
+    let _ = JOIN_EUI_NONCES.set(Mutex::new(join_eui_nonces));
+    let _ = JS_DB.set(Mutex::new(db));
+
+}
+
+pub fn get_js_device_context(dev_eui: u64) -> Option<JSDeviceContext> {
+    JS_DB
+        .get()
+        .unwrap()
+        .lock()
+        .unwrap()
+        .get(&dev_eui)
+        .cloned()
+}
+
+/// Checks the DevNonce of an authenticated Join-Request and allocates the next JoinNonce
+///
+/// LoRaWAN 1.1+ devices use DevNonce as a counter, so it SHALL be strictly increasing.
+/// LoRaWAN 1.0.x devices use a random DevNonce, so the recently used values are rejected.
+///
+/// The new state is durably stored before the JoinNonce is returned, so the Join-Accept
+/// is never sent with a JoinNonce that could be reused after a restart.
+///
+/// Returns the JoinNonce to be used in the Join-Accept.
+///
+pub fn commit_join(dev_eui: u64, join_eui: u64, dev_nonce: u16) -> AnyResult<u32> {
+
+    let mut db = JS_DB
+        .get()
+        .unwrap()
+        .lock()
+        .unwrap();
+
+    let ctx = db.get(&dev_eui)
+        .ok_or_else(|| anyhow!("unknown DevEUI: 0x{:016x}", dev_eui))?;
+
+    let is_fresh = match (ctx.mac_version, ctx.last_dev_nonce) {
+        (MACVersion::V12x, Some(last)) => dev_nonce > last,
+        (MACVersion::V12x, None) => true,
+        (MACVersion::V10x, _) => !ctx.dev_nonce_history.contains(&dev_nonce),
+    };
+    if !is_fresh {
+        return Err(anyhow!(
+            "replayed DevNonce: 0x{:04x}, last: 0x{:04x}", dev_nonce, ctx.last_dev_nonce.unwrap_or(0)
+        ));
+    }
+
+    let mut new_ctx = ctx.clone();
+    new_ctx.last_dev_nonce = Some(dev_nonce);
+    if new_ctx.mac_version == MACVersion::V10x {
+        if new_ctx.dev_nonce_history.len() >= DEV_NONCE_HISTORY_LEN {
+            new_ctx.dev_nonce_history.pop_front();
+        }
+        new_ctx.dev_nonce_history.push_back(dev_nonce);
+    }
+    new_ctx.join_eui = join_eui;
+
+    commit(&mut db, dev_eui, new_ctx)
+
+}
+
+/// Checks the RJcount of an authenticated Rejoin-Request and allocates the next JoinNonce
+///
+/// RJcount1 of type 1 Rejoin-Requests is checked here, RJcount0 of type 0 and 2
+/// Rejoin-Requests is checked by the Network Server, which owns `SNwkSIntKey`.
+///
+/// Returns the JoinNonce to be used in the Join-Accept.
+///
+/// # Specification
+///
+/// LoRaWAN 1.2.0 Draft 47 - line #2606 \
+/// 6.2.4.4 Rejoin-Request message      \
+///
+pub fn commit_rejoin(dev_eui: u64, rj_type: RJType, rj_count: u16) -> AnyResult<u32> {
+
+    let mut db = JS_DB
+        .get()
+        .unwrap()
+        .lock()
+        .unwrap();
+
+    let ctx = db.get(&dev_eui)
+        .ok_or_else(|| anyhow!("unknown DevEUI: 0x{:016x}", dev_eui))?;
+
+    if ctx.mac_version != MACVersion::V12x {
+        return Err(anyhow!("Rejoin-Request from a LoRaWAN 1.0.x device, DevEUI: 0x{:016x}", dev_eui));
+    }
+
+    let mut new_ctx = ctx.clone();
+    if let RJType::Type1 = rj_type {
+        if rj_count < ctx.rj_count_1 {
+            return Err(anyhow!(
+                "replayed RJcount1: 0x{:04x}, expected at least: 0x{:04x}", rj_count, ctx.rj_count_1
+            ));
+        }
+        new_ctx.rj_count_1 = rj_count.checked_add(1)
+            .ok_or_else(|| anyhow!("RJcount1 is exhausted for DevEUI: 0x{:016x}", dev_eui))?;
+    }
+
+    commit(&mut db, dev_eui, new_ctx)
+
+}
+
+/// Allocates the next JoinNonce for the updated device context and makes the new state durable
+///
+/// The in-memory state is changed only if the state file could be written.
+///
+fn commit(db: &mut HashMap<u64, JSDeviceContext>, dev_eui: u64, mut new_ctx: JSDeviceContext) -> AnyResult<u32> {
+
+    let scope = settings::get_or_init().join_server.join_nonce_scope;
+
+    let mut join_eui_nonces = JOIN_EUI_NONCES
+        .get()
+        .unwrap()
+        .lock()
+        .unwrap();
+
+    let last_join_nonce = match scope {
+        JoinNonceScope::Device => new_ctx.join_nonce,
+        // The device's own JoinNonce is checked too, the counter must grow for the device
+        JoinNonceScope::JoinEUI => new_ctx.join_nonce.max(
+            join_eui_nonces.get(&new_ctx.join_eui).copied().unwrap_or(0)
+        ),
+    };
+    let join_nonce = (last_join_nonce + 1) & 0x00ffffff;
+    if join_nonce == 0 {
+        return Err(anyhow!("JoinNonce is exhausted for DevEUI: 0x{:016x}", dev_eui));
+    }
+    new_ctx.join_nonce = join_nonce;
+
+    let old_ctx = db.insert(dev_eui, new_ctx.clone());
+    let old_join_eui_nonce = match scope {
+        JoinNonceScope::Device => None,
+        JoinNonceScope::JoinEUI => Some(join_eui_nonces.insert(new_ctx.join_eui, join_nonce)),
+    };
+
+    if let Some(path) = NONCE_FILE.get().unwrap() {
+        if let Err(e) = nonce_state(db, &join_eui_nonces).and_then(|state| nonce_store::save(path, &state)) {
+            // roll back, the JoinNonce must not be used
+            if let Some(old_ctx) = old_ctx {
+                db.insert(dev_eui, old_ctx);
+            }
+            match old_join_eui_nonce {
+                Some(Some(old)) => { join_eui_nonces.insert(new_ctx.join_eui, old); },
+                Some(None) => { join_eui_nonces.remove(&new_ctx.join_eui); },
+                None => {},
+            }
+            return Err(anyhow!("failed to store the Join Server state: {:?}", e));
+        }
+    }
+
+    Ok(join_nonce)
+
+}
+
+fn nonce_state(db: &HashMap<u64, JSDeviceContext>, join_eui_nonces: &HashMap<u64, u32>) -> AnyResult<NonceState> {
+    let mut devices = HashMap::with_capacity(db.len());
+    for (dev_eui, ctx) in db {
+        devices.insert(
+            format!("{:016X}", dev_eui),
+            DeviceNonces {
+                join_nonce: ctx.join_nonce,
+                dev_nonce_high_water: ctx.last_dev_nonce,
+                dev_nonce_history: ctx.dev_nonce_history.clone(),
+                rj_count_1: ctx.rj_count_1,
+                join_eui: (ctx.join_eui != 0).then(|| format!("{:016X}", ctx.join_eui)),
+                session_key_id: ctx.app_s_key.is_some().then(|| ctx.session_key_id.clone()),
+                app_s_key: ctx.app_s_key.as_ref().map(lorawan_config::registry_key_to_string).transpose()?,
+            },
+        );
+    }
+    Ok(NonceState {
+        devices,
+        join_euis: join_eui_nonces
+            .iter()
+            .map(|(join_eui, join_nonce)| (format!("{:016X}", join_eui), *join_nonce))
+            .collect(),
+    })
+}
+
+/// Stores the AppSKey of a new session until the Application Server requests it
+///
+/// The key is kept in the state file too, so it can be requested after a restart.
+///
+/// Returns the SessionKeyID (`DevEUI|JoinNonce` in hex) identifying the session.
+///
+pub fn set_app_s_key(dev_eui: u64, join_nonce: u32, app_s_key: [u8; 16]) -> AnyResult<String> {
+
+    let session_key_id = format!("{:016X}{:06X}", dev_eui, join_nonce);
+
+    let mut db = JS_DB
+        .get()
+        .unwrap()
+        .lock()
+        .unwrap();
+
+    let ctx = db.get_mut(&dev_eui)
+        .ok_or_else(|| anyhow!("unknown DevEUI: 0x{:016x}", dev_eui))?;
+    ctx.session_key_id = session_key_id.clone();
+    ctx.app_s_key = Some(app_s_key);
+
+    if let Some(path) = NONCE_FILE.get().unwrap() {
+        let join_eui_nonces = JOIN_EUI_NONCES.get().unwrap().lock().unwrap();
+        nonce_state(&db, &join_eui_nonces)
+            .and_then(|state| nonce_store::save(path, &state))
+            .map_err(|e| anyhow!("failed to store the AppSKey of DevEUI: 0x{:016x}: {:?}", dev_eui, e))?;
+    }
+
+    Ok(session_key_id)
+
+}
+
+/// Returns the AppSKey of the session identified by the SessionKeyID
+pub fn get_app_s_key(dev_eui: u64, session_key_id: &str) -> Option<[u8; 16]> {
+    JS_DB
+        .get()
+        .unwrap()
+        .lock()
+        .unwrap()
+        .get(&dev_eui)
+        .filter(|ctx| ctx.session_key_id == session_key_id)
+        .and_then(|ctx| ctx.app_s_key)
+}
@@ -0,0 +1,289 @@
+/// Join Server state (root keys and nonces)
+pub mod jsctx;
+
+/// Backend Interfaces HTTP API of the standalone Join Server role
+pub mod http_server;
+
+/// Persistent JoinNonce, DevNonce and RJcount1 state
+pub mod nonce_store;
+
+use anyhow::{ Result as AnyResult, anyhow };
+
+use crate::lorawan::{
+    MType, MACVersion, RJType,
+    crypto::{crypto10, crypto12},
+};
+
+/// The input of the Join Server, as provided by the Network Server
+pub struct JoinReqParams<'a> {
+    pub phy_payload: &'a [u8],     // MHDR|JoinEUI|DevEUI|DevNonce|MIC or a Rejoin-Request
+    pub net_id: u32,
+    pub dev_addr: u32,
+    pub dl_settings: u8,           // RX1DROffset|RX2DataRate, OptNeg is set by the Join Server
+    pub rx_delay: u8,
+    pub cf_list: Option<[u8; 16]>,
+}
+
+/// The network session keys of a Join-Accept
+pub enum SessionKeys {
+    V10x { nwk_s_key: [u8; 16] },
+    V12x { f_nwk_s_int_key: [u8; 16], s_nwk_s_int_key: [u8; 16], nwk_s_enc_key: [u8; 16] },
+}
+
+/// The output of the Join Server
+pub struct JoinAnsParams {
+    pub phy_payload: Vec<u8>,      // encrypted Join-Accept
+    pub session_keys: SessionKeys,
+    pub app_s_key: Option<[u8; 16]>, // absent when the Join Server delivers it to the Application Server only
+    pub session_key_id: Option<String>,
+}
+impl JoinAnsParams {
+    fn v10x(phy_payload: Vec<u8>, s_keys: crypto10::SKeys, session_key_id: String) -> Self {
+        JoinAnsParams {
+            phy_payload,
+            session_keys: SessionKeys::V10x { nwk_s_key: s_keys.nwk_s_key },
+            app_s_key: Some(s_keys.app_s_key),
+            session_key_id: Some(session_key_id),
+        }
+    }
+    fn v12x(phy_payload: Vec<u8>, s_keys: crypto12::SKeys, session_key_id: String) -> Self {
+        JoinAnsParams {
+            phy_payload,
+            session_keys: SessionKeys::V12x {
+                f_nwk_s_int_key: s_keys.f_nwk_s_int_key,
+                s_nwk_s_int_key: s_keys.s_nwk_s_int_key,
+                nwk_s_enc_key: s_keys.nwk_s_enc_key,
+            },
+            app_s_key: Some(s_keys.app_s_key),
+            session_key_id: Some(session_key_id),
+        }
+    }
+}
+
+/// Processes a Join-Request on behalf of an (internal) Join Server
+///
+/// Validates the MIC with `NwkKey` (`AppKey` for LoRaWAN 1.0.x), checks the DevNonce,
+/// allocates a JoinNonce, derives the session keys and builds the encrypted Join-Accept.
+///
+/// # Specification
+///
+/// LoRaWAN 1.2.0 Draft 47 - line #2442 \
+/// 6.2.2 Join-Request frame            \
+/// 6.2.3 Join-Accept frame             \
+///
+pub fn handle_join_req(req: &JoinReqParams) -> AnyResult<JoinAnsParams> {
+
+    let phy_payload = req.phy_payload;
+    if phy_payload.len() != 23 {
+        return Err(anyhow!("invalid Join-Request length: {}", phy_payload.len()));
+    }
+
+    let join_eui = u64::from_le_bytes(phy_payload[1..9].try_into().unwrap());
+    let dev_eui = u64::from_le_bytes(phy_payload[9..17].try_into().unwrap());
+    let dev_nonce = u16::from_le_bytes(phy_payload[17..19].try_into().unwrap());
+    let mic: [u8; 4] = phy_payload[19..23].try_into().unwrap();
+
+    let ctx = jsctx::get_js_device_context(dev_eui)
+        .ok_or_else(|| anyhow!("unknown DevEUI: 0x{:016x}", dev_eui))?;
+
+    let calculated_mic = match ctx.mac_version {
+        MACVersion::V10x => crypto10::join_frame_calculate_mic(&ctx.app_key, phy_payload),
+        MACVersion::V12x => crypto12::join_request_calculate_mic(&ctx.nwk_key, phy_payload),
+    };
+    if calculated_mic != mic {
+        return Err(anyhow!("invalid Join-Request MIC, DevEUI: 0x{:016x}", dev_eui));
+    }
+
+    let join_nonce = jsctx::commit_join(dev_eui, join_eui, dev_nonce)?;
+
+    match ctx.mac_version {
+
+        MACVersion::V10x => {
+
+            let mut ja = build_join_accept(join_nonce, req.net_id, req.dev_addr, req.dl_settings, req.rx_delay, req.cf_list);
+            let ja_len = ja.len();
+            let mic = crypto10::join_frame_calculate_mic(&ctx.app_key, &ja);
+            ja[ja_len - 4..].copy_from_slice(&mic);
+            crypto10::join_accept_encrypt(&ctx.app_key, &mut ja);
+
+            let s_keys = crypto10::derive_s_keys(&ctx.app_key, join_nonce, req.net_id, dev_nonce);
+            let session_key_id = jsctx::set_app_s_key(dev_eui, join_nonce, s_keys.app_s_key)?;
+
+            Ok(JoinAnsParams::v10x(ja, s_keys, session_key_id))
+
+        },
+
+        MACVersion::V12x => {
+            let ja = build_join_accept_v12x(
+                &ctx, req, crypto12::JoinReqType::JoinReq, join_eui, dev_nonce, join_nonce, &ctx.nwk_key,
+            );
+            let s_keys = crypto12::derive_s_keys(&ctx.app_key, &ctx.nwk_key, join_nonce, join_eui, dev_nonce);
+            let session_key_id = jsctx::set_app_s_key(dev_eui, join_nonce, s_keys.app_s_key)?;
+            Ok(JoinAnsParams::v12x(ja, s_keys, session_key_id))
+        },
+
+    }
+
+}
+
+/// Processes a Rejoin-Request on behalf of an (internal) Join Server
+///
+/// The MIC of type 0 and 2 Rejoin-Requests is computed with `SNwkSIntKey`, so it SHALL be
+/// validated by the Network Server before calling this function. The MIC of type 1
+/// Rejoin-Requests is validated here with `JSIntKey`.
+///
+/// The Join-Accept is encrypted with `JSEncKey`.
+///
+/// # Specification
+///
+/// LoRaWAN 1.2.0 Draft 47 - line #2606 \
+/// 6.2.4 Rejoin-Request frame          \
+/// 6.2.3 Join-Accept frame             \
+///
+pub fn handle_rejoin_req(req: &JoinReqParams) -> AnyResult<JoinAnsParams> {
+
+    let phy_payload = req.phy_payload;
+    if phy_payload.len() < 2 {
+        return Err(anyhow!("invalid Rejoin-Request length: {}", phy_payload.len()));
+    }
+    let rj_type = RJType::from_value(phy_payload[1])?;
+
+    let (dev_eui, rj_count, join_eui) = match rj_type {
+        RJType::Type0 | RJType::Type2 => {
+            if phy_payload.len() != 19 {
+                return Err(anyhow!("invalid Rejoin-Request length: {}", phy_payload.len()));
+            }
+            let dev_eui = u64::from_le_bytes(phy_payload[5..13].try_into().unwrap());
+            let rj_count = u16::from_le_bytes(phy_payload[13..15].try_into().unwrap());
+            (dev_eui, rj_count, None)
+        },
+        RJType::Type1 => {
+            if phy_payload.len() != 24 {
+                return Err(anyhow!("invalid Rejoin-Request length: {}", phy_payload.len()));
+            }
+            let join_eui = u64::from_le_bytes(phy_payload[2..10].try_into().unwrap());
+            let dev_eui = u64::from_le_bytes(phy_payload[10..18].try_into().unwrap());
+            let rj_count = u16::from_le_bytes(phy_payload[18..20].try_into().unwrap());
+            (dev_eui, rj_count, Some(join_eui))
+        },
+    };
+
+    let ctx = jsctx::get_js_device_context(dev_eui)
+        .ok_or_else(|| anyhow!("unknown DevEUI: 0x{:016x}", dev_eui))?;
+
+    // Type 0 and 2 Rejoin-Requests refer to the JoinEUI of the last Join-Request
+    let join_eui = join_eui.unwrap_or(ctx.join_eui);
+
+    if let RJType::Type1 = rj_type {
+        let mic: [u8; 4] = phy_payload[20..24].try_into().unwrap();
+        if crypto12::join_request_calculate_mic(&ctx.js_int_key, phy_payload) != mic {
+            return Err(anyhow!("invalid Rejoin-Request MIC, DevEUI: 0x{:016x}", dev_eui));
+        }
+    }
+
+    let join_nonce = jsctx::commit_rejoin(dev_eui, rj_type, rj_count)?;
+
+    let join_req_type = match rj_type {
+        RJType::Type0 => crypto12::JoinReqType::RejoinReqType0,
+        RJType::Type1 => crypto12::JoinReqType::RejoinReqType1,
+        RJType::Type2 => crypto12::JoinReqType::RejoinReqType2,
+    };
+    let ja = build_join_accept_v12x(
+        &ctx, req, join_req_type, join_eui, rj_count, join_nonce, &ctx.js_enc_key,
+    );
+    let s_keys = crypto12::derive_s_keys(&ctx.app_key, &ctx.nwk_key, join_nonce, join_eui, rj_count);
+    let session_key_id = jsctx::set_app_s_key(dev_eui, join_nonce, s_keys.app_s_key)?;
+
+    Ok(JoinAnsParams::v12x(ja, s_keys, session_key_id))
+
+}
+
+/// Builds, signs and encrypts a LoRaWAN 1.1+ Join-Accept
+///
+/// # Arguments
+///
+/// * __`dev_j_count`__ - DevNonce, RJcount0 or RJcount1 depending on `join_req_type`
+/// * __`enc_key`__ - `NwkKey` for Join-Requests, `JSEncKey` for Rejoin-Requests
+///
+fn build_join_accept_v12x(
+    ctx: &jsctx::JSDeviceContext,
+    req: &JoinReqParams,
+    join_req_type: crypto12::JoinReqType,
+    join_eui: u64,
+    dev_j_count: u16,
+    join_nonce: u32,
+    enc_key: &[u8; 16],
+) -> Vec<u8> {
+    let dl_settings = req.dl_settings | 0b10000000; // OptNeg
+    let mut ja = build_join_accept(join_nonce, req.net_id, req.dev_addr, dl_settings, req.rx_delay, req.cf_list);
+    let ja_len = ja.len();
+    let mic = crypto12::join_accept_calculate_mic(&ctx.js_int_key, join_req_type, join_eui, dev_j_count, &ja);
+    ja[ja_len - 4..].copy_from_slice(&mic);
+    crypto12::join_accept_encrypt(enc_key, &mut ja);
+    ja
+}
+
+/// Builds a clear text Join-Accept frame with a zero MIC
+///
+/// `MHDR|JoinNonce|NetID|DevAddr|DLSettings|RxDelay|CFList|MIC`
+///
+pub fn build_join_accept(
+    join_nonce: u32,
+    net_id: u32,
+    dev_addr: u32,
+    dl_settings: u8,
+    rx_delay: u8,
+    cf_list: Option<[u8; 16]>,
+) -> Vec<u8> {
+    let mut ja: Vec<u8> = Vec::with_capacity(33);
+    ja.push((MType::JoinAccept as u8) << 5);
+    ja.extend_from_slice(&join_nonce.to_le_bytes()[..3]);
+    ja.extend_from_slice(&net_id.to_le_bytes()[..3]);
+    ja.extend_from_slice(&dev_addr.to_le_bytes());
+    ja.push(dl_settings);
+    ja.push(rx_delay);
+    if let Some(cf_list) = cf_list {
+        ja.extend_from_slice(&cf_list);
+    }
+    ja.extend_from_slice(&[0; 4]);
+    ja
+}
+
+
+#[cfg(test)]
+mod tests {
+
+    use super::*;
+    use crate::lorawan::crypto::utils::key_from_string;
+
+    #[test]
+    fn test_join_accept_v12x_round_trip() {
+
+        let nwk_key = key_from_string("ffeeddccbbaa99887766554433221100").unwrap();
+        let dev_eui: u64 = 0x0000000000000002;
+        let join_eui: u64 = 0xaabbccddaabbccdd;
+        let dev_nonce: u16 = 0x0007;
+        let js_keys = crypto12::derive_js_keys(&nwk_key, dev_eui);
+
+        let mut ja = build_join_accept(0x000001, 0xb00001, 0x00000002, 0b10000000, 1, None);
+        let mic = crypto12::join_accept_calculate_mic(
+            &js_keys.js_int_key, crypto12::JoinReqType::JoinReq, join_eui, dev_nonce, &ja,
+        );
+        ja[13..17].copy_from_slice(&mic);
+        let clear_text = ja.clone();
+
+        crypto12::join_accept_encrypt(&nwk_key, &mut ja);
+        assert_ne!(clear_text, ja);
+
+        crypto12::join_accept_decrypt(&nwk_key, &mut ja);
+        assert_eq!(clear_text, ja);
+        assert_eq!(
+            mic,
+            crypto12::join_accept_calculate_mic(
+                &js_keys.js_int_key, crypto12::JoinReqType::JoinReq, join_eui, dev_nonce, &ja,
+            )
+        );
+
+    }
+
+}
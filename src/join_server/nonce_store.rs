@@ -0,0 +1,116 @@
+use std::{
+    fs,
+    io::Write,
+    path::Path,
+    collections::{HashMap, VecDeque},
+};
+use serde::{Deserialize, Serialize};
+use anyhow::{ Result as AnyResult, anyhow };
+
+/// The number of DevNonces remembered for LoRaWAN 1.0.x devices (random DevNonce)
+pub const DEV_NONCE_HISTORY_LEN: usize = 256;
+
+/// The scope of the JoinNonce counter
+#[derive(Debug, Deserialize, Clone, Copy, PartialEq)]
+pub enum JoinNonceScope {
+    Device,  // a counter per DevEUI
+    JoinEUI, // a counter per JoinEUI, shared by all devices using it
+}
+
+/// The replay protection state of a device
+#[derive(Debug, Serialize, Deserialize, Clone, Default, PartialEq)]
+pub struct DeviceNonces {
+    pub join_nonce: u32,                    // the last JoinNonce sent to the device
+    pub dev_nonce_high_water: Option<u16>,  // LoRaWAN 1.1+: the last accepted DevNonce
+    #[serde(default)]
+    pub dev_nonce_history: VecDeque<u16>,   // LoRaWAN 1.0.x: the last accepted DevNonces
+    #[serde(default)]
+    pub rj_count_1: u16,                    // the lowest acceptable RJcount1
+    #[serde(default)]
+    pub join_eui: Option<String>,           // the JoinEUI (hex) of the last accepted Join-Request
+    #[serde(default)]
+    pub session_key_id: Option<String>,     // the SessionKeyID of the last session
+    #[serde(default)]
+    pub app_s_key: Option<String>,          // the AppSKey of the last session, wrapped with the master KEK if any
+}
+
+/// The persistent state of the Join Server
+#[derive(Debug, Serialize, Deserialize, Clone, Default, PartialEq)]
+pub struct NonceState {
+    #[serde(default)]
+    pub devices: HashMap<String, DeviceNonces>,  // indexed by DevEUI (hex)
+    #[serde(default)]
+    pub join_euis: HashMap<String, u32>,         // the last JoinNonce per JoinEUI (hex)
+}
+
+/// Loads the state, an empty state is returned if the file does not exist yet
+pub fn load(path: &Path) -> AnyResult<NonceState> {
+    match fs::read_to_string(path) {
+        Ok(text) => serde_json::from_str(&text)
+            .map_err(|e| anyhow!("{}: {}", path.display(), e)),
+        Err(e) if e.kind() == std::io::ErrorKind::NotFound => Ok(NonceState::default()),
+        Err(e) => Err(anyhow!("{}: {}", path.display(), e)),
+    }
+}
+
+/// Durably replaces the state file
+///
+/// The state is written to a temporary file, flushed to the disk and renamed over the
+/// previous state, so a crash leaves either the old or the new state behind.
+///
+pub fn save(path: &Path, state: &NonceState) -> AnyResult<()> {
+
+    let dir = path.parent().filter(|dir| !dir.as_os_str().is_empty()).unwrap_or(Path::new("."));
+    fs::create_dir_all(dir)?;
+
+    let tmp_path = path.with_extension("tmp");
+    let mut file = fs::File::create(&tmp_path)?;
+    file.write_all(serde_json::to_string_pretty(state)?.as_bytes())?;
+    file.sync_all()?;
+    drop(file);
+
+    fs::rename(&tmp_path, path)?;
+
+    // The rename is durable only after the directory is flushed
+    fs::File::open(dir)?.sync_all()?;
+
+    Ok(())
+
+}
+
+
+#[cfg(test)]
+mod tests {
+
+    use super::*;
+
+    #[test]
+    fn test_save_and_load() {
+
+        let path = std::env::temp_dir()
+            .join(format!("lws_nonce_store_{}", std::process::id()))
+            .join("js_state.json");
+
+        assert_eq!(load(&path).unwrap(), NonceState::default());
+
+        let mut state = NonceState::default();
+        state.devices.insert("0000000000000002".to_string(), DeviceNonces {
+            join_nonce: 3,
+            dev_nonce_high_water: Some(7),
+            dev_nonce_history: VecDeque::new(),
+            rj_count_1: 1,
+            join_eui: Some("AA00000000000001".to_string()),
+            session_key_id: Some("0000000000000002000003".to_string()),
+            app_s_key: Some("00112233445566778899aabbccddeeff".to_string()),
+        });
+        state.join_euis.insert("AA00000000000001".to_string(), 3);
+
+        save(&path, &state).unwrap();
+        assert_eq!(load(&path).unwrap(), state);
+        assert!(!path.with_extension("tmp").exists());
+
+        fs::remove_dir_all(path.parent().unwrap()).unwrap();
+
+    }
+
+}
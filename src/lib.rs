@@ -8,10 +8,40 @@ pub mod pktf;
 
 pub mod handle_rx_packet;
 
+pub mod handle_join_request;
+
+pub mod handle_rejoin_request;
+
+pub mod handle_data_frame;
+
 pub mod devctx;
 
+pub mod mac_state;
+
+pub mod adr;
+
+pub mod app_server;
+
+pub mod app_queue;
+
+pub mod app_server_api;
+
 pub mod dd_cache;
 
+pub mod downlink;
+
+pub mod duty_cycle;
+
+pub mod scheduler;
+
+pub mod lorawan_config;
+
+pub mod rf_region_loader;
+
+pub mod join_server;
+
+pub mod backend;
+
 pub mod lorawan;
 
 #[cfg(test)]
@@ -0,0 +1,79 @@
+// ********************************
+// * CFList
+// ********************************
+
+// CFList{16}
+//     CFListContent{15}
+//     CFListType{1}
+
+#[repr(u8)]
+#[derive(Debug, Copy, Clone, PartialEq)]
+pub enum CFListType {
+    DynamicChannelList = 0,
+    FixedChannelMask = 1,
+}
+
+/// Builds a CFList of the dynamic channel plan regions (e.g. EU868)
+///
+/// # Arguments
+///
+/// * __`freqs`__ - the frequencies of the channels 3..7 in Hz, up to 5 channels (0 disables a channel)
+///
+/// # Specification
+///
+/// RP002-1.0.4 - 2.4.4 EU863-870 JoinAccept CFList
+///
+pub fn dynamic_channel_list(freqs: &[u32]) -> [u8; 16] {
+    let mut cf_list = [0_u8; 16];
+    for (i, freq) in freqs.iter().take(5).enumerate() {
+        // Freq is a 24-bit value in 100 Hz units
+        cf_list[i * 3..i * 3 + 3].copy_from_slice(&(freq / 100).to_le_bytes()[..3]);
+    }
+    cf_list[15] = CFListType::DynamicChannelList as u8;
+    cf_list
+}
+
+/// Builds a CFList of the fixed channel plan regions (e.g. US915, AU915)
+///
+/// # Arguments
+///
+/// * __`enabled_channels`__ - the indexes of the enabled uplink channels (0..71)
+///
+/// # Specification
+///
+/// RP002-1.0.4 - 2.5.4 US902-928 JoinAccept CFList
+///
+pub fn fixed_channel_mask(enabled_channels: &[u8]) -> [u8; 16] {
+    let mut cf_list = [0_u8; 16];
+    // ChMask0..ChMask4 (16 bits each, little endian) are mapped to the channels 0..71,
+    // the bits of the channels 72..79 are RFU
+    for ch in enabled_channels.iter().filter(|ch| **ch < 72) {
+        cf_list[(*ch / 8) as usize] |= 1 << (ch % 8);
+    }
+    cf_list[15] = CFListType::FixedChannelMask as u8;
+    cf_list
+}
+
+
+#[cfg(test)]
+mod tests {
+
+    use super::*;
+
+    #[test]
+    fn test_cf_list() {
+        assert_eq!(
+            hex::encode(dynamic_channel_list(&[867_100_000, 867_300_000, 867_500_000, 867_700_000, 867_900_000])),
+            "184f84e85684b85e84886684586e8400",
+        );
+        // US915 sub-band 2 (channels 8..15) and the 500 kHz channel 65
+        let mut enabled_channels: Vec<u8> = (8..16).collect();
+        enabled_channels.push(65);
+        enabled_channels.push(75); // RFU
+        assert_eq!(
+            hex::encode(fixed_channel_mask(&enabled_channels)),
+            "00ff0000000000000200000000000001",
+        );
+    }
+
+}
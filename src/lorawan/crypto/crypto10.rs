@@ -78,6 +78,8 @@ pub fn frm_payload_crypt<'a>(
 /// PHY Payload
 /// * __`nwk_s_key`__\
 ///   Network Session Key (`NwkSKey`)
+/// * __`dir`__\
+///   Direction of the frame (`Uplink` | `Downlink`)
 /// * __`dev_addr`__\
 ///   Device Address (`DevAddr`)
 /// * __`f_cnt32`__\
@@ -91,6 +93,7 @@ pub fn frm_payload_crypt<'a>(
 pub fn data_frame_calculate_mic<'a>(
 	phy_payload: &'a [u8], 
 	nwk_s_key: &'a [u8; 16], 
+	dir: Dir,
 	dev_addr: u32,
 	f_cnt32: u32,
 ) -> [u8; 4] {
@@ -101,7 +104,7 @@ pub fn data_frame_calculate_mic<'a>(
 	// 0x49|0x00|0x00|0x00|0x00|Dir|dev_addr|f_cnt|0x00|len
 	let mut block: [u8; 16] = [0; 16];
 	block[0] = 0x49;
-	block[5] = Dir::Downlink as u8;
+	block[5] = dir as u8;
 	block[6..10].copy_from_slice(&dev_addr.to_le_bytes());
 	block[10..14].copy_from_slice(&f_cnt32.to_le_bytes());
 	block[15] = (len & 0xff) as u8;
@@ -167,10 +170,7 @@ pub fn join_accept_encrypt<'a>(
 	app_key: &[u8; 16],
 	clear_text_ja_payload: &'a mut [u8],
 ) {
-	aes128_decrypt_in_place(app_key, &mut clear_text_ja_payload[1..17]);
-	if clear_text_ja_payload.len() == 33 {
-		aes128_decrypt_in_place(app_key, &mut clear_text_ja_payload[17..33]);
-	}
+	aes128_decrypt_in_place(app_key, clear_text_ja_payload);
 }
 
 
@@ -192,10 +192,7 @@ pub fn join_accept_decrypt<'a>(
 	app_key: &[u8; 16],
 	ja_payload: &'a mut [u8], 
 ) {
-	aes128_encrypt_in_place(app_key, &mut ja_payload[1..17]);
-	if ja_payload.len() == 33 {
-		aes128_encrypt_in_place(app_key, &mut ja_payload[17..33]);
-	}
+	aes128_encrypt_in_place(app_key, ja_payload);
 }
 
 
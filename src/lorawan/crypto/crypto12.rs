@@ -511,10 +511,7 @@ pub fn join_accept_encrypt<'a>(
 	join_accept_enc_key: &[u8; 16],
 	clear_text_ja_payload: &'a mut [u8],
 ) {
-	aes128_decrypt_in_place(join_accept_enc_key, &mut clear_text_ja_payload[1..17]);
-	if clear_text_ja_payload.len() == 33 {
-		aes128_decrypt_in_place(join_accept_enc_key, &mut clear_text_ja_payload[17..33]);
-	}
+	aes128_decrypt_in_place(join_accept_enc_key, clear_text_ja_payload);
 }
 
 
@@ -536,10 +533,7 @@ pub fn join_accept_decrypt<'a>(
 	join_accept_enc_key: &[u8; 16],
 	ja_payload: &'a mut [u8], 
 ) {
-	aes128_encrypt_in_place(join_accept_enc_key, &mut ja_payload[1..17]);
-	if ja_payload.len() == 33 {
-		aes128_encrypt_in_place(join_accept_enc_key, &mut ja_payload[17..33]);
-	}
+	aes128_encrypt_in_place(join_accept_enc_key, ja_payload);
 }
 
 
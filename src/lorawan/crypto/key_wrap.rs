@@ -0,0 +1,139 @@
+use anyhow::{ Result as AnyResult, anyhow };
+
+use super::utils::{aes128_encrypt, aes128_decrypt, key_from_string};
+
+// ***************************************************
+// *** AES Key Wrap
+// ***************************************************
+
+/// The default initial value of the AES Key Wrap algorithm
+const IV: [u8; 8] = [0xa6; 8];
+
+/// Wraps a 128-bit key with a 128-bit KEK
+///
+/// # Arguments
+///
+/// * __`kek`__ - Key Encryption Key
+/// * __`key`__ - the key to be protected
+///
+/// Returns `A|R1|R2` (24 bytes).
+///
+/// # Specification
+///
+/// RFC 3394 - 2.2.1 Key Wrap              \
+/// LoRaWAN Backend Interfaces 1.0 - 6.3   \
+///
+pub fn wrap_key(kek: &[u8; 16], key: &[u8; 16]) -> [u8; 24] {
+
+    let mut a: [u8; 8] = IV;
+    let mut r: [[u8; 8]; 2] = [
+        key[0..8].try_into().unwrap(),
+        key[8..16].try_into().unwrap(),
+    ];
+
+    for j in 0..6_u64 {
+        for (i, r_i) in r.iter_mut().enumerate() {
+            let t = j * 2 + i as u64 + 1;
+            let mut block = [0_u8; 16];
+            block[0..8].copy_from_slice(&a);
+            block[8..16].copy_from_slice(r_i);
+            let b = aes128_encrypt(kek, &block);
+            a = (u64::from_be_bytes(b[0..8].try_into().unwrap()) ^ t).to_be_bytes();
+            r_i.copy_from_slice(&b[8..16]);
+        }
+    }
+
+    let mut wrapped_key = [0_u8; 24];
+    wrapped_key[0..8].copy_from_slice(&a);
+    wrapped_key[8..16].copy_from_slice(&r[0]);
+    wrapped_key[16..24].copy_from_slice(&r[1]);
+    wrapped_key
+
+}
+
+/// Unwraps a 128-bit key that was wrapped with a 128-bit KEK
+///
+/// # Arguments
+///
+/// * __`kek`__ - Key Encryption Key
+/// * __`wrapped_key`__ - `A|R1|R2` (24 bytes)
+///
+/// # Specification
+///
+/// RFC 3394 - 2.2.2 Key Unwrap            \
+/// LoRaWAN Backend Interfaces 1.0 - 6.3   \
+///
+pub fn unwrap_key(kek: &[u8; 16], wrapped_key: &[u8]) -> AnyResult<[u8; 16]> {
+
+    if wrapped_key.len() != 24 {
+        return Err(anyhow!("invalid wrapped key length: {}", wrapped_key.len()));
+    }
+
+    let mut a: [u8; 8] = wrapped_key[0..8].try_into().unwrap();
+    let mut r: [[u8; 8]; 2] = [
+        wrapped_key[8..16].try_into().unwrap(),
+        wrapped_key[16..24].try_into().unwrap(),
+    ];
+
+    for j in (0..6_u64).rev() {
+        for i in (0..2_usize).rev() {
+            let t = j * 2 + i as u64 + 1;
+            let mut block = [0_u8; 16];
+            block[0..8].copy_from_slice(&(u64::from_be_bytes(a) ^ t).to_be_bytes());
+            block[8..16].copy_from_slice(&r[i]);
+            let b = aes128_decrypt(kek, &block);
+            a.copy_from_slice(&b[0..8]);
+            r[i].copy_from_slice(&b[8..16]);
+        }
+    }
+
+    if a != IV {
+        return Err(anyhow!("key unwrap integrity check failed"));
+    }
+
+    let mut key = [0_u8; 16];
+    key[0..8].copy_from_slice(&r[0]);
+    key[8..16].copy_from_slice(&r[1]);
+    Ok(key)
+
+}
+
+/// Parses a key stored as hex: 32 characters for a plain key, 48 characters for a key wrapped with `kek`
+pub fn key_from_string_with_kek(str: &str, kek: Option<&[u8; 16]>) -> AnyResult<[u8; 16]> {
+    match str.len() {
+        48 => {
+            let kek = kek.ok_or_else(|| anyhow!("a wrapped key is found, but no KEK is configured"))?;
+            unwrap_key(kek, &hex::decode(str)?)
+        },
+        _ => key_from_string(str),
+    }
+}
+
+
+#[cfg(test)]
+mod tests {
+
+    use super::*;
+
+    // RFC 3394 - 4.1 Wrap 128 bits of Key Data with a 128-bit KEK
+    #[test]
+    fn test_wrap_unwrap_key() {
+        let kek: [u8; 16] = hex::decode("000102030405060708090a0b0c0d0e0f").unwrap().try_into().unwrap();
+        let wrapped_key = hex::decode("1fa68b0a8112b447aef34bd8fb5a7b829d3e862371d2cfe5").unwrap();
+        assert_eq!(
+            hex::encode(unwrap_key(&kek, &wrapped_key).unwrap()),
+            "00112233445566778899aabbccddeeff",
+        );
+        let key: [u8; 16] = hex::decode("00112233445566778899aabbccddeeff").unwrap().try_into().unwrap();
+        assert_eq!(wrap_key(&kek, &key).to_vec(), wrapped_key);
+        let mut corrupted = wrapped_key.clone();
+        corrupted[23] ^= 0x01;
+        assert!(unwrap_key(&kek, &corrupted).is_err());
+
+        let stored = "1fa68b0a8112b447aef34bd8fb5a7b829d3e862371d2cfe5";
+        assert_eq!(key_from_string_with_kek(stored, Some(&kek)).unwrap(), key);
+        assert!(key_from_string_with_kek(stored, None).is_err());
+        assert_eq!(key_from_string_with_kek("00112233445566778899aabbccddeeff", None).unwrap(), key);
+    }
+
+}
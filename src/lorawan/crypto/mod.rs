@@ -5,3 +5,6 @@ pub mod crypto10;
 
 /// LoRaWAN 1.2 Crypto Functions
 pub mod crypto12;
+
+/// RFC 3394 AES Key Wrap (KEK protected key transport of the Backend Interfaces)
+pub mod key_wrap;
@@ -32,7 +32,7 @@ pub fn aes128_encrypt<'a>(key: &'a [u8; 16], block: &'a [u8; 16]) -> [u8; 16] {
 pub fn aes128_decrypt<'a>(key: &'a [u8; 16], block: &'a [u8; 16]) -> [u8; 16] {
 	let cipher = Aes128::new(GenericArray::from_slice(key));
     let mut decrypted_block: Block = GenericArray::clone_from_slice(block);
-	cipher.encrypt_block(&mut decrypted_block);
+	cipher.decrypt_block(&mut decrypted_block);
 	decrypted_block.into()
 }
 
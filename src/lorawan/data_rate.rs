@@ -0,0 +1,63 @@
+use std::{fmt, time::Duration};
+use anyhow::{ Result as AnyResult, anyhow };
+
+use super::time_on_air;
+
+/// The modulation parameters of a data rate
+///
+/// # Specification
+///
+/// RP002-1.0.3 - 2.1.3 Data Rate and End-Device Output Power Encoding \
+///
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum DataRate {
+    LoRa { sp_fact: u8, bandwidth: u32 },          // bandwidth: kHz
+    Fsk { bit_rate: u32 },                         // bit/s
+    LrFhss { coding_rate: u8, bandwidth: u32 },    // coding_rate: 1 = CR1/3, 2 = CR2/3, bandwidth: OCW kHz
+}
+impl DataRate {
+
+    /// Parses the `datr` of the packet forwarder: "SF7BW125" for LoRa, the bit rate for FSK
+    pub fn from_datr(datr: &str) -> AnyResult<Self> {
+        if let Some((sp_fact, bandwidth)) = datr.strip_prefix("SF").and_then(|s| s.split_once("BW")) {
+            return Ok(DataRate::LoRa {
+                sp_fact: sp_fact.parse().map_err(|_| anyhow!("invalid datr: {}", datr))?,
+                bandwidth: bandwidth.parse().map_err(|_| anyhow!("invalid datr: {}", datr))?,
+            });
+        }
+        datr.parse::<u32>()
+            .map(|bit_rate| DataRate::Fsk { bit_rate })
+            .map_err(|_| anyhow!("invalid datr: {}", datr))
+    }
+
+    /// The time-on-air of a LoRaWAN frame at this data rate
+    ///
+    /// # Arguments
+    ///
+    /// * __`payload_len`__\
+    ///   The length of the PHYPayload in bytes \
+    /// * __`coding_rate`__\
+    ///   LoRa coding rate index CR (1 for 4/5 .. 4 for 4/8), LoRaWAN uses 4/5 \
+    /// * __`crc`__\
+    ///   Whether the payload CRC is present (uplinks), FSK and LR-FHSS frames always have one \
+    ///
+    pub fn time_on_air(&self, payload_len: usize, coding_rate: u8, crc: bool) -> Duration {
+        match *self {
+            DataRate::LoRa { sp_fact, bandwidth } => {
+                time_on_air::lora_time_on_air(sp_fact, bandwidth, coding_rate, crc, payload_len)
+            },
+            DataRate::Fsk { bit_rate } => time_on_air::fsk_time_on_air(bit_rate, payload_len),
+            DataRate::LrFhss { coding_rate, .. } => time_on_air::lr_fhss_time_on_air(coding_rate, payload_len),
+        }
+    }
+
+}
+impl fmt::Display for DataRate {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            DataRate::LoRa { sp_fact, bandwidth } => write!(f, "SF{}BW{}", sp_fact, bandwidth),
+            DataRate::Fsk { bit_rate } => write!(f, "{}", bit_rate),
+            DataRate::LrFhss { coding_rate, bandwidth } => write!(f, "CR{}/3OCW{}", coding_rate, bandwidth),
+        }
+    }
+}
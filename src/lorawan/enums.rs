@@ -115,4 +115,22 @@ impl RJType {
             _ => Err(anyhow!("invalid Dir value: {}", value)),
         }
     }
-}
\ No newline at end of file
+}
+//********************************
+//* MACVersion
+//********************************
+
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum MACVersion {
+    V10x, // LoRaWAN 1.0.x
+    V12x, // LoRaWAN 1.1 and 1.2 (NwkKey/AppKey separation, OptNeg)
+}
+impl MACVersion {
+    pub fn from_version_str(value: &str) -> AnyResult<Self> {
+        match value.split('.').take(2).collect::<Vec<&str>>()[..] {
+            ["1", "0"] => Ok(MACVersion::V10x),
+            ["1", "1"] | ["1", "2"] => Ok(MACVersion::V12x),
+            _ => Err(anyhow!("invalid MACVersion value: {}", value)),
+        }
+    }
+}
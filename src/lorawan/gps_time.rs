@@ -0,0 +1,112 @@
+use std::time::{ Duration, SystemTime, UNIX_EPOCH };
+
+use super::mac_cmds::DownlinkMACCmd;
+
+/// The GPS epoch (1980-01-06T00:00:00Z) in Unix seconds
+pub const GPS_EPOCH_UNIX: u64 = 315_964_800;
+
+/// The UTC instants (Unix seconds) following the leap seconds inserted since the GPS epoch
+///
+/// GPS time does not have leap seconds, so it runs ahead of UTC by the number of leap
+/// seconds inserted before the instant. The table must be extended when the IERS announces
+/// a new leap second.
+///
+const LEAP_SECONDS: [u64; 18] = [
+    362_793_600,   // 1981-07-01
+    394_329_600,   // 1982-07-01
+    425_865_600,   // 1983-07-01
+    489_024_000,   // 1985-07-01
+    567_993_600,   // 1988-01-01
+    631_152_000,   // 1990-01-01
+    662_688_000,   // 1991-01-01
+    709_948_800,   // 1992-07-01
+    741_484_800,   // 1993-07-01
+    773_020_800,   // 1994-07-01
+    820_454_400,   // 1996-01-01
+    867_715_200,   // 1997-07-01
+    915_148_800,   // 1999-01-01
+    1_136_073_600, // 2006-01-01
+    1_230_768_000, // 2009-01-01
+    1_341_100_800, // 2012-07-01
+    1_435_708_800, // 2015-07-01
+    1_483_228_800, // 2017-01-01
+];
+
+/// The number of leap seconds between the GPS epoch and a UTC instant (Unix seconds)
+pub fn leap_seconds(unix_secs: u64) -> u64 {
+    LEAP_SECONDS.iter().filter(|leap| **leap <= unix_secs).count() as u64
+}
+
+/// Converts a UTC instant to GPS time (time elapsed since the GPS epoch)
+///
+/// Returns `None` for instants before the GPS epoch.
+///
+pub fn utc_to_gps(utc: SystemTime) -> Option<Duration> {
+    let unix = utc.duration_since(UNIX_EPOCH).ok()?;
+    let since_epoch = unix.checked_sub(Duration::from_secs(GPS_EPOCH_UNIX))?;
+    Some(since_epoch + Duration::from_secs(leap_seconds(unix.as_secs())))
+}
+
+/// Converts GPS time (time elapsed since the GPS epoch) to a UTC instant
+pub fn gps_to_utc(gps: Duration) -> SystemTime {
+    // The GPS time of the n-th (0-based) leap second itself is leap - GPS_EPOCH_UNIX + n
+    let leaps = LEAP_SECONDS
+        .iter()
+        .enumerate()
+        .filter(|(i, leap)| gps.as_secs() > **leap - GPS_EPOCH_UNIX + *i as u64)
+        .count() as u64;
+    UNIX_EPOCH + Duration::from_secs(GPS_EPOCH_UNIX) + gps - Duration::from_secs(leaps)
+}
+
+/// Builds the DeviceTimeAns for a GPS time
+///
+/// The seconds since the GPS epoch are sent modulo 2^32, the fractional second in
+/// 1/256 s steps.
+///
+/// # Arguments
+///
+/// * __`gps`__\
+///   GPS time of the end of the uplink transmission carrying DeviceTimeReq \
+///
+/// # Specification
+///
+/// LoRaWAN L2 1.0.4 - line #1435                         \
+/// 5.12 DeviceTime commands (DeviceTimeReq, DeviceTimeAns) \
+///
+pub fn device_time_ans(gps: Duration) -> DownlinkMACCmd {
+    DownlinkMACCmd::DeviceTimeAns {
+        seconds: gps.as_secs() as u32,
+        fractional_second: (gps.subsec_nanos() as u64 * 256 / 1_000_000_000) as u8,
+    }
+}
+
+
+#[cfg(test)]
+mod tests {
+
+    use super::*;
+
+    #[test]
+    fn test_gps_time() {
+
+        // 2017-01-01T00:00:00Z is 1167264018 s GPS time
+        let utc = UNIX_EPOCH + Duration::from_millis(1_483_228_800_500);
+        let gps = utc_to_gps(utc).unwrap();
+        assert_eq!(gps, Duration::from_millis(1_167_264_018_500));
+        assert_eq!(gps_to_utc(gps), utc);
+
+        // the second before, GPS was ahead by 17 s
+        let utc = UNIX_EPOCH + Duration::from_secs(1_483_228_799);
+        assert_eq!(utc_to_gps(utc).unwrap(), Duration::from_secs(1_167_264_016));
+        assert_eq!(gps_to_utc(Duration::from_secs(1_167_264_016)), utc);
+
+        assert!(utc_to_gps(UNIX_EPOCH).is_none());
+
+        assert_eq!(
+            device_time_ans(Duration::from_millis(1_167_264_018_500)),
+            DownlinkMACCmd::DeviceTimeAns { seconds: 1_167_264_018, fractional_second: 128 },
+        );
+
+    }
+
+}
@@ -0,0 +1,58 @@
+use super::mac_cmds::DownlinkMACCmd;
+
+/// The lowest SNR that can be demodulated at a spreading factor (dB)
+///
+/// The limits are the `SNR_limit_SF7..12` values of the RF region profiles.
+///
+pub fn snr_limit(sp_fact: u8) -> Option<f32> {
+    match sp_fact {
+        7  => Some(-7.0),
+        8  => Some(-9.0),
+        9  => Some(-11.5),
+        10 => Some(-14.0),
+        11 => Some(-16.5),
+        12 => Some(-19.0),
+        _  => None,
+    }
+}
+
+/// Builds the LinkCheckAns of an uplink
+///
+/// The margin is the SNR of the best gateway above the demodulation floor in dB (0..254),
+/// GwCnt is the number of gateways that received the uplink.
+///
+/// # Arguments
+///
+/// * __`sp_fact`__\
+///   Spreading factor of the uplink \
+/// * __`best_snr`__\
+///   The best SNR of the gateways that received the uplink \
+/// * __`gw_cnt`__\
+///   The number of distinct gateways that received the uplink \
+///
+/// # Specification
+///
+/// LoRaWAN L2 1.0.4 - line #1101                      \
+/// 5.2 Link Check commands (LinkCheckReq, LinkCheckAns) \
+///
+pub fn link_check_ans(sp_fact: u8, best_snr: f32, gw_cnt: usize) -> DownlinkMACCmd {
+    let floor = snr_limit(sp_fact).unwrap_or(-20.0);
+    DownlinkMACCmd::LinkCheckAns {
+        margin: (best_snr - floor).floor().clamp(0.0, 254.0) as u8, // 255 is reserved
+        gw_cnt: gw_cnt.min(255) as u8,
+    }
+}
+
+
+#[cfg(test)]
+mod tests {
+
+    use super::*;
+
+    #[test]
+    fn test_link_check_ans() {
+        assert_eq!(link_check_ans(7, 5.5, 2), DownlinkMACCmd::LinkCheckAns { margin: 12, gw_cnt: 2 });
+        assert_eq!(link_check_ans(12, -20.0, 1), DownlinkMACCmd::LinkCheckAns { margin: 0, gw_cnt: 1 });
+    }
+
+}
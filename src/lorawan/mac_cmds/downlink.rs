@@ -0,0 +1,328 @@
+use super::{MACCmdDL, MACCmds, parse, freq_from_bytes, freq_to_bytes};
+
+/// The maximum length of `FOpts`
+pub const MAX_F_OPTS_LEN: usize = 15;
+
+/// A MAC command sent by the Network Server
+///
+/// Frequencies are in Hz.
+///
+/// # Specification
+///
+/// LoRaWAN L2 1.0.4 - line #1040 \
+/// 5 MAC Commands                \
+///
+#[derive(Debug, Clone, PartialEq)]
+pub enum DownlinkMACCmd {
+    ResetConf { minor: u8 },
+    LinkCheckAns { margin: u8, gw_cnt: u8 },
+    LinkADRReq { data_rate: u8, tx_power: u8, ch_mask: u16, ch_mask_cntl: u8, nb_trans: u8 },
+    DutyCycleReq { max_duty_cycle: u8 },
+    RXParamSetupReq { rx1_dr_offset: u8, rx2_data_rate: u8, freq: u32 },
+    DevStatusReq,
+    NewChannelReq { ch_index: u8, freq: u32, max_dr: u8, min_dr: u8 },
+    RXTimingSetupReq { delay: u8 },
+    TXParamSetupReq { downlink_dwell_time: bool, uplink_dwell_time: bool, max_eirp: u8 },
+    DlChannelReq { ch_index: u8, freq: u32 },
+    RekeyConf { minor: u8 },
+    ADRParamSetupReq { limit_exp: u8, delay_exp: u8 },
+    DeviceTimeAns { seconds: u32, fractional_second: u8 },  // GPS epoch, 1/256 s steps
+    ForceRejoinReq { period: u8, max_retries: u8, rejoin_type: u8, data_rate: u8 },
+    RejoinParamSetupReq { max_time_n: u8, max_count_n: u8 },
+    PingSlotInfoAns,
+    PingSlotChannelReq { freq: u32, data_rate: u8 },
+    BeaconTimingAns { delay: u16, channel: u8 },
+    BeaconFreqReq { freq: u32 },
+    DeviceModeConf { class: u8 },
+}
+impl DownlinkMACCmd {
+    pub fn cid(&self) -> MACCmdDL {
+        match self {
+            Self::ResetConf { .. }           => MACCmdDL::ResetConf,
+            Self::LinkCheckAns { .. }        => MACCmdDL::LinkCheckAns,
+            Self::LinkADRReq { .. }          => MACCmdDL::LinkADRReq,
+            Self::DutyCycleReq { .. }        => MACCmdDL::DutyCycleReq,
+            Self::RXParamSetupReq { .. }     => MACCmdDL::RXParamSetupReq,
+            Self::DevStatusReq               => MACCmdDL::DevStatusReq,
+            Self::NewChannelReq { .. }       => MACCmdDL::NewChannelReq,
+            Self::RXTimingSetupReq { .. }    => MACCmdDL::RXTimingSetupReq,
+            Self::TXParamSetupReq { .. }     => MACCmdDL::TXParamSetupReq,
+            Self::DlChannelReq { .. }        => MACCmdDL::DlChannelReq,
+            Self::RekeyConf { .. }           => MACCmdDL::RekeyConf,
+            Self::ADRParamSetupReq { .. }    => MACCmdDL::ADRParamSetupReq,
+            Self::DeviceTimeAns { .. }       => MACCmdDL::DeviceTimeAns,
+            Self::ForceRejoinReq { .. }      => MACCmdDL::ForceRejoinReq,
+            Self::RejoinParamSetupReq { .. } => MACCmdDL::RejoinParamSetupReq,
+            Self::PingSlotInfoAns            => MACCmdDL::PingSlotInfoAns,
+            Self::PingSlotChannelReq { .. }  => MACCmdDL::PingSlotChannelReq,
+            Self::BeaconTimingAns { .. }     => MACCmdDL::BeaconTimingAns,
+            Self::BeaconFreqReq { .. }       => MACCmdDL::BeaconFreqReq,
+            Self::DeviceModeConf { .. }      => MACCmdDL::DeviceModeConf,
+        }
+    }
+
+    /// Answers and confirmations to the commands of the end-device, they are sent before requests
+    pub fn is_answer(&self) -> bool {
+        matches!(self,
+            Self::ResetConf { .. } | Self::LinkCheckAns { .. } | Self::RekeyConf { .. } |
+            Self::DeviceTimeAns { .. } | Self::PingSlotInfoAns | Self::BeaconTimingAns { .. } |
+            Self::DeviceModeConf { .. }
+        )
+    }
+
+    /// Serializes the command: CID|payload
+    pub fn to_bytes(&self) -> Vec<u8> {
+        let mut bytes = vec![self.cid() as u8];
+        match *self {
+            Self::ResetConf { minor } | Self::RekeyConf { minor } => bytes.push(minor & 0x0f),
+            Self::LinkCheckAns { margin, gw_cnt } => bytes.extend_from_slice(&[margin, gw_cnt]),
+            Self::LinkADRReq { data_rate, tx_power, ch_mask, ch_mask_cntl, nb_trans } => {
+                bytes.push((data_rate & 0x0f) << 4 | (tx_power & 0x0f));
+                bytes.extend_from_slice(&ch_mask.to_le_bytes());
+                bytes.push((ch_mask_cntl & 0x07) << 4 | (nb_trans & 0x0f));
+            },
+            Self::DutyCycleReq { max_duty_cycle } => bytes.push(max_duty_cycle & 0x0f),
+            Self::RXParamSetupReq { rx1_dr_offset, rx2_data_rate, freq } => {
+                bytes.push((rx1_dr_offset & 0x07) << 4 | (rx2_data_rate & 0x0f));
+                bytes.extend_from_slice(&freq_to_bytes(freq));
+            },
+            Self::DevStatusReq | Self::PingSlotInfoAns => {},
+            Self::NewChannelReq { ch_index, freq, max_dr, min_dr } => {
+                bytes.push(ch_index);
+                bytes.extend_from_slice(&freq_to_bytes(freq));
+                bytes.push((max_dr & 0x0f) << 4 | (min_dr & 0x0f));
+            },
+            Self::RXTimingSetupReq { delay } => bytes.push(delay & 0x0f),
+            Self::TXParamSetupReq { downlink_dwell_time, uplink_dwell_time, max_eirp } => {
+                bytes.push((downlink_dwell_time as u8) << 5 | (uplink_dwell_time as u8) << 4 | (max_eirp & 0x0f));
+            },
+            Self::DlChannelReq { ch_index, freq } => {
+                bytes.push(ch_index);
+                bytes.extend_from_slice(&freq_to_bytes(freq));
+            },
+            Self::ADRParamSetupReq { limit_exp, delay_exp } => bytes.push((limit_exp & 0x0f) << 4 | (delay_exp & 0x0f)),
+            Self::DeviceTimeAns { seconds, fractional_second } => {
+                bytes.extend_from_slice(&seconds.to_le_bytes());
+                bytes.push(fractional_second);
+            },
+            Self::ForceRejoinReq { period, max_retries, rejoin_type, data_rate } => {
+                let v = (period as u16 & 0x07) << 11 | (max_retries as u16 & 0x07) << 8
+                    | (rejoin_type as u16 & 0x07) << 4 | (data_rate as u16 & 0x0f);
+                bytes.extend_from_slice(&v.to_le_bytes());
+            },
+            Self::RejoinParamSetupReq { max_time_n, max_count_n } => {
+                bytes.push((max_time_n & 0x0f) << 4 | (max_count_n & 0x0f));
+            },
+            Self::PingSlotChannelReq { freq, data_rate } => {
+                bytes.extend_from_slice(&freq_to_bytes(freq));
+                bytes.push(data_rate & 0x0f);
+            },
+            Self::BeaconTimingAns { delay, channel } => {
+                bytes.extend_from_slice(&delay.to_le_bytes());
+                bytes.push(channel);
+            },
+            Self::BeaconFreqReq { freq } => bytes.extend_from_slice(&freq_to_bytes(freq)),
+            Self::DeviceModeConf { class } => bytes.push(class),
+        }
+        bytes
+    }
+
+    /// Builds the command from its payload, `payload` has the length of `cid.payload_len()`
+    fn from_payload(cid: MACCmdDL, payload: &[u8]) -> Self {
+        let hi = |i: usize| payload[i] >> 4;
+        let lo = |i: usize| payload[i] & 0x0f;
+        match cid {
+            MACCmdDL::ResetConf => Self::ResetConf { minor: lo(0) },
+            MACCmdDL::LinkCheckAns => Self::LinkCheckAns { margin: payload[0], gw_cnt: payload[1] },
+            MACCmdDL::LinkADRReq => Self::LinkADRReq {
+                data_rate: hi(0),
+                tx_power: lo(0),
+                ch_mask: u16::from_le_bytes([payload[1], payload[2]]),
+                ch_mask_cntl: (payload[3] >> 4) & 0x07,
+                nb_trans: lo(3),
+            },
+            MACCmdDL::DutyCycleReq => Self::DutyCycleReq { max_duty_cycle: lo(0) },
+            MACCmdDL::RXParamSetupReq => Self::RXParamSetupReq {
+                rx1_dr_offset: (payload[0] >> 4) & 0x07,
+                rx2_data_rate: lo(0),
+                freq: freq_from_bytes(&payload[1..4]),
+            },
+            MACCmdDL::DevStatusReq => Self::DevStatusReq,
+            MACCmdDL::NewChannelReq => Self::NewChannelReq {
+                ch_index: payload[0],
+                freq: freq_from_bytes(&payload[1..4]),
+                max_dr: hi(4),
+                min_dr: lo(4),
+            },
+            MACCmdDL::RXTimingSetupReq => Self::RXTimingSetupReq { delay: lo(0) },
+            MACCmdDL::TXParamSetupReq => Self::TXParamSetupReq {
+                downlink_dwell_time: payload[0] & 0x20 != 0,
+                uplink_dwell_time: payload[0] & 0x10 != 0,
+                max_eirp: lo(0),
+            },
+            MACCmdDL::DlChannelReq => Self::DlChannelReq { ch_index: payload[0], freq: freq_from_bytes(&payload[1..4]) },
+            MACCmdDL::RekeyConf => Self::RekeyConf { minor: lo(0) },
+            MACCmdDL::ADRParamSetupReq => Self::ADRParamSetupReq { limit_exp: hi(0), delay_exp: lo(0) },
+            MACCmdDL::DeviceTimeAns => Self::DeviceTimeAns {
+                seconds: u32::from_le_bytes(payload[0..4].try_into().unwrap()),
+                fractional_second: payload[4],
+            },
+            MACCmdDL::ForceRejoinReq => {
+                let v = u16::from_le_bytes([payload[0], payload[1]]);
+                Self::ForceRejoinReq {
+                    period: ((v >> 11) & 0x07) as u8,
+                    max_retries: ((v >> 8) & 0x07) as u8,
+                    rejoin_type: ((v >> 4) & 0x07) as u8,
+                    data_rate: (v & 0x0f) as u8,
+                }
+            },
+            MACCmdDL::RejoinParamSetupReq => Self::RejoinParamSetupReq { max_time_n: hi(0), max_count_n: lo(0) },
+            MACCmdDL::PingSlotInfoAns => Self::PingSlotInfoAns,
+            MACCmdDL::PingSlotChannelReq => Self::PingSlotChannelReq {
+                freq: freq_from_bytes(&payload[0..3]),
+                data_rate: lo(3),
+            },
+            MACCmdDL::BeaconTimingAns => Self::BeaconTimingAns {
+                delay: u16::from_le_bytes([payload[0], payload[1]]),
+                channel: payload[2],
+            },
+            MACCmdDL::BeaconFreqReq => Self::BeaconFreqReq { freq: freq_from_bytes(&payload[0..3]) },
+            MACCmdDL::DeviceModeConf => Self::DeviceModeConf { class: payload[0] },
+            MACCmdDL::BeaconSettingsInd | MACCmdDL::DevMobilityResp | MACCmdDL::NegotiationConf => {
+                unreachable!("{:?} has no payload length", cid)
+            },
+        }
+    }
+}
+
+/// Parses the MAC commands of a downlink (`FOpts` or decrypted `FPort` = 0 `FRMPayload`)
+pub fn parse_dl(buf: &[u8]) -> MACCmds<DownlinkMACCmd> {
+    parse(buf, MACCmdDL::from_value, MACCmdDL::payload_len, DownlinkMACCmd::from_payload)
+}
+
+/// Downlink MAC commands packed into a frame
+#[derive(Debug, Default, PartialEq)]
+pub struct PackedMACCmds {
+    pub bytes: Vec<u8>,                   // the serialized commands
+    pub in_f_opts: bool,                  // `FOpts` or the `FRMPayload` of an `FPort` = 0 frame
+    pub packed: Vec<DownlinkMACCmd>,      // the commands in `bytes`
+    pub left_over: Vec<DownlinkMACCmd>,   // the commands that do not fit into this frame
+}
+
+/// Packs downlink MAC commands into `FOpts` or into an `FPort` = 0 payload
+///
+/// Answers are placed before requests. With application data the commands SHALL go into
+/// `FOpts`, otherwise they go into `FOpts` if they fit into 15 bytes, or into an `FPort` = 0
+/// payload. The commands that do not fit are left over for a later downlink.
+///
+/// # Arguments
+///
+/// * __`cmds`__\
+///   The downlink MAC commands waiting to be sent \
+/// * __`max_mac_payload`__\
+///   The maximum `MACPayload` size (M) of the region for the data rate of the downlink \
+/// * __`app_payload_len`__\
+///   The length of the application `FRMPayload` sent in the same frame, if any \
+///
+/// # Specification
+///
+/// LoRaWAN L2 1.0.4 - line #1045 \
+/// 5 MAC Commands                \
+///
+pub fn pack(cmds: &[DownlinkMACCmd], max_mac_payload: usize, app_payload_len: Option<usize>) -> PackedMACCmds {
+
+    let mut sorted: Vec<&DownlinkMACCmd> = cmds.iter().collect();
+    sorted.sort_by_key(|cmd| !cmd.is_answer()); // stable: answers first, the order is kept otherwise
+
+    let total_len: usize = cmds.iter().map(|cmd| cmd.to_bytes().len()).sum();
+
+    // MACPayload = FHDR (7 + FOpts) | FPort (1) | FRMPayload
+    let (in_f_opts, room) = match app_payload_len {
+        Some(len) => (true, MAX_F_OPTS_LEN.min(max_mac_payload.saturating_sub(8 + len))),
+        None if total_len <= MAX_F_OPTS_LEN => (true, MAX_F_OPTS_LEN.min(max_mac_payload.saturating_sub(7))),
+        None => (false, max_mac_payload.saturating_sub(8)),
+    };
+
+    let mut packed = PackedMACCmds { in_f_opts, .. PackedMACCmds::default() };
+    for cmd in sorted {
+        let bytes = cmd.to_bytes();
+        if packed.bytes.len() + bytes.len() <= room {
+            packed.bytes.extend_from_slice(&bytes);
+            packed.packed.push(cmd.clone());
+        } else {
+            packed.left_over.push(cmd.clone());
+        }
+    }
+
+    packed
+
+}
+
+
+#[cfg(test)]
+mod tests {
+
+    use super::*;
+
+    #[test]
+    fn test_parse_dl() {
+
+        // LinkCheckAns, NewChannelReq (ch 3, 867.1 MHz, DR0..DR5), DevStatusReq
+        let result = parse_dl(&[0x02, 0x14, 0x02, 0x07, 0x03, 0x18, 0x4f, 0x84, 0x50, 0x06]);
+        assert!(result.error.is_none());
+        assert_eq!(result.cmds, vec![
+            DownlinkMACCmd::LinkCheckAns { margin: 20, gw_cnt: 2 },
+            DownlinkMACCmd::NewChannelReq { ch_index: 3, freq: 867_100_000, max_dr: 5, min_dr: 0 },
+            DownlinkMACCmd::DevStatusReq,
+        ]);
+
+        // truncated LinkADRReq
+        let result = parse_dl(&[0x03, 0x50, 0xff]);
+        assert!(result.cmds.is_empty());
+        assert!(result.error.is_some());
+
+    }
+
+    #[test]
+    fn test_to_bytes() {
+
+        let cmds = [
+            DownlinkMACCmd::LinkADRReq { data_rate: 5, tx_power: 1, ch_mask: 0x0007, ch_mask_cntl: 0, nb_trans: 1 },
+            DownlinkMACCmd::RXParamSetupReq { rx1_dr_offset: 1, rx2_data_rate: 3, freq: 869_525_000 },
+            DownlinkMACCmd::ForceRejoinReq { period: 2, max_retries: 3, rejoin_type: 2, data_rate: 5 },
+            DownlinkMACCmd::DeviceTimeAns { seconds: 1_000_000_000, fractional_second: 128 },
+        ];
+        for cmd in cmds {
+            assert_eq!(parse_dl(&cmd.to_bytes()).cmds, vec![cmd]);
+        }
+
+    }
+
+    #[test]
+    fn test_pack() {
+
+        let link_adr_req = DownlinkMACCmd::LinkADRReq { data_rate: 5, tx_power: 1, ch_mask: 7, ch_mask_cntl: 0, nb_trans: 1 };
+        let new_channel_req = DownlinkMACCmd::NewChannelReq { ch_index: 3, freq: 867_100_000, max_dr: 5, min_dr: 0 };
+        let link_check_ans = DownlinkMACCmd::LinkCheckAns { margin: 20, gw_cnt: 1 };
+
+        // 5 + 6 + 3 bytes fit into FOpts, the answer goes first
+        let packed = pack(&[link_adr_req.clone(), new_channel_req.clone(), link_check_ans.clone()], 59, None);
+        assert!(packed.in_f_opts);
+        assert_eq!(packed.packed[0], link_check_ans);
+        assert_eq!(packed.bytes.len(), 14);
+
+        // 19 bytes go into an FPort 0 payload
+        let cmds = [new_channel_req.clone(), new_channel_req.clone(), new_channel_req.clone(), DownlinkMACCmd::DevStatusReq];
+        let packed = pack(&cmds, 59, None);
+        assert!(!packed.in_f_opts);
+        assert_eq!(packed.bytes.len(), 19);
+
+        // with application data only the FOpts room that is left in the frame is used
+        let packed = pack(&[new_channel_req.clone(), link_adr_req.clone()], 59, Some(46));
+        assert!(packed.in_f_opts);
+        assert_eq!(packed.packed, vec![link_adr_req]);
+        assert_eq!(packed.left_over, vec![new_channel_req]);
+
+    }
+
+}
@@ -1,10 +1,17 @@
+use anyhow::{ Result as AnyResult, anyhow };
+
+pub mod uplink;
+pub mod downlink;
+
+pub use uplink::{UplinkMACCmd, Battery, parse_ul};
+pub use downlink::{DownlinkMACCmd, PackedMACCmds, parse_dl, pack};
 
 #[repr(u8)]
-#[derive(Debug, Clone, Copy)]
+#[derive(Debug, Clone, Copy, PartialEq)]
 pub enum MACCmdUL {
 
     // *******************************************
-    // Class A commands (0x20 to 0x2F) 
+    // Class A commands (0x00 to 0x0F) 
     // *******************************************
 
     //                    0x00, // RFU
@@ -92,14 +99,42 @@ impl MACCmdUL {
             _ => Err(anyhow!("invalid MACCmdUL value: {}", value)),
         }
     }
+
+    /// The length of the command payload (without the CID), `None` if it is not supported
+    pub fn payload_len(self) -> Option<usize> {
+        match self {
+            Self::ResetInd            => Some(1),
+            Self::LinkCheckReq        => Some(0),
+            Self::LinkADRAns          => Some(1),
+            Self::DutyCycleAns        => Some(0),
+            Self::RXParamSetupAns     => Some(1),
+            Self::DevStatusAns        => Some(2),
+            Self::NewChannelAns       => Some(1),
+            Self::RXTimingSetupAns    => Some(0),
+            Self::TXParamSetupAns     => Some(0),
+            Self::DlChannelAns        => Some(1),
+            Self::RekeyInd            => Some(1),
+            Self::ADRParamSetupAns    => Some(0),
+            Self::DeviceTimeReq       => Some(0),
+            Self::RejoinParamSetupAns => Some(1),
+            Self::PingSlotInfoReq     => Some(1),
+            Self::PingSlotChannelAns  => Some(1),
+            Self::BeaconTimingReq     => Some(0),
+            Self::BeaconFreqAns       => Some(1),
+            Self::DeviceModeInd       => Some(1),
+            // LoRaWAN 1.2.0 draft commands
+            Self::BeaconSettingsReq | Self::BeaconSettingsConf |
+            Self::DevMobilityInd | Self::NegotiationInd => None,
+        }
+    }
 }
 
 #[repr(u8)]
-#[derive(Debug, Clone, Copy)]
+#[derive(Debug, Clone, Copy, PartialEq)]
 pub enum MACCmdDL {
 
     // *******************************************
-    // Class A commands (0x20 to 0x2F) 
+    // Class A commands (0x00 to 0x0F) 
     // *******************************************
 
     //                    0x00, // RFU
@@ -139,7 +174,7 @@ pub enum MACCmdDL {
     //            0x21 to 0x2F, //  RFU
 
     // *******************************************
-    // Other commands (0x20 to 0x2F) 
+    // Other commands (0x30 to 0xFF) 
     // *******************************************
 
     DevMobilityResp     = 0x30, // (1.2.0) Acknowledges DevMobilityInd
@@ -186,4 +221,89 @@ impl MACCmdDL {
             _ => Err(anyhow!("invalid MACCmdDL value: {}", value)),
         }
     }
+
+    /// The length of the command payload (without the CID), `None` if it is not supported
+    pub fn payload_len(self) -> Option<usize> {
+        match self {
+            Self::ResetConf           => Some(1),
+            Self::LinkCheckAns        => Some(2),
+            Self::LinkADRReq          => Some(4),
+            Self::DutyCycleReq        => Some(1),
+            Self::RXParamSetupReq     => Some(4),
+            Self::DevStatusReq        => Some(0),
+            Self::NewChannelReq       => Some(5),
+            Self::RXTimingSetupReq    => Some(1),
+            Self::TXParamSetupReq     => Some(1),
+            Self::DlChannelReq        => Some(4),
+            Self::RekeyConf           => Some(1),
+            Self::ADRParamSetupReq    => Some(1),
+            Self::DeviceTimeAns       => Some(5),
+            Self::ForceRejoinReq      => Some(2),
+            Self::RejoinParamSetupReq => Some(1),
+            Self::PingSlotInfoAns     => Some(0),
+            Self::PingSlotChannelReq  => Some(4),
+            Self::BeaconTimingAns     => Some(3),
+            Self::BeaconFreqReq       => Some(3),
+            Self::DeviceModeConf      => Some(1),
+            // LoRaWAN 1.2.0 draft commands
+            Self::BeaconSettingsInd | Self::DevMobilityResp | Self::NegotiationConf => None,
+        }
+    }
+}
+
+/// MAC commands parsed from `FOpts` or from the `FRMPayload` of an `FPort` = 0 frame
+///
+/// Parsing stops at the first unknown or truncated command, the commands before it are kept.
+///
+#[derive(Debug, Default)]
+pub struct MACCmds<T> {
+    pub cmds: Vec<T>,
+    pub error: Option<anyhow::Error>,
+}
+
+/// Walks a buffer of MAC commands: CID|payload|CID|payload...
+fn parse<C: Copy + std::fmt::Debug, T>(
+    buf: &[u8],
+    from_value: fn(u8) -> AnyResult<C>,
+    payload_len: fn(C) -> Option<usize>,
+    from_payload: fn(C, &[u8]) -> T,
+) -> MACCmds<T> {
+
+    let mut result = MACCmds { cmds: Vec::new(), error: None };
+    let mut i = 0;
+
+    while i < buf.len() {
+        let cid = match from_value(buf[i]) {
+            Ok(cid) => cid,
+            Err(_) => {
+                result.error = Some(anyhow!("unknown MAC command, CID: 0x{:02x} at offset: {}", buf[i], i));
+                break;
+            },
+        };
+        let Some(len) = payload_len(cid) else {
+            result.error = Some(anyhow!("unsupported MAC command: {:?} at offset: {}", cid, i));
+            break;
+        };
+        if i + 1 + len > buf.len() {
+            result.error = Some(anyhow!(
+                "truncated MAC command: {:?} at offset: {}, {} bytes instead of {}", cid, i, buf.len() - i - 1, len
+            ));
+            break;
+        }
+        result.cmds.push(from_payload(cid, &buf[i + 1..i + 1 + len]));
+        i += 1 + len;
+    }
+
+    result
+
+}
+
+/// Frequency fields are 24-bit little endian values in 100 Hz steps
+fn freq_from_bytes(bytes: &[u8]) -> u32 {
+    u32::from_le_bytes([bytes[0], bytes[1], bytes[2], 0]) * 100
+}
+
+fn freq_to_bytes(freq: u32) -> [u8; 3] {
+    let bytes = (freq / 100).to_le_bytes();
+    [bytes[0], bytes[1], bytes[2]]
 }
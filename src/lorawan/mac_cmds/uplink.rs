@@ -0,0 +1,176 @@
+use super::{MACCmdUL, MACCmds, parse};
+
+/// A MAC command sent by an end-device
+///
+/// # Specification
+///
+/// LoRaWAN L2 1.0.4 - line #1040 \
+/// 5 MAC Commands                \
+///
+#[derive(Debug, Clone, PartialEq)]
+pub enum UplinkMACCmd {
+    ResetInd { minor: u8 },
+    LinkCheckReq,
+    LinkADRAns { power_ack: bool, data_rate_ack: bool, channel_mask_ack: bool },
+    DutyCycleAns,
+    RXParamSetupAns { rx1_dr_offset_ack: bool, rx2_data_rate_ack: bool, channel_ack: bool },
+    DevStatusAns { battery: Battery, margin: i8 },  // margin: SNR of the last DevStatusReq in dB (-32..31)
+    NewChannelAns { data_rate_range_ok: bool, channel_freq_ok: bool },
+    RXTimingSetupAns,
+    TXParamSetupAns,
+    DlChannelAns { uplink_freq_exists: bool, channel_freq_ok: bool },
+    RekeyInd { minor: u8 },
+    ADRParamSetupAns,
+    DeviceTimeReq,
+    RejoinParamSetupAns { time_ok: bool },
+    PingSlotInfoReq { periodicity: u8 },
+    PingSlotChannelAns { data_rate_ok: bool, channel_freq_ok: bool },
+    BeaconTimingReq,
+    BeaconFreqAns { beacon_freq_ok: bool },
+    DeviceModeInd { class: u8 },               // 0: Class A, 2: Class C
+}
+impl UplinkMACCmd {
+    pub fn cid(&self) -> MACCmdUL {
+        match self {
+            Self::ResetInd { .. }            => MACCmdUL::ResetInd,
+            Self::LinkCheckReq               => MACCmdUL::LinkCheckReq,
+            Self::LinkADRAns { .. }          => MACCmdUL::LinkADRAns,
+            Self::DutyCycleAns               => MACCmdUL::DutyCycleAns,
+            Self::RXParamSetupAns { .. }     => MACCmdUL::RXParamSetupAns,
+            Self::DevStatusAns { .. }        => MACCmdUL::DevStatusAns,
+            Self::NewChannelAns { .. }       => MACCmdUL::NewChannelAns,
+            Self::RXTimingSetupAns           => MACCmdUL::RXTimingSetupAns,
+            Self::TXParamSetupAns            => MACCmdUL::TXParamSetupAns,
+            Self::DlChannelAns { .. }        => MACCmdUL::DlChannelAns,
+            Self::RekeyInd { .. }            => MACCmdUL::RekeyInd,
+            Self::ADRParamSetupAns           => MACCmdUL::ADRParamSetupAns,
+            Self::DeviceTimeReq              => MACCmdUL::DeviceTimeReq,
+            Self::RejoinParamSetupAns { .. } => MACCmdUL::RejoinParamSetupAns,
+            Self::PingSlotInfoReq { .. }     => MACCmdUL::PingSlotInfoReq,
+            Self::PingSlotChannelAns { .. }  => MACCmdUL::PingSlotChannelAns,
+            Self::BeaconTimingReq            => MACCmdUL::BeaconTimingReq,
+            Self::BeaconFreqAns { .. }       => MACCmdUL::BeaconFreqAns,
+            Self::DeviceModeInd { .. }       => MACCmdUL::DeviceModeInd,
+        }
+    }
+
+    /// Builds the command from its payload, `payload` has the length of `cid.payload_len()`
+    fn from_payload(cid: MACCmdUL, payload: &[u8]) -> Self {
+        let bit = |n: u8| payload[0] & (1 << n) != 0;
+        match cid {
+            MACCmdUL::ResetInd => Self::ResetInd { minor: payload[0] & 0x0f },
+            MACCmdUL::LinkCheckReq => Self::LinkCheckReq,
+            MACCmdUL::LinkADRAns => Self::LinkADRAns {
+                power_ack: bit(2), data_rate_ack: bit(1), channel_mask_ack: bit(0),
+            },
+            MACCmdUL::DutyCycleAns => Self::DutyCycleAns,
+            MACCmdUL::RXParamSetupAns => Self::RXParamSetupAns {
+                rx1_dr_offset_ack: bit(2), rx2_data_rate_ack: bit(1), channel_ack: bit(0),
+            },
+            MACCmdUL::DevStatusAns => Self::DevStatusAns {
+                battery: Battery::from_value(payload[0]),
+                margin: ((payload[1] << 2) as i8) >> 2, // 6-bit signed value
+            },
+            MACCmdUL::NewChannelAns => Self::NewChannelAns { data_rate_range_ok: bit(1), channel_freq_ok: bit(0) },
+            MACCmdUL::RXTimingSetupAns => Self::RXTimingSetupAns,
+            MACCmdUL::TXParamSetupAns => Self::TXParamSetupAns,
+            MACCmdUL::DlChannelAns => Self::DlChannelAns { uplink_freq_exists: bit(1), channel_freq_ok: bit(0) },
+            MACCmdUL::RekeyInd => Self::RekeyInd { minor: payload[0] & 0x0f },
+            MACCmdUL::ADRParamSetupAns => Self::ADRParamSetupAns,
+            MACCmdUL::DeviceTimeReq => Self::DeviceTimeReq,
+            MACCmdUL::RejoinParamSetupAns => Self::RejoinParamSetupAns { time_ok: bit(0) },
+            MACCmdUL::PingSlotInfoReq => Self::PingSlotInfoReq { periodicity: payload[0] & 0x07 },
+            MACCmdUL::PingSlotChannelAns => Self::PingSlotChannelAns { data_rate_ok: bit(1), channel_freq_ok: bit(0) },
+            MACCmdUL::BeaconTimingReq => Self::BeaconTimingReq,
+            MACCmdUL::BeaconFreqAns => Self::BeaconFreqAns { beacon_freq_ok: bit(0) },
+            MACCmdUL::DeviceModeInd => Self::DeviceModeInd { class: payload[0] },
+            MACCmdUL::BeaconSettingsReq | MACCmdUL::BeaconSettingsConf |
+            MACCmdUL::DevMobilityInd | MACCmdUL::NegotiationInd => {
+                unreachable!("{:?} has no payload length", cid)
+            },
+        }
+    }
+}
+
+/// The battery level reported in DevStatusAns
+///
+/// # Specification
+///
+/// LoRaWAN L2 1.0.4 - line #1285                                \
+/// 5.5 End-Device Status commands (DevStatusReq, DevStatusAns) \
+///
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum Battery {
+    ExternalPower,  // 0
+    Level(u8),      // 1 (minimum) .. 254 (maximum)
+    Unknown,        // 255, the device cannot measure its battery level
+}
+impl Battery {
+    pub fn from_value(v: u8) -> Self {
+        match v {
+            0   => Self::ExternalPower,
+            255 => Self::Unknown,
+            _   => Self::Level(v),
+        }
+    }
+
+    pub fn value(self) -> u8 {
+        match self {
+            Self::ExternalPower => 0,
+            Self::Level(v)      => v,
+            Self::Unknown       => 255,
+        }
+    }
+
+    /// The battery level in percent, if the device runs on a measured battery
+    pub fn percent(self) -> Option<f32> {
+        match self {
+            Self::Level(v) => Some((v - 1) as f32 * 100.0 / 253.0),
+            _ => None,
+        }
+    }
+}
+
+/// Parses the MAC commands of an uplink (`FOpts` or decrypted `FPort` = 0 `FRMPayload`)
+pub fn parse_ul(buf: &[u8]) -> MACCmds<UplinkMACCmd> {
+    parse(buf, MACCmdUL::from_value, MACCmdUL::payload_len, UplinkMACCmd::from_payload)
+}
+
+
+#[cfg(test)]
+mod tests {
+
+    use super::*;
+
+    #[test]
+    fn test_parse_ul() {
+
+        // LinkADRAns (power rejected), DevStatusAns (battery 200, margin -5), LinkCheckReq
+        let result = parse_ul(&[0x03, 0x03, 0x06, 0xc8, 0x3b, 0x02]);
+        assert!(result.error.is_none());
+        assert_eq!(result.cmds, vec![
+            UplinkMACCmd::LinkADRAns { power_ack: false, data_rate_ack: true, channel_mask_ack: true },
+            UplinkMACCmd::DevStatusAns { battery: Battery::Level(200), margin: -5 },
+            UplinkMACCmd::LinkCheckReq,
+        ]);
+
+        // truncated DevStatusAns
+        let result = parse_ul(&[0x02, 0x06, 0xc8]);
+        assert_eq!(result.cmds, vec![UplinkMACCmd::LinkCheckReq]);
+        assert!(result.error.is_some());
+
+        let result = parse_ul(&[0x06, 0x00, 0x1f, 0x06, 0xff, 0x20]);
+        assert_eq!(result.cmds, vec![
+            UplinkMACCmd::DevStatusAns { battery: Battery::ExternalPower, margin: 31 },
+            UplinkMACCmd::DevStatusAns { battery: Battery::Unknown, margin: -32 },
+        ]);
+        assert_eq!(Battery::Level(254).percent(), Some(100.0));
+
+        // unknown CID
+        let result = parse_ul(&[0x0e, 0x02]);
+        assert!(result.cmds.is_empty());
+        assert!(result.error.is_some());
+
+    }
+
+}
@@ -1,5 +1,12 @@
 pub mod enums;
 pub mod crypto;
+pub mod data_rate;
+pub mod cf_list;
+pub mod gps_time;
+pub mod link_check;
+pub mod mac_cmds;
+pub mod region;
+pub mod time_on_air;
 // pub mod phy_payload;
 
-pub use enums::{Major, MType, RJType, Dir};
+pub use enums::{Major, MType, RJType, Dir, MACVersion};
@@ -0,0 +1,404 @@
+use super::data_rate::DataRate;
+
+/// The regional channel plans of RP002
+///
+/// CN470 follows the CN470-510 channel plan of LoRaWAN Regional Parameters v1.1 (96 uplink
+/// and 48 downlink channels, DR0..DR5 = SF12..SF7 BW125), not the CN470 plans of RP002.
+///
+/// # Specification
+///
+/// RP002-1.0.3 - 2 LoRaWAN Regional Parameters \
+///
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash)]
+pub enum Region {
+    EU868,
+    US915,
+    AU915,
+    AS923_1,
+    AS923_2,
+    AS923_3,
+    AS923_4,
+    KR920,
+    IN865,
+    CN470,
+}
+
+/// An uplink channel
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub struct Channel {
+    pub freq: u32,     // Hz
+    pub min_dr: u8,
+    pub max_dr: u8,
+}
+
+const fn lora(sp_fact: u8, bandwidth: u32) -> Option<DataRate> {
+    Some(DataRate::LoRa { sp_fact, bandwidth })
+}
+
+const fn fsk() -> Option<DataRate> {
+    Some(DataRate::Fsk { bit_rate: 50_000 })
+}
+
+const fn lr_fhss(coding_rate: u8, bandwidth: u32) -> Option<DataRate> {
+    Some(DataRate::LrFhss { coding_rate, bandwidth })
+}
+
+/// RX1 data rate of the AS923 and IN865 regions, RX1DROffset 6 and 7 raise the data rate
+fn rx1_dr_with_effective_offset(uplink_dr: u8, rx1_dr_offset: u8, min_dr: u8) -> Option<u8> {
+    let effective_offset: i16 = match rx1_dr_offset {
+        0..=5 => rx1_dr_offset as i16,
+        6 => -1,
+        7 => -2,
+        _ => return None,
+    };
+    Some((uplink_dr as i16 - effective_offset).clamp(min_dr as i16, 5) as u8)
+}
+
+/// Uplink channels at evenly spaced frequencies
+fn channel_range(count: u32, first_freq: u32, step: u32, min_dr: u8, max_dr: u8) -> impl Iterator<Item = Channel> {
+    (0..count).map(move |n| Channel { freq: first_freq + n * step, min_dr, max_dr })
+}
+
+impl Region {
+
+    /// The region of a Regional Parameter Channel Plan Common Name (e.g. "EU868", "AS923-2")
+    pub fn from_name(name: &str) -> Option<Self> {
+        match name.to_ascii_uppercase().as_str() {
+            "EU868" | "EU863-870"               => Some(Region::EU868),
+            "US915" | "US902-928"               => Some(Region::US915),
+            "AU915" | "AU915-928"               => Some(Region::AU915),
+            "AS923" | "AS923-1" | "AS923_1"     => Some(Region::AS923_1),
+            "AS923-2" | "AS923_2"               => Some(Region::AS923_2),
+            "AS923-3" | "AS923_3"               => Some(Region::AS923_3),
+            "AS923-4" | "AS923_4"               => Some(Region::AS923_4),
+            "KR920" | "KR920-923"               => Some(Region::KR920),
+            "IN865" | "IN865-867"               => Some(Region::IN865),
+            "CN470" | "CN470-510"               => Some(Region::CN470),
+            _ => None,
+        }
+    }
+
+    pub fn is_as923(self) -> bool {
+        matches!(self, Region::AS923_1 | Region::AS923_2 | Region::AS923_3 | Region::AS923_4)
+    }
+
+    /// Whether the region has a fixed channel plan, where downlink channels differ from uplink channels
+    pub fn is_fixed_plan(self) -> bool {
+        matches!(self, Region::US915 | Region::AU915 | Region::CN470)
+    }
+
+    /// The frequency band of the region (Hz), every channel is to be inside it
+    pub fn freq_range(self) -> (u32, u32) {
+        match self {
+            Region::EU868 => (863_000_000, 870_000_000),
+            Region::US915 => (902_000_000, 928_000_000),
+            Region::KR920 => (920_900_000, 923_300_000),
+            Region::IN865 => (865_000_000, 867_000_000),
+            Region::CN470 => (470_000_000, 510_000_000),
+            _ => (915_000_000, 928_000_000), // AU915, AS923
+        }
+    }
+
+    /// The modulation parameters of a data rate index
+    pub fn data_rate(self, dr: u8) -> Option<DataRate> {
+        match (self, dr) {
+            (Region::US915, 0..=3)  => lora(10 - dr, 125),
+            (Region::US915, 4)      => lora(8, 500),
+            (Region::US915, 5)      => lr_fhss(1, 1523),
+            (Region::US915, 6)      => lr_fhss(2, 1523),
+            (Region::US915, 8..=13) => lora(20 - dr, 500),
+            (Region::US915, _)      => None,
+
+            (Region::AU915, 0..=5)  => lora(12 - dr, 125),
+            (Region::AU915, 6)      => lora(8, 500),
+            (Region::AU915, 7)      => lr_fhss(1, 1523),
+            (Region::AU915, 8..=13) => lora(20 - dr, 500),
+            (Region::AU915, _)      => None,
+
+            (_, 0..=5)              => lora(12 - dr, 125),
+
+            (Region::EU868, 6)      => lora(7, 250),
+            (Region::EU868, 7)      => fsk(),
+            (Region::EU868, 8)      => lr_fhss(1, 137),
+            (Region::EU868, 9)      => lr_fhss(2, 137),
+            (Region::EU868, 10)     => lr_fhss(1, 336),
+            (Region::EU868, 11)     => lr_fhss(2, 336),
+
+            (r, 6) if r.is_as923()  => lora(7, 250),
+            (r, 7) if r.is_as923()  => fsk(),
+
+            (Region::IN865, 7)      => fsk(),
+
+            _ => None,
+        }
+    }
+
+    /// The data rate index of an uplink
+    pub fn uplink_dr(self, data_rate: &DataRate) -> Option<u8> {
+        (0..=11).find(|dr| self.data_rate(*dr).as_ref() == Some(data_rate))
+    }
+
+    /// The data rate index of a downlink
+    pub fn downlink_dr(self, data_rate: &DataRate) -> Option<u8> {
+        let drs = match self {
+            Region::US915 | Region::AU915 => 8..=13,
+            _ => 0..=7,
+        };
+        drs.into_iter().find(|dr| self.data_rate(*dr).as_ref() == Some(data_rate))
+    }
+
+    /// The maximum `MACPayload` size (M) at a data rate
+    ///
+    /// # Arguments
+    ///
+    /// * __`dwell_time`__\
+    ///   The 400 ms dwell time limitation of the direction is in force (TXParamSetupReq) \
+    ///
+    pub fn max_mac_payload(self, dr: u8, dwell_time: bool) -> Option<usize> {
+        match (self, dwell_time) {
+            (Region::EU868, _) => [59, 59, 59, 123, 230, 230, 230, 230, 58, 123, 58, 123]
+                .get(dr as usize).copied(),
+            (Region::US915, _) => match dr {
+                0 => Some(19),
+                1 | 8 => Some(61),
+                2 | 6 => Some(133),
+                3 | 4 | 10..=13 => Some(250),
+                5 => Some(58),
+                9 => Some(137),
+                _ => None,
+            },
+            (Region::AU915, false) => match dr {
+                0..=2 => Some(59),
+                3 => Some(123),
+                4..=6 => Some(230),
+                7 => Some(58),
+                8 => Some(61),
+                9 => Some(137),
+                10..=13 => Some(250),
+                _ => None,
+            },
+            (Region::AU915, true) => match dr {
+                2 => Some(19),
+                3 | 8 => Some(61),
+                4 => Some(133),
+                5 | 6 | 10..=13 => Some(250),
+                9 => Some(137),
+                _ => None,
+            },
+            (r, false) if r.is_as923() => [59, 59, 59, 123, 230, 230, 230, 230].get(dr as usize).copied(),
+            (r, true) if r.is_as923() => match dr {
+                2 => Some(19),
+                3 => Some(61),
+                4 => Some(133),
+                5..=7 => Some(250),
+                _ => None,
+            },
+            (Region::KR920, _) => [59, 59, 59, 123, 230, 230].get(dr as usize).copied(),
+            (Region::IN865, _) => match dr {
+                0..=2 => Some(59),
+                3 => Some(123),
+                4 | 5 | 7 => Some(230),
+                _ => None,
+            },
+            (Region::CN470, _) => [59, 59, 59, 123, 230, 230].get(dr as usize).copied(),
+            _ => None,
+        }
+    }
+
+    /// The default MaxEIRP of the region (dBm)
+    pub fn default_max_eirp(self) -> f32 {
+        match self {
+            Region::EU868 => 16.0,
+            Region::US915 | Region::AU915 | Region::IN865 => 30.0,
+            Region::KR920 => 14.0,
+            Region::CN470 => 19.15,
+            _ => 16.0, // AS923
+        }
+    }
+
+    /// The highest TXPower index of the region
+    pub fn max_tx_power_index(self) -> u8 {
+        match self {
+            Region::US915 | Region::AU915 => 14,
+            Region::IN865 => 10,
+            _ => 7,
+        }
+    }
+
+    /// The output power of a TXPower index (dBm EIRP), MaxEIRP - 2 dB per step
+    pub fn tx_power(self, tx_power: u8, max_eirp: Option<f32>) -> Option<f32> {
+        if tx_power > self.max_tx_power_index() {
+            return None;
+        }
+        Some(max_eirp.unwrap_or(self.default_max_eirp()) - 2.0 * tx_power as f32)
+    }
+
+    /// The RX1 data rate of an uplink data rate
+    ///
+    /// # Arguments
+    ///
+    /// * __`rx1_dr_offset`__\
+    ///   RX1DROffset of the device \
+    /// * __`downlink_dwell_time`__\
+    ///   The downlink dwell time limitation is in force (AS923) \
+    ///
+    pub fn rx1_dr(self, uplink_dr: u8, rx1_dr_offset: u8, downlink_dwell_time: bool) -> Option<u8> {
+        match self {
+            Region::US915 => {
+                let rx1_dr = match uplink_dr {
+                    0 | 5 => [10, 9, 8, 8],
+                    1 | 6 => [11, 10, 9, 8],
+                    2 => [12, 11, 10, 9],
+                    3 => [13, 12, 11, 10],
+                    4 => [13, 13, 12, 11],
+                    _ => return None,
+                };
+                rx1_dr.get(rx1_dr_offset as usize).copied()
+            },
+            Region::AU915 => {
+                if uplink_dr > 7 || rx1_dr_offset > 5 {
+                    return None;
+                }
+                // LR-FHSS (DR7) is answered like DR1
+                let base = if uplink_dr == 7 { 9 } else { 8 + uplink_dr };
+                Some((base - rx1_dr_offset).clamp(8, 13))
+            },
+            r if r.is_as923() => {
+                if uplink_dr > 7 {
+                    return None;
+                }
+                rx1_dr_with_effective_offset(uplink_dr, rx1_dr_offset, if downlink_dwell_time { 2 } else { 0 })
+            },
+            Region::IN865 => {
+                if uplink_dr > 7 {
+                    return None;
+                }
+                rx1_dr_with_effective_offset(uplink_dr, rx1_dr_offset, 0)
+            },
+            _ => {
+                let max_uplink_dr = match self {
+                    Region::EU868 => 11,
+                    _ => 5, // KR920, CN470
+                };
+                if uplink_dr > max_uplink_dr || rx1_dr_offset > 5 {
+                    return None;
+                }
+                // LR-FHSS uplinks are answered with DR1 (CR1/3) or DR2 (CR2/3)
+                let base = match uplink_dr {
+                    8 | 10 => 1,
+                    9 | 11 => 2,
+                    _ => uplink_dr,
+                };
+                Some(base.saturating_sub(rx1_dr_offset))
+            },
+        }
+    }
+
+    /// The uplink channels a device knows by default
+    ///
+    /// Dynamic channel plans have a few mandatory channels, fixed channel plans define all
+    /// their uplink channels.
+    ///
+    pub fn default_channels(self) -> Vec<Channel> {
+        let mhz = |freq: f64| (freq * 1_000_000.0).round() as u32;
+        match self {
+            Region::EU868 => [868.1, 868.3, 868.5].iter().map(|f| Channel { freq: mhz(*f), min_dr: 0, max_dr: 5 }).collect(),
+            Region::US915 => channel_range(64, 902_300_000, 200_000, 0, 3)
+                .chain(channel_range(8, 903_000_000, 1_600_000, 4, 4))
+                .collect(),
+            Region::AU915 => channel_range(64, 915_200_000, 200_000, 0, 5)
+                .chain(channel_range(8, 915_900_000, 1_600_000, 6, 6))
+                .collect(),
+            Region::AS923_1 => channel_range(2, 923_200_000, 200_000, 0, 5).collect(),
+            Region::AS923_2 => channel_range(2, 921_400_000, 200_000, 0, 5).collect(),
+            Region::AS923_3 => channel_range(2, 916_600_000, 200_000, 0, 5).collect(),
+            Region::AS923_4 => channel_range(2, 917_300_000, 200_000, 0, 5).collect(),
+            Region::KR920 => channel_range(3, 922_100_000, 200_000, 0, 5).collect(),
+            Region::IN865 => [865.0625, 865.4025, 865.985].iter().map(|f| Channel { freq: mhz(*f), min_dr: 0, max_dr: 5 }).collect(),
+            Region::CN470 => channel_range(96, 470_300_000, 200_000, 0, 5).collect(),
+        }
+    }
+
+    /// The downlink channels of fixed channel plans, RX1 uses the uplink channel modulo their number
+    pub fn downlink_channels(self) -> Vec<u32> {
+        match self {
+            Region::US915 | Region::AU915 => (0..8).map(|n| 923_300_000 + n * 600_000).collect(),
+            Region::CN470 => (0..48).map(|n| 500_300_000 + n * 200_000).collect(),
+            _ => Vec::new(),
+        }
+    }
+
+    /// The RX1 frequency of an uplink frequency (Hz)
+    ///
+    /// Dynamic channel plans answer on the uplink frequency, fixed channel plans on the
+    /// downlink channel of the uplink channel index modulo the number of downlink channels.
+    ///
+    pub fn rx1_freq(self, uplink_freq: u32) -> Option<u32> {
+        if !self.is_fixed_plan() {
+            return Some(uplink_freq);
+        }
+        let ch_index = self.default_channels().iter().position(|ch| ch.freq == uplink_freq)?;
+        let downlink_channels = self.downlink_channels();
+        downlink_channels.get(ch_index % downlink_channels.len()).copied()
+    }
+
+    /// The default RX2 frequency (Hz) and data rate
+    pub fn default_rx2(self) -> (u32, u8) {
+        match self {
+            Region::EU868 => (869_525_000, 0),
+            Region::US915 | Region::AU915 => (923_300_000, 8),
+            Region::AS923_1 => (923_200_000, 2),
+            Region::AS923_2 => (921_400_000, 2),
+            Region::AS923_3 => (916_600_000, 2),
+            Region::AS923_4 => (917_300_000, 2),
+            Region::KR920 => (921_900_000, 0),
+            Region::IN865 => (866_550_000, 2),
+            Region::CN470 => (505_300_000, 0),
+        }
+    }
+
+}
+
+
+#[cfg(test)]
+mod tests {
+
+    use super::*;
+
+    #[test]
+    fn test_region() {
+
+        assert_eq!(Region::from_name("as923-2"), Some(Region::AS923_2));
+
+        let sf8_bw500 = DataRate::LoRa { sp_fact: 8, bandwidth: 500 };
+        assert_eq!(Region::US915.uplink_dr(&sf8_bw500), Some(4));
+        assert_eq!(Region::US915.downlink_dr(&sf8_bw500), Some(12));
+        assert_eq!(Region::EU868.uplink_dr(&DataRate::LoRa { sp_fact: 9, bandwidth: 125 }), Some(3));
+
+        assert_eq!(Region::US915.max_mac_payload(0, false), Some(19));
+        assert_eq!(Region::AS923_1.max_mac_payload(0, true), None);
+        assert_eq!(Region::AS923_1.max_mac_payload(2, true), Some(19));
+
+        assert_eq!(Region::EU868.rx1_dr(5, 2, false), Some(3));
+        assert_eq!(Region::EU868.rx1_dr(9, 1, false), Some(1));
+        assert_eq!(Region::US915.rx1_dr(4, 1, false), Some(13));
+        assert_eq!(Region::AU915.rx1_dr(5, 1, false), Some(12));
+        assert_eq!(Region::AU915.rx1_dr(6, 0, false), Some(13));
+        assert_eq!(Region::AS923_1.rx1_dr(5, 7, false), Some(5));
+        assert_eq!(Region::AS923_1.rx1_dr(1, 6, true), Some(2));
+
+        assert_eq!(Region::EU868.rx1_freq(868_300_000), Some(868_300_000));
+        assert_eq!(Region::US915.rx1_freq(902_500_000), Some(923_900_000));
+        assert_eq!(Region::US915.rx1_freq(903_000_000), Some(923_300_000));
+        assert_eq!(Region::CN470.rx1_freq(480_100_000), Some(500_500_000));
+        assert_eq!(Region::CN470.data_rate(6), None);
+        assert_eq!(Region::CN470.max_mac_payload(0, false), Some(59));
+        assert_eq!(Region::CN470.rx1_dr(5, 1, false), Some(4));
+
+        assert_eq!(Region::EU868.tx_power(7, None), Some(2.0));
+        assert_eq!(Region::US915.default_channels().len(), 72);
+        assert_eq!(Region::US915.default_channels()[64].freq, 903_000_000);
+
+    }
+
+}
@@ -0,0 +1,176 @@
+use std::time::Duration;
+
+/// The number of preamble symbols of LoRaWAN frames
+pub const LORA_PREAMBLE_LEN: u32 = 8;
+
+/// The number of bytes of the FSK preamble, sync word, length field and CRC around the PHYPayload
+pub const FSK_OVERHEAD_LEN: u32 = 5 + 3 + 1 + 2;
+
+/// Parses a LoRa `codr` identifier of the packet forwarder (e.g. "4/5")
+///
+/// Returns the coding rate index CR (1 for 4/5 .. 4 for 4/8).
+///
+pub fn parse_lora_codr(codr: &str) -> Option<u8> {
+    match codr.strip_prefix("4/")?.parse::<u8>().ok()? {
+        denom @ 5..=8 => Some(denom - 4),
+        _ => None,
+    }
+}
+
+/// LR-FHSS: the bits of a header, the payload bits of a fragment and the bits of a fragment
+/// with its sync preamble; a bit lasts 2.048 ms (488.28125 bit/s)
+const LR_FHSS_HEADER_BITS: u64 = 114;
+const LR_FHSS_FRAG_BITS: u64 = 48;
+const LR_FHSS_BLOCK_BITS: u64 = 50;
+const LR_FHSS_BIT_NS: u64 = 2_048_000;
+
+/// The modulation parameters of a LoRa frame
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub struct LoRaFrameParams {
+    pub sp_fact: u8,              // 5..12
+    pub bandwidth_khz: u32,
+    pub coding_rate: u8,          // CR: 1 for 4/5 .. 4 for 4/8
+    pub preamble_len: u32,        // symbols
+    pub explicit_header: bool,
+    pub crc: bool,
+    pub low_data_rate_opt: bool,
+}
+impl LoRaFrameParams {
+
+    /// The parameters of a LoRaWAN frame: preamble of 8 symbols, explicit header and low data
+    /// rate optimization for symbols of 16 ms or longer
+    ///
+    /// # Arguments
+    ///
+    /// * __`crc`__\
+    ///   Whether the payload CRC is present (uplinks) \
+    ///
+    pub fn lorawan(sp_fact: u8, bandwidth_khz: u32, coding_rate: u8, crc: bool) -> Self {
+        LoRaFrameParams {
+            sp_fact,
+            bandwidth_khz,
+            coding_rate,
+            preamble_len: LORA_PREAMBLE_LEN,
+            explicit_header: true,
+            crc,
+            low_data_rate_opt: (1_u32 << sp_fact) >= 16 * bandwidth_khz,
+        }
+    }
+
+    /// The time-on-air of a frame with a payload of `payload_len` bytes
+    ///
+    /// The symbol time 2^SF / BW is an integer number of ns for the LoRaWAN bandwidths, the
+    /// result is exact.
+    ///
+    /// # Specification
+    ///
+    /// Semtech SX1276 Datasheet - 4.1.1.7 Time on air \
+    ///
+    pub fn time_on_air(&self, payload_len: usize) -> Duration {
+
+        let sf = self.sp_fact as i64;
+        let de = self.low_data_rate_opt as i64;
+        let ih = !self.explicit_header as i64;
+        let crc = self.crc as i64;
+
+        let numerator = 8 * payload_len as i64 - 4 * sf + 28 + 16 * crc - 20 * ih;
+        let denominator = 4 * (sf - 2 * de);
+        let n_payload = 8 + (numerator.max(0) + denominator - 1) / denominator * (self.coding_rate as i64 + 4);
+
+        // (n_preamble + 4.25 + n_payload) * 2^SF / BW, in quarter symbols
+        let quarter_symbols = 4 * (self.preamble_len as u64 + n_payload as u64) + 17;
+        let ns = quarter_symbols * (1_u64 << self.sp_fact) * 1_000_000 / (4 * self.bandwidth_khz as u64);
+
+        Duration::from_nanos(ns)
+
+    }
+
+}
+
+/// The time-on-air of a LoRa frame with explicit header and a preamble of 8 symbols
+///
+/// # Arguments
+///
+/// * __`sp_fact`__\
+///   Spreading factor (7..12) \
+/// * __`bandwidth_khz`__\
+///   Bandwidth in kHz \
+/// * __`coding_rate`__\
+///   Coding rate index CR (1 for 4/5 .. 4 for 4/8) \
+/// * __`crc`__\
+///   Whether the payload CRC is present (uplinks) \
+/// * __`payload_len`__\
+///   The length of the PHYPayload in bytes \
+///
+pub fn lora_time_on_air(sp_fact: u8, bandwidth_khz: u32, coding_rate: u8, crc: bool, payload_len: usize) -> Duration {
+    LoRaFrameParams::lorawan(sp_fact, bandwidth_khz, coding_rate, crc).time_on_air(payload_len)
+}
+
+/// The time-on-air of an FSK frame: 5 bytes of preamble, 3 bytes of sync word, a length byte,
+/// the payload and a 2-byte CRC
+///
+/// # Specification
+///
+/// RP002-1.0.3 - 3.2 LoRaWAN GFSK Physical Layer \
+///
+pub fn fsk_time_on_air(bit_rate: u32, payload_len: usize) -> Duration {
+    let bits = 8 * (FSK_OVERHEAD_LEN as u64 + payload_len as u64);
+    Duration::from_nanos(bits * 1_000_000_000 / bit_rate as u64)
+}
+
+/// The time-on-air of an LR-FHSS frame
+///
+/// The header is repeated 3 times at CR 1/3 and 2 times at CR 2/3. The payload and its CRC
+/// are coded and sent in fragments of 48 bits, each with 2 extra bits; the last partial fragment
+/// carries its remaining bits only.
+///
+/// # Arguments
+///
+/// * __`coding_rate`__\
+///   1 for CR 1/3, 2 for CR 2/3 \
+///
+/// # Specification
+///
+/// Semtech LR-FHSS Driver - lr_fhss_get_time_on_air_numerator() \
+///
+pub fn lr_fhss_time_on_air(coding_rate: u8, payload_len: usize) -> Duration {
+    let (header_count, bits) = match coding_rate {
+        1 => (3, ((payload_len as u64 + 2) * 8 + 6) * 3),
+        _ => (2, ((payload_len as u64 + 2) * 8 + 6) * 3 / 2),
+    };
+    let rem = bits % LR_FHSS_FRAG_BITS;
+    let payload_bits = bits / LR_FHSS_FRAG_BITS * LR_FHSS_BLOCK_BITS
+        + if rem > 0 { rem + LR_FHSS_BLOCK_BITS - LR_FHSS_FRAG_BITS } else { 0 };
+    Duration::from_nanos((header_count * LR_FHSS_HEADER_BITS + payload_bits) * LR_FHSS_BIT_NS)
+}
+
+
+#[cfg(test)]
+mod tests {
+
+    use super::*;
+
+    #[test]
+    fn test_lora_time_on_air() {
+        assert_eq!(parse_lora_codr("4/5"), Some(1));
+        // 13 bytes, CR 4/5, CRC on
+        assert_eq!(lora_time_on_air(7, 125, 1, true, 13).as_micros(), 46_336);
+        assert_eq!(lora_time_on_air(12, 125, 1, true, 13).as_micros(), 1_155_072);
+        // 51 bytes, CR 4/5, no CRC (downlink)
+        assert_eq!(lora_time_on_air(9, 125, 1, false, 51).as_micros(), 328_704);
+        assert_eq!(lora_time_on_air(8, 500, 1, false, 13).as_micros(), 20_608);
+        // Implicit header, CR 4/8, preamble of 12 symbols
+        let params = LoRaFrameParams {
+            explicit_header: false, coding_rate: 4, preamble_len: 12, .. LoRaFrameParams::lorawan(10, 125, 1, true)
+        };
+        assert_eq!(params.time_on_air(20).as_micros(), 460_800);
+    }
+
+    #[test]
+    fn test_fsk_lr_fhss_time_on_air() {
+        assert_eq!(fsk_time_on_air(50_000, 20).as_micros(), 4_960);
+        assert_eq!(lr_fhss_time_on_air(1, 10).as_micros(), 1_355_776);
+        assert_eq!(lr_fhss_time_on_air(2, 10).as_micros(), 796_672);
+    }
+
+}
@@ -0,0 +1,651 @@
+use std::{
+    fs, process,
+    collections::HashMap,
+    sync::OnceLock,
+};
+use serde::{Deserialize, Deserializer};
+use anyhow::{ Result as AnyResult, anyhow };
+
+use crate::{
+    settings,
+    rf_region_loader,
+    lorawan::{
+        cf_list,
+        region::Region,
+        crypto::{
+            key_wrap::{key_from_string_with_kek, wrap_key},
+            utils::key_from_string,
+        },
+    },
+};
+
+//********************************
+//* server_config.yaml
+//********************************
+
+#[derive(Debug, Deserialize)]
+#[allow(unused)]
+pub struct GlobalParams {
+    #[serde(rename = "RECEIVE_DELAY1")]
+    pub receive_delay1: u32,        // s
+    #[serde(rename = "RECEIVE_DELAY2")]
+    pub receive_delay2: u32,        // s
+    #[serde(rename = "RX1DROffset")]
+    pub rx1_dr_offset: u8,
+    #[serde(rename = "JOIN_ACCEPT_DELAY1")]
+    pub join_accept_delay1: u32,    // s
+    #[serde(rename = "JOIN_ACCEPT_DELAY2")]
+    pub join_accept_delay2: u32,    // s
+    #[serde(rename = "MAX_FCNT_GAP")]
+    pub max_fcnt_gap: u32,
+    #[serde(rename = "ADR_ACK_LIMIT")]
+    pub adr_ack_limit: u32,
+    #[serde(rename = "ADR_ACK_DELAY")]
+    pub adr_ack_delay: u32,
+    #[serde(rename = "RETRANSMIT_TIMEOUT")]
+    pub retransmit_timeout: u32,    // s
+    #[serde(rename = "DownlinkDwellTime")]
+    pub downlink_dwell_time: u8,
+    #[serde(rename = "PING_SLOT_PERIODICITY")]
+    pub ping_slot_periodicity: u8,
+    #[serde(rename = "CLASS_B_RESP_TIMEOUT")]
+    pub class_b_resp_timeout: u32,  // s
+    #[serde(rename = "CLASS_C_RESP_TIMEOUT")]
+    pub class_c_resp_timeout: u32,  // s
+}
+
+#[derive(Debug, Deserialize)]
+#[allow(unused)]
+pub struct NsConfig {
+    pub ns_id: u64,
+    pub net_ids: Vec<u32>,
+    pub global_params_for_all_rf_regions: GlobalParams,
+    #[serde(default)]
+    pub f_cnt_reset: FCntResetPolicy,
+}
+impl NsConfig {
+    /// The NetID announced in Join-Accept frames
+    pub fn net_id(&self) -> u32 {
+        self.net_ids.first().copied().unwrap_or(0)
+    }
+}
+
+/// What to do with an uplink whose FCnt is lower than expected (ABP device reset or replay)
+#[derive(Debug, Deserialize, Clone, Copy, PartialEq, Default)]
+pub enum FCntResetPolicy {
+    #[default]
+    Reject,  // the frame is dropped
+    Accept,  // the frame is accepted, flagged as FCntReset and the counters restart from its FCnt
+}
+
+#[derive(Debug, Deserialize)]
+#[allow(unused)]
+pub struct JsConfig {
+    pub join_euis: Vec<u64>,
+}
+
+#[derive(Debug, Deserialize)]
+#[allow(unused)]
+pub struct JoinServerRecord {
+    pub join_eui: u64,
+    pub url: String,
+}
+
+#[derive(Debug, Deserialize)]
+#[allow(unused)]
+pub struct KekRecord {
+    pub label: String,
+    #[serde(deserialize_with = "deserialize_key")]
+    pub kek: [u8; 16],
+    #[serde(default)]
+    pub peers: Vec<String>,                  // NetIDs/AS-IDs that receive the keys wrapped with this KEK
+}
+
+#[derive(Debug, Deserialize)]
+#[allow(unused)]
+pub struct BackendInterfacesConfig {
+    #[serde(default = "default_timeout")]
+    pub timeout: u64,                        // s
+    #[serde(default)]
+    pub join_servers: Vec<JoinServerRecord>, // external Join Servers indexed by JoinEUI
+    #[serde(default)]
+    pub keks: Vec<KekRecord>,                // Key Encryption Keys indexed by KEKLabel
+}
+impl BackendInterfacesConfig {
+    /// The URL of the external Join Server serving a JoinEUI
+    pub fn join_server_url(&self, join_eui: u64) -> Option<&str> {
+        self.join_servers
+            .iter()
+            .find(|js| js.join_eui == join_eui)
+            .map(|js| js.url.as_str())
+    }
+    pub fn kek(&self, label: &str) -> Option<&[u8; 16]> {
+        self.keks
+            .iter()
+            .find(|kek| kek.label == label)
+            .map(|kek| &kek.kek)
+    }
+    /// The KEK (label and key) to wrap the keys sent to a peer, keys are sent in clear if there is none
+    pub fn kek_for_peer(&self, peer_id: &str) -> Option<(&str, &[u8; 16])> {
+        self.keks
+            .iter()
+            .find(|kek| kek.peers.iter().any(|peer| peer.eq_ignore_ascii_case(peer_id)))
+            .map(|kek| (kek.label.as_str(), &kek.kek))
+    }
+}
+impl Default for BackendInterfacesConfig {
+    fn default() -> Self {
+        BackendInterfacesConfig { timeout: default_timeout(), join_servers: Vec::new(), keks: Vec::new() }
+    }
+}
+
+fn default_timeout() -> u64 { 5 }
+
+#[derive(Debug, Deserialize)]
+#[allow(unused)]
+pub struct ServerConfig {
+    pub ns: NsConfig,
+    pub js: JsConfig,
+    #[serde(default)]
+    pub backend_interfaces: BackendInterfacesConfig,
+}
+
+//********************************
+//* devices.yaml
+//********************************
+
+#[derive(Debug, Deserialize, Clone, Copy, PartialEq)]
+pub enum ActivationType {
+    OTAInternalJS,
+    OTA,
+    ABP,
+}
+
+#[derive(Debug, Deserialize)]
+#[allow(unused)]
+pub struct SessionContextRecord {
+    #[serde(default)]
+    pub cipher_id: String,
+    #[serde(default, deserialize_with = "deserialize_opt_key")]
+    pub nwk_s_key: Option<[u8; 16]>,
+    #[serde(default, deserialize_with = "deserialize_opt_key")]
+    pub f_nwk_s_int_key: Option<[u8; 16]>,
+    #[serde(default, deserialize_with = "deserialize_opt_key")]
+    pub s_nwk_s_int_key: Option<[u8; 16]>,
+    #[serde(default, deserialize_with = "deserialize_opt_key")]
+    pub nwk_s_enc_key: Option<[u8; 16]>,
+    #[serde(default, deserialize_with = "deserialize_opt_key")]
+    pub app_s_key: Option<[u8; 16]>,
+    #[serde(default)]
+    pub dev_addr: u32,
+    #[serde(default)]
+    pub f_cnt_up: u32,
+    #[serde(default)]
+    pub f_cnt_down: u32,
+    #[serde(default)]
+    pub n_f_cnt_down: u32,
+    #[serde(default)]
+    pub a_f_cnt_down: u32,
+    #[serde(default)]
+    pub rj_count_02: u16,
+}
+
+#[derive(Debug, Deserialize)]
+#[allow(unused)]
+pub struct NsDeviceRecord {
+    pub x_is_enabled: bool,
+    pub x_dev_addr: Option<u32>,
+    pub x_activation_type: ActivationType,
+    #[serde(default = "default_lora_wan_version")]
+    pub x_lora_wan_version: String,          // sent to external Join Servers as MACVersion
+    #[serde(default)]
+    pub x_join_eui_white_list: Vec<u64>,
+    pub device_profile_id: String,
+    pub service_profile_id: String,
+    pub routing_profile_id: String,
+    pub session_context: Option<SessionContextRecord>,
+}
+
+fn default_lora_wan_version() -> String { "1.0.4".to_string() }
+
+#[derive(Debug, Deserialize)]
+#[allow(unused)]
+pub struct JsDeviceRecord {
+    #[serde(deserialize_with = "deserialize_key")]
+    pub app_key: [u8; 16],
+    #[serde(default, deserialize_with = "deserialize_opt_key")]
+    pub nwk_key: Option<[u8; 16]>,  // LoRaWAN 1.1+ only, AppKey is used as NwkKey if absent
+    pub home_net_id: u32,
+    #[serde(default)]
+    pub as_id: String,
+    pub lora_wan_version: String,
+    #[serde(default)]
+    pub dev_nonce: u16,
+    #[serde(default)]
+    pub rj_count_1: u16,
+    #[serde(default)]
+    pub join_nonce: u32,
+}
+
+#[derive(Debug, Deserialize)]
+#[allow(unused)]
+pub struct DeviceRecord {
+    pub ns: NsDeviceRecord,
+    pub js: Option<JsDeviceRecord>,
+}
+
+//********************************
+//* rf_regions.yaml
+//********************************
+
+#[derive(Debug, Deserialize)]
+#[allow(unused)]
+#[serde(deny_unknown_fields)]
+pub struct SubBandRecord {
+    pub id: u8,
+    pub freq_range: Vec<f64>,       // MHz, [min_freq, max_freq] or a single frequency
+    pub max_duty_cycle: f32,        // %
+    pub max_tx_power: u8,           // dBm EIRP
+    #[serde(default)]
+    pub max_dwell_time: Option<u32>, // ms
+    #[serde(skip)]
+    pub line: usize,                // the line of the definition in the source file
+}
+impl SubBandRecord {
+    /// Whether a frequency (MHz) is in the sub-band
+    pub fn contains(&self, freq: f64) -> bool {
+        match (self.freq_range.first(), self.freq_range.last()) {
+            (Some(min), Some(max)) => freq >= min - 1e-6 && freq <= max + 1e-6,
+            _ => false,
+        }
+    }
+}
+
+#[derive(Debug, Deserialize)]
+#[allow(unused)]
+#[serde(deny_unknown_fields)]
+pub struct ChannelRecord {
+    pub ch_index: u8,
+    pub sub_band: u8,
+    #[serde(rename = "type")]
+    pub ch_type: String,            // default|optional|rx2|class_b_beacon|class_b_ping_slot
+    pub freq: f64,                  // MHz
+    pub dr_range: [u8; 2],
+    #[serde(skip)]
+    pub line: usize,                // the line of the definition in the source file
+}
+
+/// The ADR tuning parameters of an RF region (`ADRv2`/`ADRv3` parameters of the Actility RF region XML)
+#[derive(Debug, Deserialize, Clone)]
+#[allow(unused)]
+#[serde(default)]
+pub struct ADRParams {
+    pub margin_db: f32,              // dB, margin kept above the demodulation floor
+    pub window_size_snr: usize,      // uplinks kept in the SNR history
+    pub initial_window_size: usize,  // uplinks needed before the first adjustment
+    pub sf_min: u8,
+    pub sf_max: u8,
+    pub redundancy: u8,              // the lowest NbTrans
+    pub min_power: u8,               // dBm
+    pub max_power: u8,               // dBm
+    pub hyst_snr: f32,               // dB, hysteresis before the data rate is increased
+    pub target_per: f32,             // packet error rate after repetitions
+    pub macro_div_reliab: f32,       // 0..1, the weight of each additional receiving gateway
+    pub rep_min: u8,                 // NbTrans range of the PER strategy
+    pub rep_max: u8,
+    pub forgetting_factor: f32,      // 0..1, the weight of the last uplink in the PER estimate
+    pub per_hysteresis: f32,         // the data rate is increased below target_per * per_hysteresis
+    pub recovery_sf: u8,             // settings sent when the link collapses
+    pub recovery_tx_power: u8,       // dBm EIRP
+    pub recovery_nb_trans: u8,
+}
+impl Default for ADRParams {
+    fn default() -> Self {
+        ADRParams {
+            margin_db: 10.0,
+            window_size_snr: 50,
+            initial_window_size: 10,
+            sf_min: 7,
+            sf_max: 12,
+            redundancy: 1,
+            min_power: 2,
+            max_power: 14,
+            hyst_snr: 1.0,
+            target_per: 0.1,
+            macro_div_reliab: 0.8,
+            rep_min: 1,
+            rep_max: 3,
+            forgetting_factor: 0.15,
+            per_hysteresis: 0.5,
+            recovery_sf: 12,
+            recovery_tx_power: 16,
+            recovery_nb_trans: 3,
+        }
+    }
+}
+
+#[derive(Debug, Deserialize)]
+#[allow(unused)]
+#[serde(deny_unknown_fields)]
+pub struct RfRegionRecord {
+    pub cf_list_type: u8,           // 0: DynamicChannelList, 1: FixedChannelMask
+    #[serde(default)]
+    pub sub_bands: Vec<SubBandRecord>,
+    #[serde(default)]
+    pub channels: Vec<ChannelRecord>,
+    #[serde(default)]
+    pub enabled_uplink_channels: Vec<[u8; 2]>, // fixed channel plans: [first, last] ch_index ranges
+    #[serde(default)]
+    pub tx_param_setup_req_support: Option<String>, // Yes|No
+    #[serde(default)]
+    pub default_max_eirp: u8,       // dBm
+    #[serde(default)]
+    pub default_rx2_dr: u8,
+    #[serde(default)]
+    pub default_rx2_freq: f64,      // MHz
+    #[serde(default)]
+    pub default_class_b_beacon_freq: Option<f64>,    // MHz
+    #[serde(default)]
+    pub default_class_b_ping_slot_freq: Option<f64>, // MHz
+    #[serde(default)]
+    pub class_b_beacon_dr: Option<u8>,
+    #[serde(default)]
+    pub class_b_beacon_cr: Option<u8>,
+    #[serde(default)]
+    pub data_rates: HashMap<u8, (String, String, u32, u32)>, // DR: (modulation, SF or CR, bandwidth [kHz], bit rate [bit/s])
+    #[serde(default)]
+    pub max_payload_size: HashMap<u8, usize>,   // DR: the maximum MACPayload size (M)
+    #[serde(default)]
+    pub max_payload_size_no_repeater: HashMap<u8, usize>, // DR: M, for end-devices never behind a repeater
+    #[serde(default)]
+    pub dr_next: HashMap<u8, u8>,               // DR: the next data rate of the data rate back-off
+    #[serde(default)]
+    pub rx1_dl_dr: Vec<Vec<u8>>,                // [UpstreamDataRate][RX1DROffset]: the RX1 data rate
+    #[serde(default)]
+    pub tx_power: HashMap<u8, u8>,              // TXPower: dB below MaxEIRP
+    #[serde(default)]
+    pub adr: ADRParams,
+}
+impl RfRegionRecord {
+    /// The data rate index of a LoRa `datr` identifier (e.g. SF7BW125)
+    pub fn data_rate(&self, datr: &str) -> Option<u8> {
+        let (sf, bw) = datr.split_once("BW")?;
+        self.data_rates
+            .iter()
+            .find(|(_, (modu, sf_or_cr, bandwidth, _))| {
+                modu == "lora" && sf_or_cr == sf && bandwidth.to_string() == bw
+            })
+            .map(|(dr, _)| *dr)
+    }
+
+    /// The data rate index of a LoRa spreading factor at 125 kHz
+    pub fn lora_data_rate(&self, sp_fact: u8) -> Option<u8> {
+        self.data_rate(&format!("SF{}BW125", sp_fact))
+    }
+
+    /// The highest TXPower index that keeps the power within `adr.min_power..=adr.max_power`
+    pub fn max_tx_power_index(&self) -> u8 {
+        let power_range = self.adr.max_power.saturating_sub(self.adr.min_power);
+        self.tx_power
+            .iter()
+            .filter(|(_, reduction)| **reduction <= power_range)
+            .map(|(index, _)| *index)
+            .max()
+            .unwrap_or(0)
+    }
+
+    /// The lowest TXPower index that keeps the EIRP at or below `eirp`
+    pub fn tx_power_index(&self, eirp: u8) -> u8 {
+        let reduction = self.default_max_eirp.saturating_sub(eirp);
+        self.tx_power
+            .iter()
+            .filter(|(_, r)| **r >= reduction)
+            .min_by_key(|(_, r)| **r)
+            .map(|(index, _)| *index)
+            .unwrap_or(0)
+    }
+
+    /// The maximum `MACPayload` size (M) at a data rate, the smallest size if it is not defined
+    pub fn max_mac_payload(&self, dr: u8) -> usize {
+        self.max_payload_size.get(&dr).copied().unwrap_or(59)
+    }
+
+    /// The sub-band of a frequency (Hz)
+    pub fn sub_band(&self, freq: u32) -> Option<&SubBandRecord> {
+        self.sub_bands
+            .iter()
+            .find(|sb| sb.contains(freq as f64 / 1_000_000.0))
+    }
+
+    /// The index of the channel with the given frequency
+    pub fn ch_index(&self, freq: u32) -> Option<u8> {
+        self.channels
+            .iter()
+            .find(|ch| (ch.freq * 1_000_000.0).round() as u32 == freq)
+            .map(|ch| ch.ch_index)
+    }
+
+    /// The CFList of the Join-Accept, it carries the channels that are not known by the device by default
+    ///
+    /// The optional channels (ch_index 3..7) are listed for dynamic channel plans,
+    /// the enabled uplink channels are masked for fixed channel plans.
+    ///
+    pub fn cf_list(&self) -> Option<[u8; 16]> {
+        match self.cf_list_type {
+            0 => {
+                let optional_channels = self.optional_channels();
+                if optional_channels.is_empty() {
+                    return None;
+                }
+                let mut freqs = [0_u32; 5];
+                for ch in optional_channels {
+                    freqs[(ch.ch_index - 3) as usize] = (ch.freq * 1_000_000.0).round() as u32;
+                }
+                Some(cf_list::dynamic_channel_list(&freqs))
+            },
+            1 => {
+                let enabled_channels = self.enabled_channels();
+                if enabled_channels.is_empty() {
+                    return None;
+                }
+                Some(cf_list::fixed_channel_mask(&enabled_channels))
+            },
+            _ => None,
+        }
+    }
+
+    /// The optional channels (ch_index 3..7) of a dynamic channel plan, sent in the CFList
+    pub fn optional_channels(&self) -> Vec<&ChannelRecord> {
+        let mut optional_channels: Vec<&ChannelRecord> = self.channels
+            .iter()
+            .filter(|ch| ch.ch_type == "optional" && (3..8).contains(&ch.ch_index))
+            .collect();
+        optional_channels.sort_by_key(|ch| ch.ch_index);
+        optional_channels
+    }
+
+    /// The enabled uplink channels of a fixed channel plan, masked in the CFList
+    pub fn enabled_channels(&self) -> Vec<u8> {
+        self.enabled_uplink_channels
+            .iter()
+            .flat_map(|[first, last]| *first..=*last)
+            .collect()
+    }
+}
+
+//********************************
+//* device_profiles.yaml
+//********************************
+
+#[derive(Debug, Deserialize)]
+#[allow(unused)]
+pub struct DeviceProfile {
+    #[serde(rename = "SupportsClassB", default)]
+    pub supports_class_b: bool,
+    #[serde(rename = "SupportsClassC", default)]
+    pub supports_class_c: bool,
+    #[serde(rename = "MACVersion")]
+    pub mac_version: String,
+    #[serde(rename = "RegParamsRevision", default)]
+    pub reg_params_revision: String,
+    #[serde(rename = "SupportsJoin")]
+    pub supports_join: bool,
+    #[serde(rename = "RFRegion")]
+    pub rf_region: String,
+    #[serde(rename = "Supports32bitFCnt", default)]
+    pub supports_32bit_f_cnt: bool,
+    #[serde(rename = "RXDelay1", default)]
+    pub rx_delay1: Option<u8>,                    // s, mandatory for ABP
+    #[serde(rename = "RXDROffset1", default)]
+    pub rx_dr_offset1: Option<u8>,                // mandatory for ABP
+    #[serde(rename = "RXDataRate2", default)]
+    pub rx_data_rate2: Option<u8>,                // mandatory for ABP
+    #[serde(rename = "RXFreq2", default)]
+    pub rx_freq2: Option<f64>,                    // MHz, mandatory for ABP
+    #[serde(rename = "FactoryPresetFreqs", default)]
+    pub factory_preset_freqs: Option<Vec<f64>>,   // MHz, mandatory for ABP
+}
+
+//********************************
+//* service_profiles.yaml
+//********************************
+
+/// The ADR strategy of the devices of a service profile
+#[derive(Debug, Deserialize, Clone, Copy, PartialEq, Default)]
+pub enum ADRStrategy {
+    #[default]
+    SNRMargin,  // the SNR margin sets the data rate and the TX power
+    TargetPER,  // the packet error rate sets NbTrans and the data rate
+}
+
+#[derive(Debug, Deserialize, Default)]
+#[allow(unused)]
+pub struct ServiceProfile {
+    #[serde(rename = "DevStatusReqFreq", default)]
+    pub dev_status_req_freq: Option<u32>,         // request/day
+    #[serde(rename = "ReportDevStatusBattery", default)]
+    pub report_dev_status_battery: Option<bool>,
+    #[serde(rename = "ReportDevStatusMargin", default)]
+    pub report_dev_status_margin: Option<bool>,
+    #[serde(rename = "DRMin", default)]
+    pub dr_min: Option<u8>,
+    #[serde(rename = "DRMax", default)]
+    pub dr_max: Option<u8>,
+    #[serde(rename = "TargetPER", default)]
+    pub target_per: Option<f32>,                  // overrides adr.target_per of the RF region
+    #[serde(rename = "MinGWDiversity", default)]
+    pub min_gw_diversity: Option<u8>,
+    #[serde(rename = "XADRStrategy", default)]
+    pub x_adr_strategy: ADRStrategy,
+    #[serde(rename = "XADRAckLimitExp", default)]
+    pub x_adr_ack_limit_exp: Option<u8>,          // ADR_ACK_LIMIT = 2^exp, set by ADRParamSetupReq (1.1+)
+    #[serde(rename = "XADRAckDelayExp", default)]
+    pub x_adr_ack_delay_exp: Option<u8>,          // ADR_ACK_DELAY = 2^exp, set by ADRParamSetupReq (1.1+)
+}
+
+//********************************
+//* LorawanConfig
+//********************************
+
+#[derive(Debug)]
+pub struct LorawanConfig {
+    pub server_config: ServerConfig,
+    pub devices: HashMap<u64, DeviceRecord>,            // indexed by DevEUI
+    pub rf_regions: HashMap<String, RfRegionRecord>,    // indexed by the Channel Plan Common Name
+    pub device_profiles: HashMap<String, DeviceProfile>, // indexed by DeviceProfileID
+    pub service_profiles: HashMap<String, ServiceProfile>, // indexed by ServiceProfileID
+}
+impl LorawanConfig {
+    fn new(dir: &str) -> AnyResult<LorawanConfig> {
+        Ok(LorawanConfig {
+            server_config: read_yaml(&format!("{}/server_config.yaml", dir))?,
+            devices: read_yaml(&format!("{}/devices.yaml", dir))?,
+            rf_regions: rf_region_loader::read_rf_regions(dir)?,
+            device_profiles: read_yaml(&format!("{}/profiles/device_profiles.yaml", dir))?,
+            service_profiles: read_yaml(&format!("{}/profiles/service_profiles.yaml", dir))?,
+        })
+    }
+
+    /// The RF region of a device, according to its device profile
+    pub fn rf_region(&self, device_record: &DeviceRecord) -> Option<&RfRegionRecord> {
+        let device_profile = self.device_profile(device_record)?;
+        self.rf_regions
+            .iter()
+            .find(|(name, _)| name.eq_ignore_ascii_case(&device_profile.rf_region))
+            .map(|(_, rf_region)| rf_region)
+    }
+
+    /// The RP002 regional parameters of a device, according to the RFRegion of its device profile
+    pub fn region(&self, device_record: &DeviceRecord) -> Option<Region> {
+        Region::from_name(&self.device_profile(device_record)?.rf_region)
+    }
+
+    /// The device profile of a device
+    pub fn device_profile(&self, device_record: &DeviceRecord) -> Option<&DeviceProfile> {
+        self.device_profiles.get(&device_record.ns.device_profile_id)
+    }
+
+    /// The service profile of a device
+    pub fn service_profile(&self, device_record: &DeviceRecord) -> Option<&ServiceProfile> {
+        self.service_profiles.get(&device_record.ns.service_profile_id)
+    }
+
+    /// The CFList to be sent to a device in Join-Accepts
+    pub fn cf_list(&self, device_record: &DeviceRecord) -> Option<[u8; 16]> {
+        self.rf_region(device_record)?.cf_list()
+    }
+}
+
+fn read_yaml<T: for<'de> Deserialize<'de>>(path: &str) -> AnyResult<T> {
+    let text = fs::read_to_string(path)
+        .map_err(|e| anyhow!("{}: {}", path, e))?;
+    serde_yaml::from_str(&text)
+        .map_err(|e| anyhow!("{}: {}", path, e))
+}
+
+/// Parses a key of the device registry, unwrapping it with the master KEK if it is stored wrapped
+pub fn registry_key_from_string(s: &str) -> AnyResult<[u8; 16]> {
+    let master_kek = &settings::get_or_init().key_store.master_kek;
+    if master_kek.is_empty() {
+        key_from_string_with_kek(s, None)
+    } else {
+        key_from_string_with_kek(s, Some(&key_from_string(master_kek)?))
+    }
+}
+
+/// Formats a key to be stored, wrapped with the master KEK if one is configured
+pub fn registry_key_to_string(key: &[u8; 16]) -> AnyResult<String> {
+    let master_kek = &settings::get_or_init().key_store.master_kek;
+    if master_kek.is_empty() {
+        Ok(hex::encode(key))
+    } else {
+        Ok(hex::encode(wrap_key(&key_from_string(master_kek)?, key)))
+    }
+}
+
+fn deserialize_key<'de, D>(deserializer: D) -> Result<[u8; 16], D::Error>
+where D: Deserializer<'de> {
+    let s = String::deserialize(deserializer)?;
+    registry_key_from_string(&s).map_err(serde::de::Error::custom)
+}
+
+fn deserialize_opt_key<'de, D>(deserializer: D) -> Result<Option<[u8; 16]>, D::Error>
+where D: Deserializer<'de> {
+    match Option::<String>::deserialize(deserializer)? {
+        Some(s) => registry_key_from_string(&s).map(Some).map_err(serde::de::Error::custom),
+        None => Ok(None),
+    }
+}
+
+
+pub static LORAWAN_CONFIG: OnceLock<LorawanConfig> = OnceLock::new();
+
+pub fn get_or_init() -> &'static LorawanConfig {
+    LORAWAN_CONFIG.get_or_init(|| {
+        let settings = settings::get_or_init();
+        LorawanConfig::new(&settings.lorawan_config.dir).unwrap_or_else(|e| {
+            eprintln!("error: {:?}", e);
+            process::exit(1);
+        })
+    })
+}
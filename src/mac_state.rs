@@ -0,0 +1,334 @@
+use std::time::{ Duration, Instant, SystemTime };
+
+use crate::{
+    devctx::{self, DeviceContext, DevStatus},
+    lorawan::mac_cmds::{self, DownlinkMACCmd, UplinkMACCmd, PackedMACCmds},
+};
+
+/// The number of times an unanswered request is sent again before it is dropped
+pub const MAX_MAC_CMD_RETRIES: u8 = 3;
+
+/// A request sent to the device, waiting for its answer
+#[derive(Debug, Clone)]
+pub struct PendingMACCmd {
+    pub cmd: DownlinkMACCmd,
+    pub retries: u8,         // the number of times it has been sent again
+}
+
+/// The MAC command state of a device
+///
+/// Commands are queued until a downlink carries them. Requests are then kept pending until
+/// the device answers; a request that is still unanswered at the next downlink is sent again.
+///
+#[derive(Debug, Clone, Default)]
+pub struct MACState {
+    pub queue: Vec<DownlinkMACCmd>,   // answers and requests waiting for a downlink
+    pub pending: Vec<PendingMACCmd>,  // requests sent, waiting for their answers
+    pub downlink_required: bool,      // a sticky answer was received, a downlink stops its repetition
+    pub dev_status_req_at: Option<Instant>, // the last time DevStatusReq was queued
+}
+impl MACState {
+
+    /// Queues a command, a request replaces the queued request with the same target
+    pub fn enqueue(&mut self, cmd: DownlinkMACCmd) {
+        if !cmd.is_answer() {
+            self.queue.retain(|queued| !same_target(queued, &cmd));
+        }
+        self.queue.push(cmd);
+    }
+
+    /// Whether a request with the same target is queued or pending
+    pub fn is_requested(&self, cmd: &DownlinkMACCmd) -> bool {
+        self.queue.iter().any(|queued| same_target(queued, cmd))
+            || self.pending.iter().any(|pending| same_target(&pending.cmd, cmd))
+    }
+
+    /// Queues DevStatusReq if the period of the service profile has elapsed
+    ///
+    /// # Arguments
+    ///
+    /// * __`dev_status_req_freq`__\
+    ///   `DevStatusReqFreq` of the service profile (request/day), 0 disables the requests \
+    ///
+    pub fn schedule_dev_status_req(&mut self, dev_status_req_freq: u32) {
+        if dev_status_req_freq == 0 || self.is_requested(&DownlinkMACCmd::DevStatusReq) {
+            return;
+        }
+        let period = Duration::from_secs(86_400) / dev_status_req_freq;
+        if self.dev_status_req_at.is_some_and(|at| at.elapsed() < period) {
+            return;
+        }
+        self.enqueue(DownlinkMACCmd::DevStatusReq);
+        self.dev_status_req_at = Some(Instant::now());
+    }
+
+    /// Takes the commands of the next downlink
+    ///
+    /// The queued commands are packed together with the unanswered requests. The packed
+    /// requests become pending, the ones that do not fit stay queued.
+    ///
+    pub fn take_for_downlink(&mut self, max_mac_payload: usize, app_payload_len: Option<usize>) -> PackedMACCmds {
+
+        self.pending.retain(|pending| {
+            if pending.retries >= MAX_MAC_CMD_RETRIES {
+                log::warn!("{:?} is dropped, no answer after {} retries", pending.cmd.cid(), pending.retries);
+                return false;
+            }
+            true
+        });
+
+        let mut cmds = std::mem::take(&mut self.queue);
+        cmds.extend(self.pending.iter().map(|pending| pending.cmd.clone()));
+
+        let packed = mac_cmds::pack(&cmds, max_mac_payload, app_payload_len);
+
+        for cmd in packed.packed.iter().filter(|cmd| !cmd.is_answer()) {
+            match self.pending.iter_mut().find(|pending| pending.cmd == *cmd) {
+                Some(pending) => pending.retries += 1,
+                None => self.pending.push(PendingMACCmd { cmd: cmd.clone(), retries: 0 }),
+            }
+        }
+        self.queue = packed.left_over
+            .iter()
+            .filter(|cmd| !self.pending.iter().any(|pending| pending.cmd == **cmd))
+            .cloned()
+            .collect();
+        self.downlink_required = false;
+
+        packed
+
+    }
+
+    /// Removes the oldest pending request answered by `ans`
+    fn take_pending(&mut self, ans: &UplinkMACCmd) -> Option<DownlinkMACCmd> {
+        let cid = ans.cid() as u8;
+        let i = self.pending.iter().position(|pending| pending.cmd.cid() as u8 == cid)?;
+        Some(self.pending.remove(i).cmd)
+    }
+
+}
+
+/// Commands addressing the same parameter, e.g. NewChannelReq for the same channel
+fn same_target(a: &DownlinkMACCmd, b: &DownlinkMACCmd) -> bool {
+    match (a, b) {
+        (DownlinkMACCmd::NewChannelReq { ch_index: x, .. }, DownlinkMACCmd::NewChannelReq { ch_index: y, .. }) |
+        (DownlinkMACCmd::DlChannelReq { ch_index: x, .. }, DownlinkMACCmd::DlChannelReq { ch_index: y, .. }) => x == y,
+        _ => a.cid() as u8 == b.cid() as u8,
+    }
+}
+
+/// Applies the answers of an uplink to the requests pending for a device
+///
+/// The parameters of an acknowledged request are committed to the device context.
+/// RXParamSetupAns, RXTimingSetupAns and DlChannelAns are repeated by the device until
+/// it receives a downlink, so a downlink is required after them.
+///
+/// Returns the answered requests with their answers.
+///
+/// # Specification
+///
+/// LoRaWAN L2 1.0.4 - line #1068 \
+/// 5 MAC Commands                \
+///
+pub fn handle_answers(dev_eui: u64, mac_cmds: &[UplinkMACCmd]) -> Vec<(DownlinkMACCmd, UplinkMACCmd)> {
+    devctx::update_device_context(dev_eui, |ctx| apply_answers(ctx, mac_cmds))
+        .unwrap_or_default()
+}
+
+fn apply_answers(ctx: &mut DeviceContext, mac_cmds: &[UplinkMACCmd]) -> Vec<(DownlinkMACCmd, UplinkMACCmd)> {
+
+    let mut answered = Vec::new();
+
+    for ans in mac_cmds {
+
+        if let UplinkMACCmd::RXParamSetupAns { .. } | UplinkMACCmd::RXTimingSetupAns | UplinkMACCmd::DlChannelAns { .. } = ans {
+            ctx.mac_state_mut().downlink_required = true;
+        }
+
+        // Repeated sticky answers and answers to unknown requests are ignored
+        let Some(req) = ctx.mac_state_mut().take_pending(ans) else {
+            continue;
+        };
+
+        match (&req, ans) {
+            (
+                DownlinkMACCmd::LinkADRReq { data_rate, tx_power, ch_mask, ch_mask_cntl, nb_trans },
+                UplinkMACCmd::LinkADRAns { power_ack: true, data_rate_ack: true, channel_mask_ack: true },
+            ) => {
+                // 0xF (DataRate, TXPower) and 0 (NbTrans) keep the current value
+                let mac_params = ctx.mac_params_mut();
+                if *data_rate != 0x0f {
+                    mac_params.data_rate = *data_rate;
+                }
+                if *tx_power != 0x0f {
+                    mac_params.tx_power = *tx_power;
+                }
+                if *nb_trans != 0 {
+                    mac_params.nb_trans = *nb_trans;
+                }
+                // ChMaskCntl 0 applies ChMask to the channels 0..15, the other values keep the
+                // active channels of fixed channel plans (adr::link_adr_ch_mask)
+                if *ch_mask_cntl == 0 {
+                    ctx.active_channels_mut().retain(|ch_index, _| *ch_index >= 16 || ch_mask & 1 << ch_index != 0);
+                }
+                ctx.adr_state_mut().handle_link_adr_ans(&req, ans);
+            },
+            (DownlinkMACCmd::LinkADRReq { .. }, UplinkMACCmd::LinkADRAns { .. }) => {
+                log::warn!("{:?} is rejected with {:?}", req, ans);
+                ctx.adr_state_mut().handle_link_adr_ans(&req, ans);
+            },
+            (DownlinkMACCmd::DutyCycleReq { max_duty_cycle }, UplinkMACCmd::DutyCycleAns) => {
+                ctx.mac_params_mut().max_duty_cycle = *max_duty_cycle;
+            },
+            (
+                DownlinkMACCmd::RXParamSetupReq { rx1_dr_offset, rx2_data_rate, freq },
+                UplinkMACCmd::RXParamSetupAns { rx1_dr_offset_ack: true, rx2_data_rate_ack: true, channel_ack: true },
+            ) => {
+                let rx_params = ctx.rx_params_mut();
+                rx_params.rx1_dr_offset = *rx1_dr_offset;
+                rx_params.rx2_dr = *rx2_data_rate;
+                rx_params.rx2_freq = *freq;
+            },
+            (
+                DownlinkMACCmd::NewChannelReq { ch_index, freq, max_dr, min_dr },
+                UplinkMACCmd::NewChannelAns { data_rate_range_ok: true, channel_freq_ok: true },
+            ) => {
+                if *freq == 0 {
+                    ctx.active_channels_mut().remove(ch_index);
+                } else {
+                    ctx.active_channels_mut().insert(*ch_index, (*freq, max_dr << 4 | min_dr));
+                }
+            },
+            (DownlinkMACCmd::RXTimingSetupReq { delay }, UplinkMACCmd::RXTimingSetupAns) => {
+                ctx.rx_params_mut().rx1_delay = (*delay).max(1);
+            },
+            (
+                DownlinkMACCmd::TXParamSetupReq { downlink_dwell_time, uplink_dwell_time, max_eirp },
+                UplinkMACCmd::TXParamSetupAns,
+            ) => {
+                let mac_params = ctx.mac_params_mut();
+                mac_params.downlink_dwell_time = *downlink_dwell_time;
+                mac_params.uplink_dwell_time = *uplink_dwell_time;
+                mac_params.max_eirp = *max_eirp;
+            },
+            (
+                DownlinkMACCmd::DlChannelReq { ch_index, freq },
+                UplinkMACCmd::DlChannelAns { uplink_freq_exists: true, channel_freq_ok: true },
+            ) => {
+                ctx.rx_params_mut().rx1_dl_freqs.insert(*ch_index, *freq);
+            },
+            (DownlinkMACCmd::DevStatusReq, UplinkMACCmd::DevStatusAns { battery, margin }) => {
+                *ctx.dev_status_mut() = Some(DevStatus {
+                    battery: *battery,
+                    margin: *margin,
+                    received_at: SystemTime::now(),
+                });
+            },
+            (DownlinkMACCmd::ADRParamSetupReq { limit_exp, delay_exp }, UplinkMACCmd::ADRParamSetupAns) => {
+                let mac_params = ctx.mac_params_mut();
+                mac_params.adr_ack_limit_exp = Some(*limit_exp);
+                mac_params.adr_ack_delay_exp = Some(*delay_exp);
+            },
+            _ => {
+                log::warn!("{:?} is rejected with {:?}", req, ans);
+            },
+        }
+
+        answered.push((req, ans.clone()));
+
+    }
+
+    answered
+
+}
+
+
+#[cfg(test)]
+mod tests {
+
+    use super::*;
+    use crate::devctx::DeviceContextV10x;
+
+    #[test]
+    fn test_request_answer_and_retry() {
+
+        let mut ctx = DeviceContext::V10x(DeviceContextV10x::default());
+        let mac_state = ctx.mac_state_mut();
+        mac_state.enqueue(DownlinkMACCmd::RXTimingSetupReq { delay: 3 });
+        mac_state.enqueue(DownlinkMACCmd::DevStatusReq);
+
+        let packed = mac_state.take_for_downlink(59, None);
+        assert_eq!(packed.bytes, vec![0x08, 0x03, 0x06]);
+        assert_eq!(mac_state.pending.len(), 2);
+
+        // only RXTimingSetupReq is answered, DevStatusReq is sent again
+        let answered = apply_answers(&mut ctx, &[UplinkMACCmd::RXTimingSetupAns]);
+        assert_eq!(answered.len(), 1);
+        let DeviceContext::V10x(ctx_10) = &ctx else { unreachable!() };
+        assert_eq!(ctx_10.rx_params.rx1_delay, 3);
+        assert!(ctx_10.mac_state.downlink_required);
+
+        let mac_state = ctx.mac_state_mut();
+        let packed = mac_state.take_for_downlink(59, None);
+        assert_eq!(packed.bytes, vec![0x06]);
+        assert!(!mac_state.downlink_required);
+
+        // the repeated sticky answer does not match a request anymore
+        assert!(apply_answers(&mut ctx, &[UplinkMACCmd::RXTimingSetupAns]).is_empty());
+
+        // the unanswered request is dropped after MAX_MAC_CMD_RETRIES
+        let mac_state = ctx.mac_state_mut();
+        for _ in 0..MAX_MAC_CMD_RETRIES {
+            mac_state.take_for_downlink(59, None);
+        }
+        assert!(mac_state.take_for_downlink(59, None).bytes.is_empty());
+        assert!(mac_state.pending.is_empty());
+
+    }
+
+    #[test]
+    fn test_dev_status_req() {
+
+        let mut ctx = DeviceContext::V10x(DeviceContextV10x::default());
+        let mac_state = ctx.mac_state_mut();
+        mac_state.schedule_dev_status_req(24);
+        mac_state.schedule_dev_status_req(24);
+        assert_eq!(mac_state.queue, vec![DownlinkMACCmd::DevStatusReq]);
+
+        // not requested again within the period, even after the answer
+        mac_state.take_for_downlink(59, None);
+        let answer = UplinkMACCmd::DevStatusAns { battery: mac_cmds::Battery::Level(127), margin: 10 };
+        assert_eq!(apply_answers(&mut ctx, &[answer]).len(), 1);
+        let mac_state = ctx.mac_state_mut();
+        mac_state.schedule_dev_status_req(24);
+        assert!(mac_state.queue.is_empty());
+        assert_eq!(ctx.dev_status_mut().as_ref().map(|s| s.margin), Some(10));
+
+    }
+
+    #[test]
+    fn test_link_adr_ans() {
+
+        let mut ctx = DeviceContext::V10x(DeviceContextV10x::default());
+        ctx.mac_params_mut().data_rate = 2;
+        ctx.mac_params_mut().nb_trans = 1;
+        for ch_index in 0..4 {
+            ctx.active_channels_mut().insert(ch_index, (868_100_000 + ch_index as u32 * 200_000, 0x50));
+        }
+
+        // DataRate is changed, TXPower and NbTrans are kept, channel 3 is disabled
+        let req = DownlinkMACCmd::LinkADRReq { data_rate: 5, tx_power: 0x0f, ch_mask: 0x0007, ch_mask_cntl: 0, nb_trans: 0 };
+        ctx.mac_state_mut().enqueue(req);
+        ctx.mac_state_mut().take_for_downlink(59, None);
+        let ans = UplinkMACCmd::LinkADRAns { power_ack: true, data_rate_ack: true, channel_mask_ack: true };
+        assert_eq!(apply_answers(&mut ctx, &[ans]).len(), 1);
+
+        let mac_params = ctx.mac_params();
+        assert_eq!((mac_params.data_rate, mac_params.tx_power, mac_params.nb_trans), (5, 0, 1));
+        let mut active_channels: Vec<u8> = ctx.active_channels().keys().copied().collect();
+        active_channels.sort();
+        assert_eq!(active_channels, vec![0, 1, 2]);
+
+    }
+
+}
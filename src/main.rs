@@ -1,5 +1,6 @@
 use std::{ 
-    str, net, thread
+    str, net, thread,
+    time::SystemTime,
 };
 
 // use log::{ info, warn, error, debug, trace };
@@ -8,7 +9,8 @@ use std::{
 use anyhow::Result as AnyResult;
 
 use lws::{ 
-    settings, logger, pktf, dd_cache,
+    settings, logger, pktf, dd_cache, devctx, downlink, lorawan_config, scheduler, app_server_api,
+    join_server::{jsctx, http_server},
     settings::Settings, 
     handle_rx_packet::handle_rx_packet,
 };
@@ -22,9 +24,32 @@ async fn main() -> AnyResult<()> {
 
     log::debug!("{:?}", settings);
 
+    lorawan_config::get_or_init();
+
     dd_cache::init_dd_cache();
 
-    udp_server(settings).await?;
+    devctx::init_db();
+
+    jsctx::init_js_db(&settings.join_server.nonce_file);
+
+    let js_task = if settings.roles.join_server {
+        Some(tokio::spawn(http_server::run(&settings.join_server.addr)))
+    } else {
+        None
+    };
+
+    if settings.roles.network_server {
+        tokio::spawn(async {
+            if let Err(e) = app_server_api::run(&settings.remote_application_server.downlink_addr).await {
+                log::error!("Downlink API error: {:?}", e);
+            }
+        });
+        udp_server(settings).await?;
+    }
+
+    if let Some(js_task) = js_task {
+        js_task.await??;
+    }
 
     Ok(())
 
@@ -33,6 +58,7 @@ async fn main() -> AnyResult<()> {
 async fn udp_server(settings: &Settings) -> AnyResult<()> {
 
     let socket = net::UdpSocket::bind(&settings.udp_server.addr).expect("UdpSocket::bind() must work");
+    downlink::init_downlink(&socket);
     let mut buf = [0; 1024];
     loop {
 
@@ -118,16 +144,20 @@ async fn udp_server(settings: &Settings) -> AnyResult<()> {
                         thread::spawn(move || {
 
                             
-                            let sp_fact = if &rx_packet.datr[3..4] == "B" { &rx_packet.datr[..3] } else { &rx_packet.datr[..4] };
+                            let sp_fact = if &rx_packet.datr[3..4] == "B" { &rx_packet.datr[2..3] } else { &rx_packet.datr[2..4] };
                             let sp_fact = sp_fact.parse::<u8>().unwrap();
 
                             let dd_data = dd_cache::DDData {
                                 gw_eui: gw_eui,
+                                tmst: rx_packet.tmst,
                                 freq: rx_packet.freq,
                                 sp_fact,
                                 rssi: rx_packet.rssi,
-                                snr: rx_packet.lsnr
+                                snr: rx_packet.lsnr,
+                                tmms: rx_packet.tmms,
+                                rx_time: SystemTime::now(),
                             };
+                            downlink::set_gw_clock(gw_eui, dd_data.tmst as u32, dd_data.rx_time);
 
                             let is_first = dd_cache::add_data(dd_data, &rx_packet.data);
                             if !is_first { return };
@@ -154,6 +184,8 @@ async fn udp_server(settings: &Settings) -> AnyResult<()> {
                 ack_msg[3] = pktf::MType::PullAck as u8;
                 socket.send_to(ack_msg, &addr).expect("socket.send_to() must always work");
 
+                downlink::set_gw_pull_addr(gw_eui, addr);
+
                 log::trace!(
                     "PULL_DATA received from Gateway: x{:16x} IP: {} Port: {} Data: {}",
                     gw_eui, &addr.ip(), &addr.port(), hex::encode(&buf),
@@ -165,6 +197,17 @@ async fn udp_server(settings: &Settings) -> AnyResult<()> {
                     "TX_ACK received from Gateway: x{:16x} IP:{} Port:{} Data: {}",
                     &gw_eui, &addr.ip(), &addr.port(), hex::encode(&buf),
                 );
+
+                let token = u16::from_be_bytes([buf[1], buf[2]]);
+                let tx_ack: Option<pktf::TxAck> = str::from_utf8(&buf[12..n])
+                    .ok()
+                    .and_then(|s| serde_json::from_str(s).ok());
+                if let Some(txpk_ack) = tx_ack.and_then(|tx_ack| tx_ack.txpk_ack) {
+                    if !txpk_ack.error.is_empty() && txpk_ack.error != "NONE" {
+                        scheduler::handle_tx_ack_error(gw_eui, token, &txpk_ack.error);
+                        downlink::handle_tx_ack_error(gw_eui, token);
+                    }
+                }
             },
 
             // DOWNLINK MESSAGES that are always invalid...
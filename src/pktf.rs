@@ -1,4 +1,4 @@
-use serde::Deserialize;
+use serde::{Deserialize, Serialize};
 
 use anyhow::{ Result as AnyResult, anyhow };
 
@@ -133,3 +133,50 @@ pub struct PushData {
     #[serde(default)]
 	pub stat: Option<Stat>,
 }
+
+//********************************
+//* TXPacket
+//********************************
+
+#[derive(Debug, Serialize, Default)]
+pub struct TXPacket {
+	pub imme: bool,   // | bool   | Send packet immediately (will ignore tmst & time)
+	#[serde(skip_serializing_if = "Option::is_none")]
+	pub tmst: Option<u32>, // | number | Send packet on a certain timestamp value (will ignore time)
+	#[serde(skip_serializing_if = "Option::is_none")]
+	pub tmms: Option<i64>, // | number | Send packet at a certain GPS time (GPS synchronization required)
+	pub freq: f32,    // | number | TX central frequency in MHz (unsigned float, Hz precision)
+	pub rfch: u8,     // | number | Concentrator "RF chain" used for TX (unsigned integer)
+	pub powe: i8,     // | number | TX output power in dBm (unsigned integer, dBm precision)
+	pub modu: String, // | string | Modulation identifier "LORA" or "FSK"
+	pub datr: String, // | string | LoRa datarate identifier (eg. SF12BW500)
+	pub codr: String, // | string | LoRa ECC coding rate identifier
+	pub ipol: bool,   // | bool   | Lora modulation polarization inversion
+	pub size: u16,    // | number | RF packet payload size in bytes (unsigned integer)
+	pub data: String, // | string | Base64 encoded RF packet payload, padding optional
+}
+
+//********************************
+//* PullResp
+//********************************
+
+#[derive(Debug, Serialize)]
+pub struct PullResp {
+	pub txpk: TXPacket,
+}
+
+//********************************
+//* TxAck
+//********************************
+
+#[derive(Debug, Deserialize)]
+pub struct TxAck {
+    #[serde(default)]
+	pub txpk_ack: Option<TxPkAck>,
+}
+
+#[derive(Debug, Deserialize)]
+pub struct TxPkAck {
+    #[serde(default)]
+	pub error: String, // | string | NONE, TOO_LATE, TOO_EARLY, COLLISION_PACKET, COLLISION_BEACON, TX_FREQ, TX_POWER, GPS_UNLOCKED
+}
@@ -0,0 +1,442 @@
+use std::{
+    fs,
+    collections::{HashMap, HashSet},
+    str::FromStr,
+};
+use anyhow::{ Result as AnyResult, anyhow };
+use roxmltree::{Document, Node};
+
+use crate::{
+    lorawan_config::{ADRParams, ChannelRecord, RfRegionRecord, SubBandRecord},
+    lorawan::{
+        data_rate::DataRate,
+        region::Region,
+    },
+};
+
+const RX2_CH_INDEX: u8 = 252;
+const CLASS_B_BEACON_CH_INDEX: u8 = 253;
+const CLASS_B_PING_SLOT_CH_INDEX: u8 = 254;
+
+const RX_CHANNEL_TAGS: [&str; 9] = ["ChIndex", "IsDefault", "LC", "Frequency", "SB", "DTC", "MinDR", "MaxDR", "LRR_power"];
+const TX_CHANNEL_TAGS: [&str; 9] = ["LC", "UsedForBeacon", "UsedForPingSlot", "Frequency", "SB", "DTC", "MinDR", "MaxDR", "LRR_power"];
+
+/// Reads the RF regions of a LoRaWAN config directory, indexed by the Channel Plan Common Name
+///
+/// The regions of `rf_regions.yaml` are completed with the Actility RF region XML files
+/// (`rf_region_<name>_*.xml`) of the directory. A region defined in both is taken from the YAML file.
+/// Every region is validated, errors are reported with the file and the line of the definition.
+///
+pub fn read_rf_regions(dir: &str) -> AnyResult<HashMap<String, RfRegionRecord>> {
+
+    let mut rf_regions = read_rf_regions_yaml(&format!("{}/rf_regions.yaml", dir))?;
+
+    let mut xml_paths: Vec<String> = fs::read_dir(dir)
+        .map_err(|e| anyhow!("{}: {}", dir, e))?
+        .filter_map(|entry| entry.ok()?.file_name().into_string().ok())
+        .filter(|file_name| file_name.starts_with("rf_region_") && file_name.ends_with(".xml"))
+        .map(|file_name| format!("{}/{}", dir, file_name))
+        .collect();
+    xml_paths.sort();
+
+    for path in xml_paths {
+        let (name, rf_region) = read_rf_region_xml(&path)?;
+        if !rf_regions.keys().any(|k| k.eq_ignore_ascii_case(&name)) {
+            rf_regions.insert(name, rf_region);
+        }
+    }
+
+    Ok(rf_regions)
+
+}
+
+/// Reads and validates the RF regions of a YAML file
+pub fn read_rf_regions_yaml(path: &str) -> AnyResult<HashMap<String, RfRegionRecord>> {
+
+    let text = fs::read_to_string(path)
+        .map_err(|e| anyhow!("{}: {}", path, e))?;
+
+    let mut rf_regions: HashMap<String, RfRegionRecord> = serde_yaml::from_str(&text)
+        .map_err(|e| match e.location() {
+            Some(location) => anyhow!("{}:{}: {}", path, location.line(), e),
+            None => anyhow!("{}: {}", path, e),
+        })?;
+
+    for (name, rf_region) in rf_regions.iter_mut() {
+        for (sub_band, line) in rf_region.sub_bands.iter_mut().zip(yaml_list_item_lines(&text, name, "sub_bands")) {
+            sub_band.line = line;
+        }
+        for (ch, line) in rf_region.channels.iter_mut().zip(yaml_list_item_lines(&text, name, "channels")) {
+            ch.line = line;
+        }
+        validate(path, name, rf_region)?;
+    }
+
+    Ok(rf_regions)
+
+}
+
+/// The line numbers of the items of a list under a top-level key of a YAML file
+fn yaml_list_item_lines(text: &str, top_level_key: &str, list_key: &str) -> Vec<usize> {
+
+    let mut lines = Vec::new();
+    let mut in_top_level = false;
+    let mut list_indent: Option<usize> = None;
+
+    for (i, line) in text.lines().enumerate() {
+        let trimmed = line.trim_start();
+        if trimmed.is_empty() || trimmed.starts_with('#') {
+            continue;
+        }
+        let indent = line.len() - trimmed.len();
+        if indent == 0 {
+            in_top_level = trimmed.split(':').next() == Some(top_level_key);
+            list_indent = None;
+            continue;
+        }
+        if !in_top_level {
+            continue;
+        }
+        match list_indent {
+            Some(list_indent) if indent <= list_indent && !trimmed.starts_with('-') => break,
+            Some(_) if trimmed.starts_with("- ") || trimmed == "-" => lines.push(i + 1),
+            Some(_) => (),
+            None if trimmed.split(':').next() == Some(list_key) => list_indent = Some(indent),
+            None => (),
+        }
+    }
+
+    lines
+
+}
+
+/// Reads and validates an Actility RF region XML file, the region is named after the file
+///
+/// The name is to be a Regional Parameter Channel Plan Common Name, the tables missing from
+/// the XML (data rates, payload sizes, TX power) and the frequency band of the sub-bands are
+/// taken from its definition.
+///
+pub fn read_rf_region_xml(path: &str) -> AnyResult<(String, RfRegionRecord)> {
+
+    let name = path
+        .rsplit('/')
+        .next()
+        .and_then(|file_name| file_name.strip_prefix("rf_region_"))
+        .and_then(|s| s.split(['_', '.']).next())
+        .ok_or_else(|| anyhow!("{}: the file name is not rf_region_<name>_*.xml", path))?
+        .to_string();
+    let region = Region::from_name(&name)
+        .ok_or_else(|| anyhow!("{}: unknown region: {}", path, name))?;
+
+    let text = fs::read_to_string(path)
+        .map_err(|e| anyhow!("{}: {}", path, e))?;
+    let doc = Document::parse(&text)
+        .map_err(|e| anyhow!("{}:{}: {}", path, e.pos().row, e))?;
+    let xml = XmlReader { path, doc: &doc };
+    let root = doc.root_element();
+
+    let max_eirp: u8 = xml.value(root, "MaxEIRP")?.unwrap_or(region.default_max_eirp() as u8);
+
+    let mut rf_region = RfRegionRecord {
+        cf_list_type: region.is_fixed_plan() as u8,
+        sub_bands: Vec::new(),
+        channels: Vec::new(),
+        enabled_uplink_channels: Vec::new(),
+        tx_param_setup_req_support: None,
+        default_max_eirp: max_eirp,
+        default_rx2_dr: xml.value(root, "RX2DataRate")?.unwrap_or(0),
+        default_rx2_freq: xml.value(root, "RX2Freq")?.unwrap_or(0.0),
+        default_class_b_beacon_freq: None,
+        default_class_b_ping_slot_freq: None,
+        class_b_beacon_dr: None,
+        class_b_beacon_cr: None,
+        data_rates: HashMap::new(),
+        max_payload_size: HashMap::new(),
+        max_payload_size_no_repeater: HashMap::new(),
+        dr_next: HashMap::new(),
+        rx1_dl_dr: Vec::new(),
+        tx_power: HashMap::new(),
+        adr: xml.adr_params(root)?,
+    };
+
+    // Sub-bands: (id, duty cycle, max power)
+    let mut sub_bands: Vec<(u8, f32, u8)> = Vec::new();
+    let mut add_to_sub_band = |id: u8, duty_cycle: f32, max_tx_power: u8| {
+        if !sub_bands.iter().any(|(sb_id, ..)| *sb_id == id) {
+            sub_bands.push((id, duty_cycle, max_tx_power));
+        }
+    };
+
+    for node in xml.elements(root, "RxChannels", "RxChannel") {
+        xml.check_tags(node, &RX_CHANNEL_TAGS)?;
+        let ch = ChannelRecord {
+            ch_index: xml.required(node, "ChIndex")?,
+            sub_band: xml.required(node, "SB")?,
+            ch_type: if xml.value(node, "IsDefault")? == Some(1_u8) { "default" } else { "optional" }.to_string(),
+            freq: xml.required(node, "Frequency")?,
+            dr_range: [xml.required(node, "MinDR")?, xml.required(node, "MaxDR")?],
+            line: xml.line(node),
+        };
+        add_to_sub_band(ch.sub_band, xml.value(node, "DTC")?.unwrap_or(1.0), max_eirp);
+        rf_region.channels.push(ch);
+    }
+
+    if let Some(rx2_sub_band) = xml.value::<u8>(root, "RX2SB")? {
+        let rx2_tx_power = xml.value(root, "RX2TxPower")?.unwrap_or(max_eirp);
+        add_to_sub_band(rx2_sub_band, xml.value(root, "RX2DTC")?.unwrap_or(1.0), rx2_tx_power);
+        rf_region.channels.push(ChannelRecord {
+            ch_index: RX2_CH_INDEX,
+            sub_band: rx2_sub_band,
+            ch_type: "rx2".to_string(),
+            freq: rf_region.default_rx2_freq,
+            dr_range: [0, rf_region.default_rx2_dr],
+            line: root.children().find(|n| n.has_tag_name("RX2SB")).map_or(0, |n| xml.line(n)),
+        });
+    }
+
+    for node in xml.elements(root, "TxChannels", "TxChannel") {
+        xml.check_tags(node, &TX_CHANNEL_TAGS)?;
+        let (ch_index, ch_type) = if xml.value(node, "UsedForBeacon")? == Some(1_u8) {
+            (CLASS_B_BEACON_CH_INDEX, "class_b_beacon")
+        } else if xml.value(node, "UsedForPingSlot")? == Some(1_u8) {
+            (CLASS_B_PING_SLOT_CH_INDEX, "class_b_ping_slot")
+        } else {
+            return Err(anyhow!("{}:{}: TxChannel is neither used for beacons nor for ping slots", path, xml.line(node)));
+        };
+        let ch = ChannelRecord {
+            ch_index,
+            sub_band: xml.required(node, "SB")?,
+            ch_type: ch_type.to_string(),
+            freq: xml.required(node, "Frequency")?,
+            dr_range: [xml.value(node, "MinDR")?.unwrap_or(0), xml.required(node, "MaxDR")?],
+            line: xml.line(node),
+        };
+        let lrr_power: i8 = xml.value(node, "LRR_power")?.unwrap_or(0);
+        add_to_sub_band(ch.sub_band, xml.value(node, "DTC")?.unwrap_or(1.0), max_eirp.saturating_add_signed(lrr_power));
+        match ch_index {
+            CLASS_B_BEACON_CH_INDEX => {
+                rf_region.default_class_b_beacon_freq = Some(ch.freq);
+                rf_region.class_b_beacon_dr = Some(ch.dr_range[1]);
+            },
+            _ => rf_region.default_class_b_ping_slot_freq = Some(ch.freq),
+        }
+        rf_region.channels.push(ch);
+    }
+
+    // The sub-bands of the XML only group the channels, their limits are those of the region
+    let (min_freq, max_freq) = region.freq_range();
+    rf_region.sub_bands = sub_bands
+        .into_iter()
+        .map(|(id, max_duty_cycle, max_tx_power)| SubBandRecord {
+            id,
+            freq_range: vec![min_freq as f64 / 1_000_000.0, max_freq as f64 / 1_000_000.0],
+            max_duty_cycle,
+            max_tx_power,
+            max_dwell_time: None,
+            line: 0,
+        })
+        .collect();
+
+    // The data rate, payload size and TX power tables are not part of the XML
+    for dr in 0..15 {
+        let Some(data_rate) = region.data_rate(dr) else {
+            continue;
+        };
+        let data_rate = match data_rate {
+            DataRate::LoRa { sp_fact, bandwidth } => ("lora".to_string(), format!("SF{}", sp_fact), bandwidth, 0),
+            DataRate::Fsk { bit_rate } => ("fsk".to_string(), String::new(), 0, bit_rate),
+            DataRate::LrFhss { coding_rate, bandwidth } => ("lrfhss".to_string(), format!("CR{}/3", coding_rate), bandwidth, 0),
+        };
+        rf_region.data_rates.insert(dr, data_rate);
+        if let Some(m) = region.max_mac_payload(dr, false) {
+            rf_region.max_payload_size.insert(dr, m);
+        }
+    }
+    for tx_power in 0..=region.max_tx_power_index() {
+        rf_region.tx_power.insert(tx_power, 2 * tx_power);
+    }
+
+    validate(path, &name, &rf_region)?;
+
+    Ok((name, rf_region))
+
+}
+
+/// Typed access to the elements of an XML document, errors report the file and the line
+struct XmlReader<'a, 'input> {
+    path: &'a str,
+    doc: &'a Document<'input>,
+}
+impl<'a, 'input> XmlReader<'a, 'input> {
+
+    fn line(&self, node: Node) -> usize {
+        self.doc.text_pos_at(node.range().start).row as usize
+    }
+
+    /// The elements `<list><item>..</item></list>` of a parent element
+    fn elements(&self, parent: Node<'a, 'input>, list: &'a str, item: &'a str) -> impl Iterator<Item = Node<'a, 'input>> {
+        parent
+            .children()
+            .filter(move |n| n.has_tag_name(list))
+            .flat_map(move |n| n.children().filter(move |n| n.has_tag_name(item)))
+    }
+
+    /// Fails on the child elements that are not listed
+    fn check_tags(&self, node: Node, tags: &[&str]) -> AnyResult<()> {
+        match node.children().find(|n| n.is_element() && !tags.contains(&n.tag_name().name())) {
+            Some(n) => Err(anyhow!("{}:{}: unknown element <{}> in <{}>", self.path, self.line(n), n.tag_name().name(), node.tag_name().name())),
+            None => Ok(()),
+        }
+    }
+
+    /// The value of a child element, `None` if it is missing
+    fn value<T: FromStr>(&self, node: Node, tag: &str) -> AnyResult<Option<T>> {
+        let Some(child) = node.children().find(|n| n.has_tag_name(tag)) else {
+            return Ok(None);
+        };
+        let text = child.text().unwrap_or("").trim();
+        text.parse()
+            .map(Some)
+            .map_err(|_| anyhow!("{}:{}: invalid value of <{}>: {:?}", self.path, self.line(child), tag, text))
+    }
+
+    /// The value of a mandatory child element
+    fn required<T: FromStr>(&self, node: Node, tag: &str) -> AnyResult<T> {
+        self.value(node, tag)?
+            .ok_or_else(|| anyhow!("{}:{}: missing <{}> in <{}>", self.path, self.line(node), tag, node.tag_name().name()))
+    }
+
+    /// The ADRv2/ADRv3 parameters, the missing ones take their default values
+    fn adr_params(&self, root: Node) -> AnyResult<ADRParams> {
+        let mut adr = ADRParams::default();
+        macro_rules! set {
+            ($field:ident, $tag:literal) => {
+                if let Some(v) = self.value(root, $tag)? { adr.$field = v; }
+            };
+        }
+        set!(margin_db, "margin_db");
+        set!(window_size_snr, "WindowSize_SNR");
+        set!(initial_window_size, "Initial_WindowSize");
+        set!(sf_min, "SFmin");
+        set!(sf_max, "SFmax");
+        set!(redundancy, "Redundancy");
+        set!(min_power, "MinPower");
+        set!(max_power, "MaxPower");
+        set!(hyst_snr, "Hyst_SNR");
+        set!(target_per, "Target_PER");
+        set!(macro_div_reliab, "Macro_Div_Reliab");
+        set!(rep_min, "REP_Min");
+        set!(rep_max, "REP_Max");
+        set!(forgetting_factor, "Forgetting_Factor");
+        set!(per_hysteresis, "PER_Hysteresis");
+        set!(recovery_sf, "RecoverySF");
+        set!(recovery_tx_power, "RecoveryTxPower");
+        set!(recovery_nb_trans, "RecoveryNbTrans");
+        Ok(adr)
+    }
+
+}
+
+/// Checks the sub-bands and the channels of an RF region
+///
+/// Sub-band ids and channel indexes are to be unique, every channel is to be in a sub-band
+/// (when sub-bands are defined) and its data rate range is to be ordered.
+///
+fn validate(path: &str, name: &str, rf_region: &RfRegionRecord) -> AnyResult<()> {
+
+    let mut errors = Vec::new();
+
+    let mut sub_band_ids = HashSet::new();
+    for sub_band in &rf_region.sub_bands {
+        if !sub_band_ids.insert(sub_band.id) {
+            errors.push(format!("{}:{}: {} duplicate sub-band id: {}", path, sub_band.line, name, sub_band.id));
+        }
+        if sub_band.freq_range.is_empty() || sub_band.freq_range.len() > 2 {
+            errors.push(format!("{}:{}: {} sub-band {} freq_range is not [min_freq, max_freq]", path, sub_band.line, name, sub_band.id));
+        }
+    }
+
+    let mut ch_indexes = HashSet::new();
+    for ch in &rf_region.channels {
+        if !ch_indexes.insert(ch.ch_index) {
+            errors.push(format!("{}:{}: {} duplicate ch_index: {}", path, ch.line, name, ch.ch_index));
+        }
+        if ch.dr_range[0] > ch.dr_range[1] {
+            errors.push(format!("{}:{}: {} ch_index {} dr_range is not [min_dr, max_dr]: {:?}", path, ch.line, name, ch.ch_index, ch.dr_range));
+        }
+        if rf_region.sub_bands.is_empty() {
+            continue;
+        }
+        if !rf_region.sub_bands.iter().any(|sb| sb.contains(ch.freq)) {
+            errors.push(format!("{}:{}: {} ch_index {} freq {} MHz is outside of the sub-bands", path, ch.line, name, ch.ch_index, ch.freq));
+        }
+        if !sub_band_ids.contains(&ch.sub_band) {
+            errors.push(format!("{}:{}: {} ch_index {} has an undefined sub_band: {}", path, ch.line, name, ch.ch_index, ch.sub_band));
+        }
+    }
+
+    match errors.is_empty() {
+        true => Ok(()),
+        false => Err(anyhow!(errors.join("\n"))),
+    }
+
+}
+
+
+#[cfg(test)]
+mod tests {
+
+    use super::*;
+
+    #[test]
+    fn test_yaml_validation() {
+
+        let dir = std::env::temp_dir().join(format!("lws_rf_regions_{}", std::process::id()));
+        fs::create_dir_all(&dir).unwrap();
+        let path = dir.join("rf_regions.yaml").to_string_lossy().to_string();
+
+        let region = |ch_key: &str, freq: f64| format!(
+            "EU868:\n  cf_list_type: 0\n  sub_bands:\n    - id: 0\n      freq_range: [868.0, 868.6]\n      max_duty_cycle: 1\n      max_tx_power: 16\n  channels:\n    - ch_index: 0\n      sub_band: 0\n      type: default\n      freq: 868.1\n      dr_range: [0, 5]\n    - {}: 1\n      sub_band: 0\n      type: default\n      freq: {}\n      dr_range: [0, 5]\n",
+            ch_key, freq,
+        );
+
+        fs::write(&path, region("ch_index", 868.3)).unwrap();
+        let rf_regions = read_rf_regions_yaml(&path).unwrap();
+        assert_eq!(rf_regions["EU868"].channels[1].line, 14);
+
+        fs::write(&path, region("ch:index", 868.3)).unwrap();
+        assert!(read_rf_regions_yaml(&path).unwrap_err().to_string().contains("rf_regions.yaml:14:"));
+
+        fs::write(&path, region("ch_index", 869.0)).unwrap();
+        assert!(read_rf_regions_yaml(&path).unwrap_err().to_string().contains("rf_regions.yaml:14: EU868 ch_index 1 freq 869"));
+
+        fs::write(&path, region("ch_index", 868.3).replace("- ch_index: 1", "- ch_index: 0")).unwrap();
+        assert!(read_rf_regions_yaml(&path).unwrap_err().to_string().contains("rf_regions.yaml:14: EU868 duplicate ch_index: 0"));
+
+        fs::write(&path, region("ch_index", 868.3).replace("cf_list_type", "cf_list_typ")).unwrap();
+        assert!(read_rf_regions_yaml(&path).unwrap_err().to_string().contains("unknown field `cf_list_typ`"));
+
+        fs::remove_dir_all(&dir).unwrap();
+
+    }
+
+    #[test]
+    fn test_xml() {
+        let (name, rf_region) = read_rf_region_xml("config/lorawan_config/rf_region_EU868_Actility.xml").unwrap();
+        assert_eq!(name, "EU868");
+        assert_eq!(rf_region.channels.len(), 11);
+        assert_eq!(rf_region.default_class_b_beacon_freq, Some(869.525));
+        assert_eq!(rf_region.sub_bands.iter().find(|sb| sb.id == 3).map(|sb| sb.max_tx_power), Some(29));
+        assert_eq!(rf_region.max_mac_payload(3), 123);
+
+        // a channel outside of the band of the region
+        let dir = std::env::temp_dir().join(format!("lws_rf_region_xml_{}", std::process::id()));
+        fs::create_dir_all(&dir).unwrap();
+        let path = dir.join("rf_region_EU868_test.xml").to_string_lossy().to_string();
+        let text = fs::read_to_string("config/lorawan_config/rf_region_EU868_Actility.xml").unwrap();
+        fs::write(&path, text.replace("<Frequency>867.10</Frequency>", "<Frequency>871.10</Frequency>")).unwrap();
+        assert!(read_rf_region_xml(&path).unwrap_err().to_string().contains("EU868 ch_index 3 freq 871.1 MHz is outside of the sub-bands"));
+        fs::remove_dir_all(&dir).unwrap();
+    }
+
+}
@@ -0,0 +1,147 @@
+use std::{
+    collections::HashMap,
+    sync::{Mutex, OnceLock},
+    time::Duration,
+};
+
+/// The time the concentrator needs to start a transmission, kept free before each downlink (µs)
+pub const TX_START_DELAY: u32 = 1_500;
+
+/// The device class of a planned downlink
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum TxClass {
+    ClassA,
+    ClassC,
+}
+
+/// A downlink planned on a gateway, in the `tmst` time base of the gateway
+#[derive(Debug, Clone, PartialEq)]
+struct PlannedTx {
+    start: u32,             // tmst
+    duration: u32,          // µs, TX_START_DELAY included
+    class: TxClass,
+    token: Option<u16>,     // the token of the PULL_RESP, None until it is sent
+}
+impl PlannedTx {
+    fn overlaps(&self, start: u32, duration: u32) -> bool {
+        start.wrapping_sub(self.start) < self.duration || self.start.wrapping_sub(start) < duration
+    }
+    fn has_ended(&self, now: u32) -> bool {
+        (now.wrapping_sub(self.start.wrapping_add(self.duration)) as i32) >= 0
+    }
+}
+
+// The planned downlinks of each gateway, indexed by GwEUI
+static PLANS: OnceLock<Mutex<HashMap<u64, Vec<PlannedTx>>>> = OnceLock::new();
+
+fn plans() -> &'static Mutex<HashMap<u64, Vec<PlannedTx>>> {
+    PLANS.get_or_init(|| Mutex::new(HashMap::new()))
+}
+
+/// Reserves the transmission time of a downlink on a gateway
+///
+/// A concentrator sends one packet at a time, a reservation fails when it overlaps a planned
+/// downlink. Class A receive windows take priority: a Class A downlink preempts the overlapping
+/// Class C downlinks that have not been sent to the gateway yet.
+///
+/// # Arguments
+///
+/// * __`now`__\
+///   The current `tmst` of the gateway (e.g. of its last uplink), earlier downlinks are dropped \
+/// * __`start`__\
+///   The `tmst` of the downlink \
+/// * __`toa`__\
+///   The time-on-air of the downlink \
+///
+pub fn reserve(gw_eui: u64, now: u32, start: u32, toa: Duration, class: TxClass) -> bool {
+
+    let duration = TX_START_DELAY + toa.as_micros() as u32;
+    let start = start.wrapping_sub(TX_START_DELAY);
+
+    let mut plans = plans().lock().unwrap();
+    let planned = plans.entry(gw_eui).or_default();
+    planned.retain(|tx| !tx.has_ended(now));
+
+    let is_free = planned
+        .iter()
+        .filter(|tx| tx.overlaps(start, duration))
+        .all(|tx| class == TxClass::ClassA && tx.class == TxClass::ClassC && tx.token.is_none());
+    if !is_free {
+        return false;
+    }
+
+    planned.retain(|tx| {
+        let is_preempted = tx.overlaps(start, duration);
+        if is_preempted {
+            log::warn!("Class C downlink at tmst: {} is preempted on Gateway: x{:016x}", tx.start, gw_eui);
+        }
+        !is_preempted
+    });
+    planned.push(PlannedTx { start, duration, class, token: None });
+
+    true
+
+}
+
+/// Records the PULL_RESP token of a reserved downlink once it is sent
+pub fn confirm(gw_eui: u64, start: u32, token: u16) {
+    let start = start.wrapping_sub(TX_START_DELAY);
+    if let Some(tx) = plans().lock().unwrap()
+        .get_mut(&gw_eui)
+        .and_then(|planned| planned.iter_mut().find(|tx| tx.start == start)) {
+        tx.token = Some(token);
+    }
+}
+
+/// Releases a reserved downlink that could not be sent
+pub fn cancel(gw_eui: u64, start: u32) {
+    let start = start.wrapping_sub(TX_START_DELAY);
+    if let Some(planned) = plans().lock().unwrap().get_mut(&gw_eui) {
+        planned.retain(|tx| tx.start != start);
+    }
+}
+
+/// Releases the downlink of a PULL_RESP the gateway has rejected (TX_ACK with an error,
+/// e.g. COLLISION_PACKET or TOO_LATE)
+pub fn handle_tx_ack_error(gw_eui: u64, token: u16, error: &str) {
+    if let Some(planned) = plans().lock().unwrap().get_mut(&gw_eui) {
+        planned.retain(|tx| tx.token != Some(token));
+    }
+    log::warn!("Downlink is rejected by Gateway: x{:016x} token: {} error: {}", gw_eui, token, error);
+}
+
+
+#[cfg(test)]
+mod tests {
+
+    use super::*;
+
+    #[test]
+    fn test_reserve() {
+
+        let gw_eui = 0x0a0b0c0d0e0f0001;
+        let toa = Duration::from_millis(50);
+
+        assert!(reserve(gw_eui, 0, 1_000_000, toa, TxClass::ClassA));
+        assert!(!reserve(gw_eui, 0, 1_020_000, toa, TxClass::ClassA));
+        assert!(!reserve(gw_eui, 0, 990_000, toa, TxClass::ClassC));
+        assert!(reserve(gw_eui, 0, 2_000_000, toa, TxClass::ClassA));
+        assert!(reserve(gw_eui + 1, 0, 1_020_000, toa, TxClass::ClassA));
+
+        // Class A preempts a Class C downlink that is not sent yet
+        assert!(reserve(gw_eui, 0, 3_000_000, toa, TxClass::ClassC));
+        assert!(reserve(gw_eui, 0, 3_010_000, toa, TxClass::ClassA));
+        assert!(reserve(gw_eui, 0, 4_000_000, toa, TxClass::ClassC));
+        confirm(gw_eui, 4_000_000, 7);
+        assert!(!reserve(gw_eui, 0, 4_010_000, toa, TxClass::ClassA));
+        handle_tx_ack_error(gw_eui, 7, "COLLISION_PACKET");
+        assert!(reserve(gw_eui, 0, 4_010_000, toa, TxClass::ClassA));
+
+        // The tmst counter wraps around
+        assert!(reserve(gw_eui, u32::MAX - 10_000, u32::MAX - 5_000, toa, TxClass::ClassA));
+        assert!(!reserve(gw_eui, u32::MAX - 10_000, 20_000, toa, TxClass::ClassA));
+        assert!(reserve(gw_eui, u32::MAX - 10_000, 60_000, toa, TxClass::ClassA));
+
+    }
+
+}
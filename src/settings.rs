@@ -5,6 +5,8 @@ use std:: {
 use config::{Config, ConfigError};
 use serde_derive::Deserialize;
 
+use crate::join_server::nonce_store::JoinNonceScope;
+
 // This is the local, manual copy of the definition of the external log::LevelFilter type
 
 #[derive(Debug, Deserialize)]
@@ -26,12 +28,34 @@ pub struct UdpServer {
     pub addr: String,
 }
 
+#[derive(Debug, Deserialize)]
+#[allow(unused)]
+pub struct Roles {
+    pub network_server: bool,
+    pub join_server: bool,
+}
+
+#[derive(Debug, Deserialize)]
+#[allow(unused)]
+pub struct JoinServer {
+    pub addr: String,
+    pub nonce_file: String,
+    pub join_nonce_scope: JoinNonceScope,
+}
+
 #[derive(Debug, Deserialize)]
 #[allow(unused)]
 pub struct RemoteApplicationServer {
     pub url: String,
     pub timeout: u64,
     pub forward_incorrect_mic: bool,
+    pub downlink_addr: String,
+}
+
+#[derive(Debug, Deserialize)]
+#[allow(unused)]
+pub struct KeyStore {
+    pub master_kek: String,
 }
 
 #[derive(Debug, Deserialize)]
@@ -43,13 +67,23 @@ pub struct Log {
     pub level: log::LevelFilter,
 }
 
+#[derive(Debug, Deserialize)]
+#[allow(unused)]
+pub struct LorawanConfig {
+    pub dir: String,
+}
+
 #[derive(Debug, Deserialize)]
 #[allow(unused)]
 pub struct Settings {
     pub debug: bool,
     pub default_key: String,
+    pub roles: Roles,
     pub udp_server: UdpServer,
+    pub join_server: JoinServer,
     pub remote_application_server: RemoteApplicationServer,
+    pub lorawan_config: LorawanConfig,
+    pub key_store: KeyStore,
     pub log: Log,
 }
 impl Settings {
@@ -62,13 +96,29 @@ r#"
 debug = true
 default_key = "00000000000000000000000000000000"
 
+[roles]
+network_server = true
+join_server = false
+
 [udp_server]
 addr = "0.0.0.0:1700"
 
+[join_server]
+addr = "0.0.0.0:3001"   # Backend Interfaces HTTP API
+nonce_file = "data/js_state.json" # JoinNonce/DevNonce state, kept in memory only if empty
+join_nonce_scope = "Device"       # Device|JoinEUI
+
 [remote_application_server]
 url = "http://localhost"
 timeout = 5           # seconds
 forward_incorrect_mic = false
+downlink_addr = "0.0.0.0:3002"  # the downlink API of the Network Server
+
+[lorawan_config]
+dir = "config/lorawan_config"
+
+[key_store]
+master_kek = ""       # if set, the 48 hex digit keys of devices.yaml and of the JS state are unwrapped with it
 
 [log]
 dir = "log"
@@ -79,13 +129,29 @@ level = "Trace"       # Error|Warn|Info|Debug|Trace
         let _default_settings = Settings {
             debug: true,
             default_key: "00000000000000000000000000000000".to_owned(),
+            roles: Roles {
+                network_server: true,
+                join_server: false,
+            },
             udp_server: UdpServer {
                 addr: "0.0.0.0:1700".to_owned(), 
             },
+            join_server: JoinServer {
+                addr: "0.0.0.0:3001".to_owned(),
+                nonce_file: "data/js_state.json".to_owned(),
+                join_nonce_scope: JoinNonceScope::Device,
+            },
             remote_application_server: RemoteApplicationServer {
                 url: "http://localhost".to_owned(),
                 timeout: 5, // seconds
                 forward_incorrect_mic: false,
+                downlink_addr: "0.0.0.0:3002".to_owned(),
+            },
+            lorawan_config: LorawanConfig {
+                dir: "config/lorawan_config".to_owned(),
+            },
+            key_store: KeyStore {
+                master_kek: "".to_owned(),
             },
             log: Log {
                 dir: "log".to_owned(),